@@ -0,0 +1,108 @@
+//! Processing throughput of the transaction stores on the artefact files scaled up.
+//!
+//! Every artefact is repeated `SCALE` times, shifting the transaction ids of each copy past the
+//! previous one so that the stores keep growing the way a long input would make them.
+
+use std::{env, fs, io::Cursor};
+
+use anyhow::Result;
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use play_with_csv::{
+    Accountant, DiskStore, Engine, MemoryStore, ReaderConfig, RecordWrite, SpillStore,
+    TransactionStore,
+};
+use serde::Serialize;
+
+const SCALE: u32 = 200;
+const MEMORY_BUDGET: usize = 64 << 10;
+
+struct NullWriter;
+
+impl RecordWrite for NullWriter {
+    fn write_record<T: Serialize>(&mut self, _record: &T) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Repeats the rows of `artefact` `SCALE` times with shifted transaction ids.
+fn scaled(artefact: &str) -> (Vec<u8>, u64) {
+    let path = format!("{}/artefacts/{artefact}", env!("CARGO_MANIFEST_DIR"));
+    let input = fs::read_to_string(path).expect("artefact is readable");
+    let rows: Vec<Vec<&str>> = input
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').map(str::trim).collect())
+        .collect();
+    let stride = rows
+        .iter()
+        .filter_map(|row| row.get(2)?.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    let mut scaled = String::from("type,client,tx,amount\n");
+    for copy in 0..SCALE {
+        for row in &rows {
+            let tx = row[2].parse::<u32>().expect("artefact tx is numeric") + copy * stride;
+            let amount = row.get(3).copied().unwrap_or("");
+            scaled.push_str(&format!("{},{},{tx},{amount}\n", row[0], row[1]));
+        }
+    }
+    (scaled.into_bytes(), (rows.len() as u64) * u64::from(SCALE))
+}
+
+fn run(input: Vec<u8>, store: Box<dyn TransactionStore>) -> Result<()> {
+    let accountant = Accountant::new().with_store(store)?;
+    let reader = ReaderConfig::default().open(Box::new(Cursor::new(input)))?;
+    Engine::new(NullWriter, reader, accountant).run()?;
+    Ok(())
+}
+
+fn spill_path(name: &str) -> std::path::PathBuf {
+    env::temp_dir().join(format!("bench-{}-{name}.spill", std::process::id()))
+}
+
+fn stores(c: &mut Criterion) {
+    for artefact in [
+        "test_transactions_small.csv",
+        "test_transactions_large.csv",
+        "test_transactions_with_all_types.csv",
+    ] {
+        let (input, rows) = scaled(artefact);
+        let mut group = c.benchmark_group(artefact);
+        group.throughput(Throughput::Elements(rows));
+        group.sample_size(10);
+
+        group.bench_function("memory", |b| {
+            b.iter_batched(
+                || input.clone(),
+                |input| run(input, Box::new(MemoryStore::new())),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("spill", |b| {
+            b.iter_batched(
+                || {
+                    let disk = DiskStore::create(spill_path("spill")).expect("scratch file");
+                    (input.clone(), SpillStore::new(MEMORY_BUDGET, disk))
+                },
+                |(input, store)| run(input, Box::new(store)),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("disk", |b| {
+            b.iter_batched(
+                || {
+                    let disk = DiskStore::create(spill_path("disk")).expect("scratch file");
+                    (input.clone(), disk)
+                },
+                |(input, store)| run(input, Box::new(store)),
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, stores);
+criterion_main!(benches);
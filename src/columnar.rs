@@ -0,0 +1,994 @@
+//! Apache Parquet input and output.
+//!
+//! `ParquetReader` reads transactions from a Parquet file through a `ColumnMapping` naming the column of
+//! every transaction field. `ParquetWriter` writes records as Parquet, typing the columns after the first
+//! record and storing the balance amounts as `decimal(38, s)`, `s` being the scale of the precision policy.
+
+use std::{fmt, io::Write, str::FromStr, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Decimal128Array, Float64Array, Int64Array, RecordBatch,
+    StringArray, UInt8Array, UInt16Array, UInt32Array, UInt64Array, cast::AsArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use getset::Getters;
+use parquet::{
+    arrow::{
+        ArrowWriter, ProjectionMask,
+        arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder},
+    },
+    basic::Compression,
+    file::{properties::WriterProperties, reader::ChunkReader},
+};
+use rust_decimal::Decimal;
+use serde::{
+    Serialize,
+    ser::{self, Impossible, SerializeStruct},
+};
+use thiserror::Error;
+
+use crate::{
+    errors::InputError,
+    models::{Currency, Transaction, TransactionType},
+    precision::{FIXED_POINT, Precision},
+    processor::MalformedRow,
+    traits::{RecordRead, RecordWrite},
+};
+
+/// Precision of the decimal columns written, the widest a 128-bit decimal holds.
+const DECIMAL_PRECISION: u8 = 38;
+
+/// Number of records buffered into one Parquet row group.
+const ROWS_PER_GROUP: usize = 8192;
+
+/// ColumnMapping names the Parquet columns holding each transaction field.
+///
+/// The default mapping reads the `type`, `client`, `tx`, `amount`, `to` and `currency` columns.
+/// The amount column may be missing from files holding only disputes, resolves and chargebacks,
+/// the `to` column from files holding no transfers and the `currency` column from single-currency files.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct ColumnMapping {
+    /// The column of the transaction type.
+    #[getset(get = "pub")]
+    type_: String,
+    /// The column of the client id.
+    #[getset(get = "pub")]
+    client: String,
+    /// The column of the transaction id.
+    #[getset(get = "pub")]
+    tx: String,
+    /// The column of the amount.
+    #[getset(get = "pub")]
+    amount: String,
+    /// The column of the receiving client of a transfer.
+    #[getset(get = "pub")]
+    to: String,
+    /// The column of the currency.
+    #[getset(get = "pub")]
+    currency: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            type_: "type".to_string(),
+            client: "client".to_string(),
+            tx: "tx".to_string(),
+            amount: "amount".to_string(),
+            to: "to".to_string(),
+            currency: "currency".to_string(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Sets the column of the transaction type.
+    pub fn with_type(mut self, column: impl Into<String>) -> Self {
+        self.type_ = column.into();
+        self
+    }
+
+    /// Sets the column of the client id.
+    pub fn with_client(mut self, column: impl Into<String>) -> Self {
+        self.client = column.into();
+        self
+    }
+
+    /// Sets the column of the transaction id.
+    pub fn with_tx(mut self, column: impl Into<String>) -> Self {
+        self.tx = column.into();
+        self
+    }
+
+    /// Sets the column of the amount.
+    pub fn with_amount(mut self, column: impl Into<String>) -> Self {
+        self.amount = column.into();
+        self
+    }
+
+    /// Sets the column of the receiving client of a transfer.
+    pub fn with_to(mut self, column: impl Into<String>) -> Self {
+        self.to = column.into();
+        self
+    }
+
+    /// Sets the column of the currency.
+    pub fn with_currency(mut self, column: impl Into<String>) -> Self {
+        self.currency = column.into();
+        self
+    }
+}
+
+impl FromStr for ColumnMapping {
+    type Err = anyhow::Error;
+
+    /// Parses comma separated `<field>=<column>` pairs such as `type=kind,amount=value`;
+    /// fields left out keep their default column.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mapping = Self::default();
+        for pair in s.split(',') {
+            let (field, column) = pair
+                .split_once('=')
+                .ok_or(anyhow!("column mapping {pair:?} is not <field>=<column>"))?;
+            mapping = match field {
+                "type" => mapping.with_type(column),
+                "client" => mapping.with_client(column),
+                "tx" => mapping.with_tx(column),
+                "amount" => mapping.with_amount(column),
+                "to" => mapping.with_to(column),
+                "currency" => mapping.with_currency(column),
+                _ => Err(anyhow!(
+                    "unknown transaction field {field:?}, expected type, client, tx, amount, to or currency"
+                ))?,
+            };
+        }
+        Ok(mapping)
+    }
+}
+
+/// One record batch with the mapped columns cast to text.
+struct Batch {
+    type_: StringArray,
+    client: StringArray,
+    tx: StringArray,
+    amount: Option<StringArray>,
+    to: Option<StringArray>,
+    currency: Option<StringArray>,
+    next: usize,
+}
+
+impl Batch {
+    fn new(batch: &RecordBatch, columns: &ColumnMapping) -> Result<Self> {
+        let text = |name: &str| -> Result<Option<StringArray>> {
+            batch
+                .column_by_name(name)
+                .map(|column| {
+                    Ok(arrow_cast::cast(column, &DataType::Utf8)?
+                        .as_string()
+                        .clone())
+                })
+                .transpose()
+        };
+        let required = |name: &str| -> Result<StringArray> {
+            text(name)?.ok_or(anyhow!("parquet input has no {name:?} column"))
+        };
+        Ok(Self {
+            type_: required(columns.type_())?,
+            client: required(columns.client())?,
+            tx: required(columns.tx())?,
+            amount: text(columns.amount())?,
+            to: text(columns.to())?,
+            currency: text(columns.currency())?,
+            next: 0,
+        })
+    }
+
+    fn field(column: &StringArray, index: usize) -> Option<&str> {
+        column.is_valid(index).then(|| column.value(index))
+    }
+
+    fn optional_field(column: &Option<StringArray>, index: usize) -> Option<&str> {
+        column
+            .as_ref()
+            .and_then(|column| Self::field(column, index))
+    }
+
+    /// Returns the fields of row `index` joined by commas, nulls left empty.
+    fn raw(&self, index: usize) -> String {
+        let mut fields = vec![
+            Self::field(&self.type_, index),
+            Self::field(&self.client, index),
+            Self::field(&self.tx, index),
+            Self::optional_field(&self.amount, index),
+        ];
+        let to = Self::optional_field(&self.to, index);
+        let currency = Self::optional_field(&self.currency, index);
+        if to.is_some() || currency.is_some() {
+            fields.push(to);
+        }
+        if currency.is_some() {
+            fields.push(currency);
+        }
+        fields
+            .into_iter()
+            .map(|field| field.unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Parses row `index` into a transaction.
+    fn parse(&self, index: usize) -> Result<Transaction, String> {
+        let type_ = Self::field(&self.type_, index)
+            .ok_or("missing type")?
+            .parse::<TransactionType>()
+            .map_err(|err| err.to_string())?;
+        let client = Self::field(&self.client, index)
+            .and_then(|client| client.parse().ok())
+            .ok_or("missing or invalid client")?;
+        let tx = Self::field(&self.tx, index)
+            .and_then(|tx| tx.parse().ok())
+            .ok_or("missing or invalid tx")?;
+        let amount = Self::optional_field(&self.amount, index)
+            .filter(|amount| !amount.is_empty())
+            .map(|amount| {
+                Decimal::from_str_exact(amount).map_err(|_| format!("invalid amount {amount:?}"))
+            })
+            .transpose()?;
+        let to = Self::optional_field(&self.to, index)
+            .filter(|to| !to.is_empty())
+            .map(|to| to.parse().map_err(|_| format!("invalid to {to:?}")))
+            .transpose()?;
+        let currency = Self::optional_field(&self.currency, index)
+            .filter(|currency| !currency.is_empty())
+            .map(|currency| currency.parse::<Currency>().map_err(|err| err.to_string()))
+            .transpose()?;
+
+        let mut transaction = Transaction::new(type_, client, tx, amount);
+        if let Some(to) = to {
+            transaction = transaction.with_to(to);
+        }
+        if let Some(currency) = currency {
+            transaction = transaction.with_currency(currency);
+        }
+        Ok(transaction)
+    }
+}
+
+/// ParquetReader reads transactions from a Parquet file, one record batch at a time.
+///
+/// Columns are cast to text before parsing, so integer, string, dictionary and decimal columns are all
+/// accepted. Rows are numbered from 1 in place of input lines and the offset of a transaction is the
+/// number of rows read up to and including it, so a journal can resume Parquet input by row.
+pub struct ParquetReader {
+    batches: ParquetRecordBatchReader,
+    columns: ColumnMapping,
+    batch: Option<Batch>,
+    row: u64,
+}
+
+impl ParquetReader {
+    /// Creates a reader over `input` with the default column mapping.
+    ///
+    /// # Arguments
+    /// * `input` - The Parquet data, for example a `File` or `bytes::Bytes`.
+    ///
+    /// # Returns
+    /// The reader, or an error if `input` is not Parquet or lacks a mapped column.
+    pub fn new(input: impl ChunkReader + 'static) -> Result<Self> {
+        Self::with_columns(input, ColumnMapping::default())
+    }
+
+    /// Creates a reader over `input` taking the transaction fields from the `columns` columns.
+    ///
+    /// # Arguments
+    /// * `input` - The Parquet data, for example a `File` or `bytes::Bytes`.
+    /// * `columns` - The column of every transaction field.
+    ///
+    /// # Returns
+    /// The reader, or an error if `input` is not Parquet or lacks a mapped column.
+    pub fn with_columns(input: impl ChunkReader + 'static, columns: ColumnMapping) -> Result<Self> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(input)?;
+        let schema = builder.schema().clone();
+        let mut roots = Vec::new();
+        for name in [columns.type_(), columns.client(), columns.tx()] {
+            roots.push(
+                schema
+                    .index_of(name)
+                    .map_err(|_| anyhow!("parquet input has no {name:?} column"))?,
+            );
+        }
+        roots.extend(schema.index_of(columns.amount()).ok());
+        roots.extend(schema.index_of(columns.to()).ok());
+        roots.extend(schema.index_of(columns.currency()).ok());
+
+        let mask = ProjectionMask::roots(builder.parquet_schema(), roots);
+        Ok(Self {
+            batches: builder.with_projection(mask).build()?,
+            columns,
+            batch: None,
+            row: 0,
+        })
+    }
+}
+
+impl RecordRead for ParquetReader {
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>> {
+        loop {
+            if let Some(batch) = &mut self.batch
+                && batch.next < batch.type_.len()
+            {
+                let index = batch.next;
+                batch.next += 1;
+                self.row += 1;
+
+                let row = self.row;
+                let parsed = batch
+                    .parse(index)
+                    .map_err(|reason| InputError::Parquet { row, reason })
+                    .and_then(|tx| {
+                        tx.validate(precision)
+                            .map_err(|source| InputError::InvalidAmount { line: row, source })?;
+                        Ok(tx.with_line(row).with_offset(row))
+                    });
+                return Ok(Some(parsed.map_err(|error| {
+                    MalformedRow::at(row, row - 1, batch.raw(index), error)
+                })));
+            }
+
+            match self.batches.next() {
+                None => return Ok(None),
+                Some(batch) => self.batch = Some(Batch::new(&batch?, &self.columns)?),
+            }
+        }
+    }
+}
+
+/// A record field that cannot be written as a Parquet column.
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct UnsupportedField(String);
+
+impl ser::Error for UnsupportedField {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// A serialized value: a scalar cell, or the fields of a record.
+#[derive(Debug)]
+enum Cell {
+    Null,
+    Bool(bool),
+    UInt(u64, DataType),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Decimal(Decimal),
+    Record(Vec<(&'static str, Cell, bool)>),
+}
+
+impl Cell {
+    /// Returns the column type the cell is stored as, `None` for nulls.
+    fn data_type(&self) -> Result<Option<DataType>> {
+        Ok(match self {
+            Self::Null => None,
+            Self::Bool(_) => Some(DataType::Boolean),
+            Self::UInt(_, data_type) => Some(data_type.clone()),
+            Self::Int(_) => Some(DataType::Int64),
+            Self::Float(_) => Some(DataType::Float64),
+            Self::Text(_) => Some(DataType::Utf8),
+            Self::Decimal(value) => {
+                Some(DataType::Decimal128(DECIMAL_PRECISION, value.scale() as i8))
+            }
+            Self::Record(_) => Err(anyhow!("nested records cannot be written as parquet"))?,
+        })
+    }
+
+    fn unsigned(self) -> Option<u64> {
+        match self {
+            Self::UInt(value, _) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the cell as text, which a column left untyped by nulls accepts from any scalar.
+    fn text(self) -> Option<String> {
+        match self {
+            Self::Bool(value) => Some(value.to_string()),
+            Self::UInt(value, _) => Some(value.to_string()),
+            Self::Int(value) => Some(value.to_string()),
+            Self::Float(value) => Some(value.to_string()),
+            Self::Text(value) => Some(value),
+            Self::Decimal(value) => Some(value.to_string()),
+            Self::Null | Self::Record(_) => None,
+        }
+    }
+}
+
+/// Serializes a record into cells, telling which ones came from an `Option`.
+struct CellSerializer;
+
+/// Collects the fields of a serialized struct.
+struct RecordSerializer {
+    fields: Vec<(&'static str, Cell, bool)>,
+}
+
+impl SerializeStruct for RecordSerializer {
+    type Ok = (Cell, bool);
+    type Error = UnsupportedField;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        let (cell, optional) = value.serialize(CellSerializer)?;
+        self.fields.push((key, cell, optional));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Record(self.fields), false))
+    }
+}
+
+impl ser::Serializer for CellSerializer {
+    type Ok = (Cell, bool);
+    type Error = UnsupportedField;
+    type SerializeSeq = Impossible<Self::Ok, Self::Error>;
+    type SerializeTuple = Impossible<Self::Ok, Self::Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, Self::Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, Self::Error>;
+    type SerializeMap = Impossible<Self::Ok, Self::Error>;
+    type SerializeStruct = RecordSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, Self::Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Bool(v), false))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Int(v), false))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::UInt(v.into(), DataType::UInt8), false))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::UInt(v.into(), DataType::UInt16), false))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::UInt(v.into(), DataType::UInt32), false))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::UInt(v, DataType::UInt64), false))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Float(v), false))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Text(v.to_string()), false))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(ser::Error::custom("bytes cannot be written as parquet"))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Null, true))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        Ok((value.serialize(self)?.0, true))
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok((Cell::Null, false))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        match (name, value.serialize(self)?) {
+            (FIXED_POINT, (Cell::Text(text), optional)) => {
+                let amount = Decimal::from_str_exact(&text)
+                    .map_err(|_| ser::Error::custom(format!("invalid amount {text:?}")))?;
+                Ok((Cell::Decimal(amount), optional))
+            }
+            (_, serialized) => Ok(serialized),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        Err(ser::Error::custom(format!(
+            "{name}::{variant} cannot be written as parquet"
+        )))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(ser::Error::custom("sequences cannot be written as parquet"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(ser::Error::custom("tuples cannot be written as parquet"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(ser::Error::custom(format!(
+            "{name} cannot be written as parquet"
+        )))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(ser::Error::custom(format!(
+            "{name}::{variant} cannot be written as parquet"
+        )))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(ser::Error::custom("maps cannot be written as parquet"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(RecordSerializer {
+            fields: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(ser::Error::custom(format!(
+            "{name}::{variant} cannot be written as parquet"
+        )))
+    }
+}
+
+/// The buffered cells of one output column.
+struct Column {
+    name: &'static str,
+    nullable: bool,
+    cells: Vec<Cell>,
+}
+
+impl Column {
+    /// Returns the column field, typed after its first non-null cell or as text when all are null.
+    fn field(&self) -> Result<Field> {
+        let mut data_type = None;
+        for cell in &self.cells {
+            data_type = cell.data_type()?;
+            if data_type.is_some() {
+                break;
+            }
+        }
+        Ok(Field::new(
+            self.name,
+            data_type.unwrap_or(DataType::Utf8),
+            self.nullable,
+        ))
+    }
+
+    /// Moves the buffered cells into an array of the `field` type.
+    fn take(&mut self, field: &Field) -> Result<ArrayRef> {
+        let name = self.name;
+        let cells = std::mem::take(&mut self.cells);
+        let array: ArrayRef = match field.data_type() {
+            DataType::Boolean => Arc::new(BooleanArray::from(values(
+                name,
+                cells,
+                |cell| match cell {
+                    Cell::Bool(value) => Some(value),
+                    _ => None,
+                },
+            )?)),
+            DataType::UInt8 => Arc::new(UInt8Array::from(values(name, cells, |cell| {
+                cell.unsigned()?.try_into().ok()
+            })?)),
+            DataType::UInt16 => Arc::new(UInt16Array::from(values(name, cells, |cell| {
+                cell.unsigned()?.try_into().ok()
+            })?)),
+            DataType::UInt32 => Arc::new(UInt32Array::from(values(name, cells, |cell| {
+                cell.unsigned()?.try_into().ok()
+            })?)),
+            DataType::UInt64 => Arc::new(UInt64Array::from(values(name, cells, Cell::unsigned)?)),
+            DataType::Int64 => {
+                Arc::new(Int64Array::from(values(name, cells, |cell| match cell {
+                    Cell::Int(value) => Some(value),
+                    _ => None,
+                })?))
+            }
+            DataType::Float64 => Arc::new(Float64Array::from(values(
+                name,
+                cells,
+                |cell| match cell {
+                    Cell::Float(value) => Some(value),
+                    _ => None,
+                },
+            )?)),
+            DataType::Utf8 => Arc::new(StringArray::from(values(name, cells, Cell::text)?)),
+            DataType::Decimal128(precision, scale) => Arc::new(
+                Decimal128Array::from(values(name, cells, |cell| match cell {
+                    Cell::Decimal(mut value) => {
+                        value.rescale(*scale as u32);
+                        Some(value.mantissa())
+                    }
+                    _ => None,
+                })?)
+                .with_precision_and_scale(*precision, *scale)?,
+            ),
+            data_type => Err(anyhow!("column {name:?} has unsupported type {data_type}"))?,
+        };
+        Ok(array)
+    }
+}
+
+/// Converts the cells of column `name` with `value`, keeping nulls.
+///
+/// # Returns
+/// The values, or an error if a cell does not convert.
+fn values<T>(
+    name: &str,
+    cells: Vec<Cell>,
+    value: impl Fn(Cell) -> Option<T>,
+) -> Result<Vec<Option<T>>> {
+    cells
+        .into_iter()
+        .map(|cell| match cell {
+            Cell::Null => Ok(None),
+            cell => value(cell)
+                .map(Some)
+                .ok_or(anyhow!("column {name:?} changes type between records")),
+        })
+        .collect()
+}
+
+/// ParquetWriter writes records as a Parquet file.
+///
+/// Records must be flat structs. The columns are named after the fields of the first record and typed
+/// after the first non-null value of each in the first row group: booleans, unsigned integers of the same
+/// width, 64-bit signed integers and floats, strings, and `decimal(38, s)` for the balance amounts, `s`
+/// being their scale. `Option` fields are nullable. The file is complete once `finish` is called.
+pub struct ParquetWriter<W: Write + Send> {
+    output: Option<W>,
+    writer: Option<(ArrowWriter<W>, SchemaRef)>,
+    columns: Vec<Column>,
+    rows: usize,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    /// Creates a writer encoding records as Parquet into `output`.
+    ///
+    /// # Arguments
+    /// * `output` - The destination of the file.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record.
+    pub fn new(output: W) -> Self {
+        Self {
+            output: Some(output),
+            writer: None,
+            columns: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    /// Returns the output the file is written to.
+    pub(crate) fn get_mut(&mut self) -> Option<&mut W> {
+        match &mut self.writer {
+            Some((writer, _)) => Some(writer.inner_mut()),
+            None => self.output.as_mut(),
+        }
+    }
+
+    /// Writes the buffered records as one row group, opening the file on the first call.
+    fn flush(&mut self) -> Result<()> {
+        let (writer, schema) = match (&mut self.writer, self.output.take()) {
+            (Some(writer), _) => writer,
+            (None, Some(output)) => {
+                let fields = self
+                    .columns
+                    .iter()
+                    .map(Column::field)
+                    .collect::<Result<Vec<_>>>()?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let schema = Arc::new(Schema::new(fields));
+                let writer = ArrowWriter::try_new(output, schema.clone(), Some(properties))?;
+                self.writer.insert((writer, schema))
+            }
+            (None, None) => Err(anyhow!("parquet writer has no output"))?,
+        };
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let arrays = self
+            .columns
+            .iter_mut()
+            .zip(schema.fields())
+            .map(|(column, field)| column.take(field))
+            .collect::<Result<Vec<_>>>()?;
+        writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+        self.rows = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RecordWrite for ParquetWriter<W> {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        let Cell::Record(fields) = record.serialize(CellSerializer)?.0 else {
+            Err(anyhow!("only structs can be written as parquet records"))?
+        };
+        if self.columns.is_empty() && self.writer.is_none() {
+            self.columns = fields
+                .iter()
+                .map(|(name, _, _)| Column {
+                    name,
+                    nullable: false,
+                    cells: Vec::with_capacity(ROWS_PER_GROUP),
+                })
+                .collect();
+        }
+        if fields.len() != self.columns.len()
+            || fields
+                .iter()
+                .zip(&self.columns)
+                .any(|((name, _, _), column)| *name != column.name)
+        {
+            Err(anyhow!("parquet records must all have the same fields"))?
+        }
+
+        for ((_, cell, optional), column) in fields.into_iter().zip(&mut self.columns) {
+            column.nullable |= optional;
+            column.cells.push(cell);
+        }
+        self.rows += 1;
+        if self.rows == ROWS_PER_GROUP {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()?;
+        if let Some((writer, _)) = &mut self.writer {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::ClientBalance, precision::Precision};
+    use arrow_array::Int32Array;
+    use bytes::Bytes;
+    use rust_decimal::prelude::*;
+
+    fn parquet(batch: RecordBatch) -> Result<Bytes> {
+        let mut output = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut output, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(Bytes::from(output))
+    }
+
+    #[test]
+    fn test_reads_mapped_columns() -> Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("kind", DataType::Utf8, false),
+            Field::new("client", DataType::Int32, false),
+            Field::new("tx", DataType::Int32, false),
+            Field::new("value", DataType::Decimal128(10, 2), true),
+        ]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    "deposit", "Deposit", "oops", "dispute",
+                ])),
+                Arc::new(Int32Array::from(vec![1, 70000, 1, 1])),
+                Arc::new(Int32Array::from(vec![1, 2, 3, 1])),
+                Arc::new(
+                    Decimal128Array::from(vec![Some(150), Some(100), None, None])
+                        .with_precision_and_scale(10, 2)?,
+                ),
+            ],
+        )?;
+        let columns = "type=kind,amount=value".parse::<ColumnMapping>()?;
+        let mut reader = ParquetReader::with_columns(parquet(batch)?, columns)?;
+
+        let mut read = Vec::new();
+        while let Some(next) = reader.read_transaction(Precision::default())? {
+            read.push(next);
+        }
+        assert_eq!(read.len(), 4);
+
+        let deposit = read[0].as_ref().map_err(|_| anyhow!("row 1 is valid"))?;
+        assert_eq!(*deposit.type_(), TransactionType::Deposit);
+        assert_eq!(*deposit.amount(), Some(dec!(1.50)));
+        assert_eq!(*deposit.line(), Some(1));
+        assert_eq!(*deposit.offset(), Some(1));
+
+        let Err(out_of_range) = &read[1] else {
+            return Err(anyhow!("row 2 has a client out of range"));
+        };
+        assert_eq!(*out_of_range.line(), 2);
+        assert_eq!(*out_of_range.byte(), 1);
+        assert_eq!(out_of_range.raw(), "Deposit,70000,2,1.00");
+        assert!(matches!(
+            out_of_range.error(),
+            InputError::Parquet { row: 2, .. }
+        ));
+
+        assert!(matches!(
+            &read[2],
+            Err(row) if matches!(row.error(), InputError::Parquet { row: 3, .. })
+        ));
+
+        let dispute = read[3].as_ref().map_err(|_| anyhow!("row 4 is valid"))?;
+        assert_eq!(*dispute.type_(), TransactionType::Dispute);
+        assert_eq!(*dispute.amount(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_column_is_refused() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("type", DataType::Utf8, false)]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![Arc::new(StringArray::from(vec!["deposit"]))],
+        )?;
+        assert!(ParquetReader::new(parquet(batch)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_writes_balances_as_decimals() -> Result<()> {
+        let mut first = ClientBalance::new(1);
+        first.deposit(dec!(1.5000))?;
+        let second = ClientBalance::new(2);
+
+        let mut output = Vec::new();
+        let mut writer = ParquetWriter::new(&mut output);
+        writer.write_record(&first)?;
+        writer.write_record(&second)?;
+        writer.finish()?;
+        drop(writer);
+
+        let mut batches = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(output))?.build()?;
+        let batch = batches.next().ok_or(anyhow!("one row group"))??;
+        let schema = batch.schema();
+        let types: Vec<(&str, &DataType, bool)> = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                (
+                    field.name().as_str(),
+                    field.data_type(),
+                    field.is_nullable(),
+                )
+            })
+            .collect();
+        let decimal = DataType::Decimal128(38, 4);
+        assert_eq!(
+            types,
+            vec![
+                ("client", &DataType::UInt16, false),
+                ("available", &decimal, false),
+                ("held", &decimal, false),
+                ("total", &decimal, false),
+                ("locked", &DataType::Boolean, false),
+            ]
+        );
+
+        let available = batch
+            .column(1)
+            .as_primitive::<arrow_array::types::Decimal128Type>();
+        assert_eq!(available.value(0), 15000);
+        assert_eq!(available.value(1), 0);
+        assert!(batches.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_fields_are_nullable() -> Result<()> {
+        #[derive(Serialize)]
+        struct Row {
+            tx: u32,
+            input_line: Option<u64>,
+        }
+
+        let mut output = Vec::new();
+        let mut writer = ParquetWriter::new(&mut output);
+        writer.write_record(&Row {
+            tx: 1,
+            input_line: None,
+        })?;
+        writer.write_record(&Row {
+            tx: 2,
+            input_line: Some(7),
+        })?;
+        writer.finish()?;
+        drop(writer);
+
+        let mut batches = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(output))?.build()?;
+        let batch = batches.next().ok_or(anyhow!("one row group"))??;
+        let schema = batch.schema();
+        let input_line = schema.field_with_name("input_line")?;
+        assert_eq!(input_line.data_type(), &DataType::UInt64);
+        assert!(input_line.is_nullable());
+        assert!(batch.column(1).is_null(0));
+        Ok(())
+    }
+}
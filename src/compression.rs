@@ -0,0 +1,212 @@
+//! Transparent gzip and zstd compression of input and output.
+//!
+//! Compressed input is recognized by its magic bytes, so it works on stdin as well as on files.
+//! Output is compressed with the codec chosen by the caller, or the one named by a file extension.
+
+use std::{
+    io::{self, Cursor, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Result, anyhow};
+use flate2::{Compression as GzipLevel, read::MultiGzDecoder, write::GzEncoder};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression is the codec wrapped around input or output data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Plain data.
+    #[default]
+    None,
+    /// gzip, possibly made of several concatenated members.
+    Gzip,
+    /// Zstandard, possibly made of several concatenated frames.
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// Parses `none`, `gzip` or `zstd`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(anyhow!(
+                "unknown compression {s:?}, expected none, gzip or zstd"
+            )),
+        }
+    }
+}
+
+impl Compression {
+    /// Recognizes the codec from the first bytes of the data, `None` for anything else.
+    pub fn from_magic(head: &[u8]) -> Self {
+        if head.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Recognizes the codec from a `.gz` or `.zst` file extension, `None` for any other.
+    pub fn from_extension(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Decompresses `input` if its magic bytes name a known codec, passing it through otherwise.
+///
+/// # Arguments
+/// * `input` - The possibly compressed data.
+///
+/// # Returns
+/// The plain data, or an error if the first bytes cannot be read.
+pub fn decompress<'a>(mut input: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut head = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut input)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut head)?;
+    let compression = Compression::from_magic(&head);
+    let input = Cursor::new(head).chain(input);
+
+    Ok(match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(input)?),
+    })
+}
+
+/// Encoder compresses written data, to be finished after the last write.
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder compressing into `output` with `compression`.
+    pub(crate) fn new(output: W, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::Plain(output),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(output, GzipLevel::default())),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(output, 0)?),
+        })
+    }
+
+    /// Writes the end of the compressed stream and flushes the output.
+    pub(crate) fn try_finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(output) => output.flush(),
+            Self::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            Self::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(output) => output.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(output) => output.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(Vec::new(), compression)?;
+        encoder.write_all(data)?;
+        encoder.try_finish()?;
+        Ok(match encoder {
+            Encoder::Plain(output) => output,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+
+    fn decompressed(data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decompress(Cursor::new(data))?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn test_round_trips_every_codec() -> Result<()> {
+        let data = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let encoded = compressed(compression, data)?;
+            assert_eq!(Compression::from_magic(&encoded), compression);
+            assert_eq!(decompressed(encoded)?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_concatenated_members_are_read_whole() -> Result<()> {
+        let mut gzip = compressed(Compression::Gzip, b"type,client,tx,amount\n")?;
+        gzip.extend(compressed(Compression::Gzip, b"deposit,1,1,1.0\n")?);
+        assert_eq!(
+            decompressed(gzip)?,
+            b"type,client,tx,amount\ndeposit,1,1,1.0\n"
+        );
+
+        let mut zstd = compressed(Compression::Zstd, b"a\n")?;
+        zstd.extend(compressed(Compression::Zstd, b"b\n")?);
+        assert_eq!(decompressed(zstd)?, b"a\nb\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_plain_input_passes_through() -> Result<()> {
+        assert_eq!(decompressed(Vec::new())?, b"");
+        assert_eq!(decompressed(b"\x1f".to_vec())?, b"\x1f");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_names() -> Result<()> {
+        assert_eq!("gzip".parse::<Compression>()?, Compression::Gzip);
+        assert_eq!("zstd".parse::<Compression>()?, Compression::Zstd);
+        assert_eq!("none".parse::<Compression>()?, Compression::None);
+        assert!("xz".parse::<Compression>().is_err());
+        assert_eq!(
+            Compression::from_extension("drop.csv.gz"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_extension("drop.csv.zst"),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::from_extension("drop.csv"), Compression::None);
+        Ok(())
+    }
+}
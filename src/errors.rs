@@ -1,20 +1,244 @@
 use rust_decimal::Decimal;
+use std::io;
+
+use crate::models::{Currency, TransactionState, TransactionType};
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+/// Reasons the ledger refuses to apply a transaction.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
 pub enum LedgerError {
+    /// The balance cannot cover the requested amount.
     #[error("insufficient funds: tried to withdraw {amount}, only {balance} available")]
-    InsufficientFunds { amount: Decimal, balance: Decimal },
+    InsufficientFunds {
+        /// The requested amount.
+        amount: Decimal,
+        /// The balance left after the operation.
+        balance: Decimal,
+    },
 
+    /// The client account is locked after a chargeback.
     #[error("account {0} is locked")]
     AccountLocked(u16),
 
+    /// The referenced transaction does not exist.
     #[error("transaction {0} not found")]
     TxNotFound(u32),
 
+    /// A deposit, withdrawal or transfer reuses an existing transaction id.
     #[error("transaction {0} is duplicated")]
     TxDuplicated(u32),
 
+    /// A dispute, resolve or chargeback references another client's transaction.
+    #[error("transaction {tx} belongs to client {expected}, not to client {got}")]
+    ClientMismatch {
+        /// The referenced transaction.
+        tx: u32,
+        /// The client owning the referenced transaction.
+        expected: u16,
+        /// The client named on the refused row.
+        got: u16,
+    },
+
+    /// The referenced transaction cannot move to the requested lifecycle state.
+    #[error("transaction {tx} cannot move from {from} to {to}")]
+    IllegalTransition {
+        /// The referenced transaction.
+        tx: u32,
+        /// The current state.
+        from: TransactionState,
+        /// The requested state.
+        to: TransactionState,
+    },
+
+    /// A deposit, withdrawal or transfer carries no amount.
+    #[error("transaction {0} has no amount")]
+    MissingAmount(u32),
+
+    /// A transfer names no receiving client.
+    #[error("transfer {0} has no receiving client")]
+    MissingDestination(u32),
+
+    /// A transfer names its own client as the receiving client.
+    #[error("transfer {0} sends funds to its own client")]
+    TransferToSelf(u32),
+
+    /// A dispute, resolve or chargeback names another currency than the referenced transaction.
+    #[error("transaction {tx} is not in {got}")]
+    CurrencyMismatch {
+        /// The referenced transaction.
+        tx: u32,
+        /// The currency of the referenced transaction.
+        expected: Option<Currency>,
+        /// The currency named on the refused row.
+        got: Currency,
+    },
+
+    /// A transaction is made by or sent to the house account, which only collects fees.
+    #[error("client {0} is the house account and only collects fees")]
+    HouseAccount(u16),
+
+    /// The fee of a deposit would take all of its amount.
+    #[error("fee {fee} of deposit {tx} is not below its amount")]
+    FeeExceedsAmount {
+        /// The refused deposit.
+        tx: u32,
+        /// The fee the schedule charges on it.
+        fee: Decimal,
+    },
+
+    /// An amount has more decimal places than the precision policy keeps and may not be rounded.
+    #[error("transaction {tx} has more than {max_scale} decimal places")]
+    TooPrecise {
+        /// The refused transaction.
+        tx: u32,
+        /// The number of decimal places kept.
+        max_scale: u32,
+    },
+
+    /// An amount is too large to keep all the decimal places of the precision policy.
+    #[error("transaction {tx} is too large to keep {max_scale} decimal places")]
+    TooLarge {
+        /// The refused transaction.
+        tx: u32,
+        /// The number of decimal places kept.
+        max_scale: u32,
+    },
+
+    /// A row other than a transfer names a receiving client.
+    #[error("transaction {0} is not a transfer but names a receiving client")]
+    UnexpectedDestination(u32),
+
+    /// An amount is zero or negative.
+    #[error("amount {0} must be strictly positive")]
+    NonPositiveAmount(Decimal),
+
+    /// A balance would overflow.
     #[error("value overflow")]
     ValueOverflow,
 }
+
+/// Reasons an input amount does not match its transaction type.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum AmountError {
+    /// A deposit, withdrawal or transfer has no amount.
+    #[error("{0} requires an amount")]
+    Missing(TransactionType),
+
+    /// A dispute, resolve or chargeback carries an amount.
+    #[error("{0} must not carry an amount")]
+    Unexpected(TransactionType),
+
+    /// The amount is zero or negative.
+    #[error("amount {0} must be strictly positive")]
+    NotPositive(Decimal),
+
+    /// The amount has more decimal places than allowed.
+    #[error("amount {amount} has more than {max_scale} decimal places")]
+    TooPrecise {
+        /// The refused amount.
+        amount: Decimal,
+        /// The maximum number of decimal places allowed.
+        max_scale: u32,
+    },
+
+    /// The amount is too large to keep the allowed number of decimal places.
+    #[error("amount {amount} is too large to keep {max_scale} decimal places")]
+    TooLarge {
+        /// The refused amount.
+        amount: Decimal,
+        /// The number of decimal places it must keep.
+        max_scale: u32,
+    },
+}
+
+/// The transaction type name is not known.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("unknown transaction type {0:?}")]
+pub struct UnknownTransactionType(pub String);
+
+/// The currency code is not one to eight ASCII letters or digits.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("invalid currency {0:?}")]
+pub struct InvalidCurrency(pub String);
+
+/// Reasons an input row cannot be turned into a transaction.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum InputError {
+    /// The row is not valid CSV for a transaction.
+    #[error("line {line}: {source}")]
+    Malformed {
+        /// The input line of the row.
+        line: u64,
+        /// The underlying CSV error.
+        source: csv::Error,
+    },
+
+    /// The line is not valid JSON for a transaction.
+    #[error("line {line}: {source}")]
+    Json {
+        /// The input line.
+        line: u64,
+        /// The underlying JSON error.
+        source: serde_json::Error,
+    },
+
+    /// The Parquet row is not valid for a transaction.
+    #[error("row {row}: {reason}")]
+    Parquet {
+        /// The input row, starting at 1.
+        row: u64,
+        /// Why the row is not a valid transaction.
+        reason: String,
+    },
+
+    /// The row amount does not match its transaction type.
+    #[error("line {line}: {source}")]
+    InvalidAmount {
+        /// The input line of the row.
+        line: u64,
+        /// The amount problem.
+        source: AmountError,
+    },
+
+    /// More malformed rows were seen than the error policy allows.
+    #[error("more than {limit} malformed rows, stopped at line {line}")]
+    TooManyMalformed {
+        /// The number of malformed rows allowed.
+        limit: usize,
+        /// The input line of the row over the limit.
+        line: u64,
+    },
+
+    /// Reading the input failed.
+    #[error("input i/o: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Reasons a snapshot or journal cannot be read.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// The data does not start with the expected magic bytes.
+    #[error("not a ledger snapshot")]
+    UnknownFormat,
+
+    /// The format version is not supported by this build.
+    #[error("snapshot version {0} is not supported")]
+    UnsupportedVersion(u16),
+
+    /// An enum tag is out of range.
+    #[error("snapshot contains unknown {kind} tag {tag}")]
+    UnknownTag {
+        /// The kind of value being decoded.
+        kind: &'static str,
+        /// The unknown tag.
+        tag: u8,
+    },
+
+    /// Reading or writing failed.
+    #[error("snapshot i/o: {0}")]
+    Io(#[from] io::Error),
+}
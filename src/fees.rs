@@ -0,0 +1,432 @@
+//! Fee schedule charged on deposits and withdrawals.
+//!
+//! A schedule is a CSV file of rules with `type,client,currency,from,flat,percent` columns. A rule charges
+//! `flat + amount * percent / 100` on deposits or withdrawals of at least `from`; the rule with the highest
+//! `from` not above the amount applies, so several rules of a type make a tiered schedule. Rules naming a
+//! client take precedence over the rules without one for that client, and rules naming a currency over the
+//! rules without one for transactions in that currency. When none of the more specific rules starts at or
+//! below the amount, the less specific ones apply.
+//!
+//! `flat` and `from` are amounts of one currency, so a rule without a currency that sets either only applies
+//! to transactions without a currency. A rule with only a `percent` applies to every currency.
+
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{Context, Result, anyhow};
+use csv::StringRecord;
+use getset::Getters;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::decompress,
+    models::{Currency, TransactionType},
+    reader::ReaderConfig,
+};
+
+/// Client id of the account collecting fees when none is configured.
+pub const DEFAULT_HOUSE_ACCOUNT: u16 = 0;
+
+/// One line of a fee schedule.
+#[derive(Debug, Clone, PartialEq, Deserialize, Getters)]
+pub struct FeeRule {
+    /// The charged transaction type, a deposit or a withdrawal.
+    #[getset(get = "pub")]
+    #[serde(rename = "type")]
+    type_: TransactionType,
+    /// The client the rule is for, every client without rules of their own when `None`.
+    #[getset(get = "pub")]
+    #[serde(default)]
+    client: Option<u16>,
+    /// The currency the rule is for, every currency without rules of its own when `None`.
+    #[getset(get = "pub")]
+    #[serde(default)]
+    currency: Option<Currency>,
+    /// The lowest amount the rule applies to.
+    #[getset(get = "pub")]
+    #[serde(default)]
+    from: Option<Decimal>,
+    /// The fixed part of the fee.
+    #[getset(get = "pub")]
+    #[serde(default)]
+    flat: Option<Decimal>,
+    /// The part of the fee proportional to the amount, in percent.
+    #[getset(get = "pub")]
+    #[serde(default)]
+    percent: Option<Decimal>,
+}
+
+impl FeeRule {
+    /// Creates a rule charging nothing on every `type_` transaction.
+    pub fn new(type_: TransactionType) -> Self {
+        FeeRule {
+            type_,
+            client: None,
+            currency: None,
+            from: None,
+            flat: None,
+            percent: None,
+        }
+    }
+
+    /// Restricts the rule to `client`.
+    pub fn with_client(mut self, client: u16) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Restricts the rule to transactions in `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    /// Applies the rule to amounts of at least `from` only.
+    pub fn with_from(mut self, from: Decimal) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    /// Sets the fixed part of the fee.
+    pub fn with_flat(mut self, flat: Decimal) -> Self {
+        self.flat = Some(flat);
+        self
+    }
+
+    /// Sets the part of the fee proportional to the amount, in percent.
+    pub fn with_percent(mut self, percent: Decimal) -> Self {
+        self.percent = Some(percent);
+        self
+    }
+
+    fn lowest(&self) -> Decimal {
+        self.from.unwrap_or_default()
+    }
+
+    /// Checks whether the rule charges transactions in `currency`.
+    fn applies_to(&self, currency: Option<Currency>) -> bool {
+        match self.currency {
+            Some(own) => Some(own) == currency,
+            None => currency.is_none() || (self.flat.is_none() && self.from.is_none()),
+        }
+    }
+
+    /// Returns why the rule cannot be used, if it cannot.
+    fn check(&self) -> Option<String> {
+        if !matches!(
+            self.type_,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return Some(format!(
+                "fees apply to deposits and withdrawals, not {}",
+                self.type_
+            ));
+        }
+        if self
+            .percent
+            .is_some_and(|percent| percent > Decimal::ONE_HUNDRED)
+        {
+            return Some("percent must not be above 100".into());
+        }
+        [
+            ("from", self.from),
+            ("flat", self.flat),
+            ("percent", self.percent),
+        ]
+        .into_iter()
+        .find(|(_, value)| value.is_some_and(|value| value.is_sign_negative()))
+        .map(|(name, _)| format!("{name} must not be negative"))
+    }
+}
+
+/// FeeSchedule decides the fee of every deposit and withdrawal and the account collecting it.
+#[derive(Debug, Clone, PartialEq, Getters)]
+pub struct FeeSchedule {
+    /// The client id of the account credited with every fee.
+    #[getset(get = "pub")]
+    house: u16,
+    /// The rules, in the order they were added.
+    #[getset(get = "pub")]
+    rules: Vec<FeeRule>,
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeeSchedule {
+    /// Creates a schedule without rules, charging nothing.
+    pub fn new() -> Self {
+        FeeSchedule {
+            house: DEFAULT_HOUSE_ACCOUNT,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Reads the rules of a CSV schedule, possibly gzip or zstd compressed.
+    ///
+    /// # Arguments
+    /// * `input` - The schedule, with a header row naming the `type`, `client`, `currency`, `from`, `flat` and
+    ///   `percent` columns.
+    ///   Only `type` is required, empty fields are left unset.
+    ///
+    /// # Returns
+    /// The schedule, or an error naming the line of the first invalid rule.
+    pub fn from_reader(input: impl Read + 'static) -> Result<Self> {
+        let mut reader = ReaderConfig::default().build(decompress(Box::new(input))?);
+        let headers = reader.headers()?.clone();
+        let mut record = StringRecord::new();
+        let mut schedule = Self::new();
+        while reader.read_record(&mut record)? {
+            let line = record.position().map_or(0, |position| position.line());
+            schedule = record
+                .deserialize::<FeeRule>(Some(&headers))
+                .map_err(anyhow::Error::from)
+                .and_then(|rule| schedule.with_rule(rule))
+                .with_context(|| format!("fee rule on line {line}"))?;
+        }
+        Ok(schedule)
+    }
+
+    /// Reads the rules of the CSV schedule at `path`, see `FeeSchedule::from_reader`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        Self::from_reader(File::open(path)?).with_context(|| path.display().to_string())
+    }
+
+    /// Sets the client id of the account credited with every fee.
+    pub fn with_house(mut self, house: u16) -> Self {
+        self.house = house;
+        self
+    }
+
+    /// Adds a rule to the schedule.
+    ///
+    /// # Returns
+    /// The schedule, or an error if the rule is not for deposits or withdrawals, has a negative value or a
+    /// percent above 100, or starts at the same amount as another rule of its type, client and currency.
+    pub fn with_rule(mut self, rule: FeeRule) -> Result<Self> {
+        if let Some(reason) = rule.check() {
+            Err(anyhow!(reason))?
+        }
+        if self.rules.iter().any(|other| {
+            other.type_ == rule.type_
+                && other.client == rule.client
+                && other.currency == rule.currency
+                && other.lowest() == rule.lowest()
+        }) {
+            Err(anyhow!(
+                "another {} rule starts at {}",
+                rule.type_,
+                rule.lowest()
+            ))?
+        }
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    /// Returns the fee of a `type_` transaction of `amount` in `currency` made by `client`, before rounding.
+    ///
+    /// # Returns
+    /// The fee, or `None` if computing it overflows.
+    pub fn fee(
+        &self,
+        type_: TransactionType,
+        client: u16,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> Option<Decimal> {
+        let rules_of = |client: Option<u16>, named: Option<Currency>| {
+            self.rules.iter().filter(move |rule| {
+                rule.type_ == type_
+                    && rule.client == client
+                    && rule.currency == named
+                    && rule.applies_to(currency)
+            })
+        };
+        [
+            (Some(client), currency),
+            (Some(client), None),
+            (None, currency),
+            (None, None),
+        ]
+        .into_iter()
+        .find_map(|(client, named)| {
+            rules_of(client, named)
+                .filter(|rule| rule.lowest() <= amount)
+                .max_by_key(|rule| rule.lowest())
+        })
+        .map_or(Some(Decimal::ZERO), |rule| {
+            amount
+                .checked_mul(rule.percent.unwrap_or_default())?
+                .checked_div(Decimal::ONE_HUNDRED)?
+                .checked_add(rule.flat.unwrap_or_default())
+        })
+    }
+}
+
+/// A fee charged on an accepted deposit or withdrawal and credited to the house account.
+#[derive(Debug, Clone, PartialEq, Serialize, Getters)]
+pub struct FeePosting {
+    /// The charged deposit or withdrawal.
+    #[getset(get = "pub")]
+    tx: u32,
+    /// The charged client.
+    #[getset(get = "pub")]
+    client: u16,
+    /// The house account credited with the fee.
+    #[getset(get = "pub")]
+    house: u16,
+    /// The currency of the fee, the one of the charged transaction.
+    #[getset(get = "pub")]
+    currency: Option<Currency>,
+    /// The fee, rounded to the precision policy.
+    #[getset(get = "pub")]
+    fee: Decimal,
+}
+
+impl FeePosting {
+    /// Creates the posting of `fee` charged on `tx` of `client`, also used when restoring a snapshot.
+    pub(crate) fn new(
+        tx: u32,
+        client: u16,
+        house: u16,
+        currency: Option<Currency>,
+        fee: Decimal,
+    ) -> Self {
+        FeePosting {
+            tx,
+            client,
+            house,
+            currency,
+            fee,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::prelude::*;
+
+    const SCHEDULE: &str = "type,client,from,flat,percent
+withdrawal,,,0.50,
+withdrawal,,1000,,0.1
+deposit,,,,1
+withdrawal,7,,0,
+deposit,8,100,0,
+";
+
+    #[test]
+    fn test_flat_percentage_tiered_and_client_fees() -> Result<()> {
+        let schedule = FeeSchedule::from_reader(SCHEDULE.as_bytes())?;
+        let withdrawal = TransactionType::Withdrawal;
+        assert_eq!(
+            schedule.fee(withdrawal, 1, None, dec!(10)),
+            Some(dec!(0.50))
+        );
+        assert_eq!(
+            schedule.fee(withdrawal, 1, None, dec!(999.99)),
+            Some(dec!(0.50))
+        );
+        assert_eq!(schedule.fee(withdrawal, 1, None, dec!(2000)), Some(dec!(2)));
+        assert_eq!(schedule.fee(withdrawal, 7, None, dec!(2000)), Some(dec!(0)));
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, 7, None, dec!(12.5)),
+            Some(dec!(0.125))
+        );
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, 8, None, dec!(150)),
+            Some(dec!(0))
+        );
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, 8, None, dec!(50)),
+            Some(dec!(0.5))
+        );
+        let half = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_percent(dec!(50)))?;
+        assert_eq!(
+            half.fee(TransactionType::Deposit, 1, None, Decimal::MAX),
+            None
+        );
+        assert_eq!(
+            FeeSchedule::new().fee(TransactionType::Deposit, 1, None, dec!(12.5)),
+            Some(dec!(0))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_currency_fees() -> Result<()> {
+        let schedule = FeeSchedule::from_reader(
+            "type,client,currency,from,flat,percent
+deposit,,,,,2
+deposit,,JPY,,100,
+deposit,,JPY,10000,,1
+deposit,3,USD,,0.5,
+"
+            .as_bytes(),
+        )?;
+        let deposit = TransactionType::Deposit;
+        let (usd, jpy, eur) = ("USD".parse()?, "JPY".parse()?, "EUR".parse()?);
+        assert_eq!(schedule.fee(deposit, 1, None, dec!(10)), Some(dec!(0.2)));
+        assert_eq!(
+            schedule.fee(deposit, 1, Some(usd), dec!(10)),
+            Some(dec!(0.2))
+        );
+        assert_eq!(
+            schedule.fee(deposit, 1, Some(eur), dec!(10)),
+            Some(dec!(0.2))
+        );
+        assert_eq!(
+            schedule.fee(deposit, 1, Some(jpy), dec!(1000)),
+            Some(dec!(100))
+        );
+        assert_eq!(
+            schedule.fee(deposit, 1, Some(jpy), dec!(20000)),
+            Some(dec!(200))
+        );
+        assert_eq!(
+            schedule.fee(deposit, 3, Some(usd), dec!(10)),
+            Some(dec!(0.5))
+        );
+        assert_eq!(
+            schedule.fee(deposit, 3, Some(jpy), dec!(1000)),
+            Some(dec!(100))
+        );
+
+        let flat = FeeSchedule::from_reader("type,flat\ndeposit,1\n".as_bytes())?;
+        assert_eq!(flat.fee(deposit, 1, Some(usd), dec!(10)), Some(dec!(0)));
+        assert_eq!(flat.fee(deposit, 1, Some(jpy), dec!(10)), Some(dec!(0)));
+        assert_eq!(flat.fee(deposit, 1, None, dec!(10)), Some(dec!(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_rules_are_refused() {
+        for (rules, error) in [
+            (
+                "type,flat\ntransfer,1\n",
+                "fees apply to deposits and withdrawals, not transfer",
+            ),
+            ("type,percent\ndeposit,-1\n", "percent must not be negative"),
+            (
+                "type,percent\nwithdrawal,100.5\n",
+                "percent must not be above 100",
+            ),
+            (
+                "type,from,flat\ndeposit,5,1\ndeposit,5,2\n",
+                "another deposit rule starts at 5",
+            ),
+        ] {
+            let result = FeeSchedule::from_reader(rules.as_bytes());
+            assert_eq!(
+                result.map_err(|error| error.root_cause().to_string()).err(),
+                Some(error.to_string())
+            );
+        }
+        assert!(FeeSchedule::from_reader("type,flat\ndeposit,x\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,606 @@
+//! HTTP/JSON API over a shared ledger.
+//!
+//! * `POST /transactions` applies one transaction sent as JSON, for example
+//!   `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`, and answers with the client balance,
+//!   or with `409 Conflict` and the rejection when the ledger refuses it.
+//! * `POST /transactions/batch` applies a body in the input format and answers with a `BatchReport`;
+//!   a `text/csv` or `application/jsonl` content type overrides the configured format.
+//! * `GET /transactions/{tx}` returns a stored deposit or withdrawal with its dispute state.
+//! * `GET /clients/{id}` returns the balance of one client, in the currency named by `?currency=EUR` if any.
+//! * `GET /clients` exports every balance in the ledger export order, as JSON or with `?format=csv` as CSV.
+//!
+//! Errors are answered as `{"error": "..."}` with a 4xx or 5xx status.
+
+use std::{cell::RefCell, future::Future, net::SocketAddr, pin::pin, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{
+        Path, Query, State,
+        rejection::{PathRejection, QueryRejection},
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::Mutex,
+};
+
+use crate::{
+    errors::InputError,
+    ledger::Accountant,
+    models::{ClientBalance, Currency, Rejection, Transaction},
+    processor::{ErrorPolicy, IngestReport},
+    reader::{InputFormat, ReaderConfig, parse_json},
+    server::Settings,
+    streaming::{BalanceUpdate, apply_update, transaction_stream},
+    traits::{Accounting, CSVExport},
+    writer::{BufferCSVWriter, JsonValueWriter},
+};
+
+/// An error answered as `{"error": "..."}`.
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn new(status: StatusCode, error: impl ToString) -> Self {
+        Self(status, error.to_string())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error)
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// A stored transaction as returned by `GET /transactions/{tx}`.
+#[derive(Serialize)]
+struct TransactionView {
+    tx: u32,
+    client: u16,
+    #[serde(rename = "type")]
+    type_: String,
+    amount: Option<String>,
+    to: Option<u16>,
+    currency: Option<String>,
+    state: String,
+}
+
+impl From<Transaction> for TransactionView {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            tx: *transaction.tx(),
+            client: *transaction.client(),
+            type_: transaction.type_().to_string(),
+            amount: transaction.amount().map(|amount| amount.to_string()),
+            to: *transaction.to(),
+            currency: transaction.currency().map(|currency| currency.to_string()),
+            state: transaction.state().to_string(),
+        }
+    }
+}
+
+/// A skipped row of a batch.
+#[derive(Serialize)]
+struct MalformedView {
+    line: u64,
+    byte: u64,
+    raw: String,
+    error: String,
+}
+
+/// The outcome of `POST /transactions/batch`.
+#[derive(Default, Serialize)]
+struct BatchReport {
+    rows_read: u64,
+    applied: u64,
+    rejected: Vec<Rejection>,
+    malformed: Vec<MalformedView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<Format>,
+}
+
+#[derive(Deserialize)]
+struct BalanceQuery {
+    currency: Option<Currency>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    ledger: Arc<Mutex<Accountant>>,
+    settings: Settings,
+}
+
+/// HttpServer serves the HTTP/JSON API over a single shared ledger.
+pub struct HttpServer {
+    listener: TcpListener,
+    state: AppState,
+}
+
+impl HttpServer {
+    /// Listens on `addr` for requests to `accountant`.
+    ///
+    /// # Arguments
+    /// * `addr` - The address to listen on, port 0 picking any free port.
+    /// * `accountant` - The ledger every request applies to.
+    ///
+    /// # Returns
+    /// The server, answering requests once it runs.
+    pub async fn bind(addr: impl ToSocketAddrs, accountant: Accountant) -> Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            state: AppState {
+                settings: Settings::of(&accountant),
+                ledger: Arc::new(Mutex::new(accountant)),
+            },
+        })
+    }
+
+    /// Sets how CSV batches are read.
+    pub fn with_reader_config(mut self, reader_config: ReaderConfig) -> Self {
+        self.state.settings.reader_config = reader_config;
+        self
+    }
+
+    /// Sets the policy applied to batch rows that cannot be parsed, counted per batch.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.state.settings.error_policy = error_policy;
+        self
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves requests until `shutdown` completes, letting requests in flight finish.
+    ///
+    /// # Arguments
+    /// * `shutdown` - Completes when the server should stop, for example on ctrl-c.
+    ///
+    /// # Returns
+    /// The ledger with every request applied, or an error if serving fails.
+    pub async fn run_until(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Accountant> {
+        let ledger = Arc::clone(&self.state.ledger);
+        axum::serve(self.listener, router(self.state))
+            .with_graceful_shutdown(shutdown)
+            .await?;
+
+        let mut accountant = Arc::into_inner(ledger)
+            .ok_or(anyhow!("the ledger is still shared after shutdown"))?
+            .into_inner();
+        accountant.finish()?;
+        Ok(accountant)
+    }
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/transactions", post(submit))
+        .route("/transactions/batch", post(submit_batch))
+        .route("/transactions/{tx}", get(transaction))
+        .route("/clients", get(clients))
+        .route("/clients/{id}", get(client))
+        .with_state(state)
+}
+
+async fn submit(State(state): State<AppState>, body: Bytes) -> Result<Response, ApiError> {
+    let transaction =
+        parse_json(&body).map_err(|error| ApiError::new(StatusCode::BAD_REQUEST, error))?;
+    transaction
+        .validate(state.settings.precision)
+        .map_err(|error| ApiError::new(StatusCode::BAD_REQUEST, error))?;
+
+    let (client, currency) = (*transaction.client(), *transaction.currency());
+    Ok(
+        match apply_update(&mut *state.ledger.lock().await, transaction)? {
+            Some(BalanceUpdate::Applied { balance, .. }) => Json(balance).into_response(),
+            Some(BalanceUpdate::Rejected(rejection)) => {
+                (StatusCode::CONFLICT, Json(rejection)).into_response()
+            }
+            None => Json(
+                ClientBalance::new(client)
+                    .with_currency(currency)
+                    .with_scale(*state.settings.precision.scale()),
+            )
+            .into_response(),
+        },
+    )
+}
+
+async fn submit_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let settings = state.settings;
+    let reader_config = match headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes) {
+        Some(b"text/csv") => settings.reader_config.with_format(InputFormat::Csv),
+        Some(b"application/jsonl" | b"application/x-ndjson") => {
+            settings.reader_config.with_format(InputFormat::Jsonl)
+        }
+        _ => settings.reader_config,
+    };
+    let mut rows = pin!(transaction_stream(
+        &body[..],
+        reader_config,
+        settings.precision
+    ));
+    let mut ingest = IngestReport::default();
+    let mut batch = BatchReport::default();
+
+    while let Some(item) = rows.next().await {
+        let transaction = match item {
+            Ok(transaction) => transaction,
+            Err(row) => {
+                if !row.raw().is_empty() {
+                    batch.rows_read += 1;
+                }
+                let malformed = MalformedView {
+                    line: *row.line(),
+                    byte: *row.byte(),
+                    raw: row.raw().clone(),
+                    error: row.error().to_string(),
+                };
+                if matches!(row.error(), InputError::Io(_)) {
+                    return Err(anyhow::Error::from(row.into_error()).into());
+                }
+                if let Err(error) = ingest.skip(settings.error_policy, row) {
+                    batch.error = Some(error.to_string());
+                    return Ok((StatusCode::BAD_REQUEST, Json(batch)).into_response());
+                }
+                batch.malformed.push(malformed);
+                continue;
+            }
+        };
+
+        batch.rows_read += 1;
+        match apply_update(&mut *state.ledger.lock().await, transaction)? {
+            Some(BalanceUpdate::Rejected(rejection)) => batch.rejected.push(rejection),
+            _ => batch.applied += 1,
+        }
+    }
+
+    Ok(Json(batch).into_response())
+}
+
+async fn transaction(
+    State(state): State<AppState>,
+    tx: Result<Path<u32>, PathRejection>,
+) -> Result<Json<TransactionView>, ApiError> {
+    let Path(tx) = tx?;
+    match state.ledger.lock().await.transaction(tx)? {
+        Some(transaction) => Ok(Json(transaction.into())),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("transaction {tx} not found"),
+        )),
+    }
+}
+
+async fn client(
+    State(state): State<AppState>,
+    id: Result<Path<u16>, PathRejection>,
+    query: Result<Query<BalanceQuery>, QueryRejection>,
+) -> Result<Json<ClientBalance>, ApiError> {
+    let Path(id) = id?;
+    let Query(query) = query?;
+    match state.ledger.lock().await.balance(id, query.currency) {
+        Some(balance) => Ok(Json(balance.clone())),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            match query.currency {
+                Some(currency) => format!("client {id} has no {currency} balance"),
+                None => format!("client {id} not found"),
+            },
+        )),
+    }
+}
+
+async fn clients(
+    State(state): State<AppState>,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(query) = query?;
+    let accountant = state.ledger.lock().await;
+    Ok(match query.format.unwrap_or(Format::Json) {
+        Format::Json => {
+            let mut writer = JsonValueWriter::new();
+            accountant.export(RefCell::new(&mut writer))?;
+            Json(writer.into_values()).into_response()
+        }
+        Format::Csv => {
+            let mut writer = BufferCSVWriter::new();
+            accountant.export(RefCell::new(&mut writer))?;
+            ([(header::CONTENT_TYPE, "text/csv")], writer.into_bytes()?).into_response()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use rust_decimal::prelude::*;
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        router(AppState {
+            ledger: Arc::new(Mutex::new(Accountant::new())),
+            settings: Settings {
+                error_policy: ErrorPolicy::Skip,
+                ..Settings::default()
+            },
+        })
+    }
+
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> Result<(StatusCode, Bytes)> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))?;
+        let response = app.clone().oneshot(request).await?;
+        let status = response.status();
+        Ok((status, to_bytes(response.into_body(), usize::MAX).await?))
+    }
+
+    async fn call_json(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> Result<(StatusCode, Value)> {
+        let (status, body) = call(app, method, uri, body).await?;
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    #[tokio::test]
+    async fn test_submit_transactions() -> Result<()> {
+        let app = app();
+
+        let (status, balance) = call_json(
+            &app,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(balance["available"], "10.5000");
+
+        let (status, rejection) = call_json(
+            &app,
+            "POST",
+            "/transactions",
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "20"}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(rejection["tx"], 2);
+
+        let (status, error) = call_json(
+            &app,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 3, "amount": "-1"}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].is_string());
+
+        let (status, _) = call_json(
+            &app,
+            "POST",
+            "/transactions",
+            r#"{"type": "dispute", "client": 1, "tx": 1}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, transaction) = call_json(&app, "GET", "/transactions/1", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(transaction["state"], "disputed");
+        assert_eq!(transaction["amount"], "10.5000");
+
+        let (status, balance) = call_json(&app, "GET", "/clients/1", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(balance["held"], "10.5000");
+
+        assert_eq!(
+            call(&app, "GET", "/clients/2", "").await?.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(&app, "GET", "/transactions/2", "").await?.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let (status, error) = call_json(&app, "GET", "/clients/x", "").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].is_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance_per_currency() -> Result<()> {
+        let app = app();
+        let (status, balance) = call_json(
+            &app,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2", "currency": "eur"}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(balance["currency"], "EUR");
+
+        let (status, balance) = call_json(&app, "GET", "/clients/1?currency=EUR", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(balance["total"], "2.0000");
+        assert_eq!(
+            call(&app, "GET", "/clients/1", "").await?.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(&app, "GET", "/clients/1?currency=euro!", "").await?.0,
+            StatusCode::BAD_REQUEST
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_submit_batch() -> Result<()> {
+        let app = app();
+        let (status, report) = call_json(
+            &app,
+            "POST",
+            "/transactions/batch",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,1.0\nwithdrawal,2,3,5.0\ndeposit,x,4,1.0\n",
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["rows_read"], 4);
+        assert_eq!(report["applied"], 2);
+        assert_eq!(report["rejected"][0]["tx"], 3);
+        assert_eq!(report["malformed"][0]["line"], 5);
+        assert!(report.get("error").is_none());
+
+        let (status, clients) = call_json(&app, "GET", "/clients", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(clients.as_array().map(Vec::len), Some(2));
+        assert_eq!(clients[0]["total"], "10.0000");
+
+        let (status, csv) = call(&app, "GET", "/clients?format=csv", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            &csv[..],
+            b"client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n2,1.0000,0.0000,1.0000,false\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_submit_json_lines_batch() -> Result<()> {
+        let app = app();
+        let request = Request::builder()
+            .method("POST")
+            .uri("/transactions/batch")
+            .header(header::CONTENT_TYPE, "application/jsonl")
+            .body(Body::from(
+                "{\"type\":\"deposit\",\"client\":4,\"tx\":1,\"amount\":3.5}\n{\"type\":\"dispute\",\"client\":4,\"tx\":1}\n",
+            ))?;
+        let response = app.clone().oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let report: Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await?)?;
+        assert_eq!(report["applied"], 2);
+
+        let (_, balance) = call_json(&app, "GET", "/clients/4", "").await?;
+        assert_eq!(balance["held"], "3.5000");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_stops_on_error_policy() -> Result<()> {
+        let app = router(AppState {
+            ledger: Arc::new(Mutex::new(Accountant::new())),
+            settings: Settings::default(),
+        });
+        let (status, report) = call_json(
+            &app,
+            "POST",
+            "/transactions/batch",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n",
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(report["applied"], 1);
+        assert!(report["error"].is_string());
+        assert_eq!(
+            call(&app, "GET", "/transactions/3", "").await?.0,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serves_over_tcp() -> Result<()> {
+        let server = HttpServer::bind("127.0.0.1:0", Accountant::new()).await?;
+        let addr = server.local_addr()?;
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async {
+            stopped.await.ok();
+        }));
+
+        let mut socket = TcpStream::connect(addr).await?;
+        let body = r#"{"type": "deposit", "client": 7, "tx": 1, "amount": "2"}"#;
+        let request = format!(
+            "POST /transactions HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        socket.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        stop.send(()).ok();
+        let accountant = running.await??;
+        assert_eq!(
+            accountant.client(7).map(|balance| *balance.total()),
+            Some(dec!(2))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Expansion of input arguments into the ordered list of files applied to one ledger.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::{Result, anyhow};
+
+/// InputOrder decides the order in which the files of a directory or glob are processed.
+/// Ties are always broken by file name so the order is deterministic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputOrder {
+    /// Lexicographic order of the paths.
+    #[default]
+    Name,
+    /// Oldest modification time first.
+    Modified,
+    /// Ascending sequence number, the last run of digits in the file name such as `7` in `drop-7.csv.gz`.
+    Sequence,
+}
+
+impl FromStr for InputOrder {
+    type Err = anyhow::Error;
+
+    /// Parses `name`, `mtime` or `sequence`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "name" => Ok(Self::Name),
+            "mtime" => Ok(Self::Modified),
+            "sequence" => Ok(Self::Sequence),
+            _ => Err(anyhow!(
+                "unknown input order {s:?}, expected name, mtime or sequence"
+            )),
+        }
+    }
+}
+
+/// Returns the last run of digits in the file name of `path`.
+fn sequence_of(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let end = name.rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = name[..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |at| at + 1);
+    name[start..end].parse().ok()
+}
+
+/// The sort key of one file under an order.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Name,
+    Modified(SystemTime),
+    Sequence(u64),
+}
+
+/// Sorts `paths` in `order`, breaking ties by path.
+fn sort(paths: Vec<PathBuf>, order: InputOrder) -> Result<Vec<PathBuf>> {
+    let mut keyed = paths
+        .into_iter()
+        .map(|path| {
+            let key = match order {
+                InputOrder::Name => Key::Name,
+                InputOrder::Modified => Key::Modified(fs::metadata(&path)?.modified()?),
+                InputOrder::Sequence => Key::Sequence(
+                    sequence_of(&path)
+                        .ok_or(anyhow!("{} has no sequence number", path.display()))?,
+                ),
+            };
+            Ok((key, path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    keyed.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    Ok(keyed.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Lists the regular files directly in `dir`, leaving out hidden ones.
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Expands input arguments into the files to process, in processing order.
+///
+/// Arguments are processed in the order given. A directory stands for the regular files directly in it
+/// and an argument holding `*`, `?` or `[` for the files it matches; both are sorted by `order`.
+/// A file reached through several arguments is processed once, at its first position.
+///
+/// # Arguments
+/// * `inputs` - Files, directories and glob patterns.
+/// * `order` - The order of the files of a directory or glob.
+///
+/// # Returns
+/// The files, or an error if a directory or glob holds no file or a file cannot be ordered.
+pub fn expand_inputs(inputs: &[impl AsRef<str>], order: InputOrder) -> Result<Vec<PathBuf>> {
+    let mut expanded: Vec<PathBuf> = Vec::new();
+    let mut seen = Vec::new();
+    for input in inputs {
+        let input = input.as_ref();
+        let path = Path::new(input);
+        let files = if path.is_dir() {
+            sort(files_in(path)?, order)?
+        } else if input.contains(['*', '?', '[']) {
+            let mut files = Vec::new();
+            for matched in glob::glob(input)? {
+                let matched = matched?;
+                if matched.is_file() {
+                    files.push(matched);
+                }
+            }
+            sort(files, order)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        if files.is_empty() {
+            Err(anyhow!("{input} holds no input file"))?
+        }
+
+        for file in files {
+            let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+            if !seen.contains(&canonical) {
+                seen.push(canonical);
+                expanded.push(file);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn scratch(name: &str) -> Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("play-with-csv-{}-{name}", process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_sequence_of() {
+        assert_eq!(sequence_of(Path::new("drop-7.csv.gz")), Some(7));
+        assert_eq!(sequence_of(Path::new("dir9/2024_0012.csv")), Some(12));
+        assert_eq!(sequence_of(Path::new("drop.csv")), None);
+    }
+
+    #[test]
+    fn test_directory_and_glob_are_ordered() -> Result<()> {
+        let dir = scratch("inputs")?;
+        for name in ["drop-10.csv", "drop-9.csv", "drop-100.csv", ".hidden-1.csv"] {
+            fs::write(dir.join(name), "type,client,tx,amount\n")?;
+        }
+        fs::create_dir(dir.join("nested-1"))?;
+        let dir_arg = dir.to_string_lossy().into_owned();
+
+        assert_eq!(
+            names(&expand_inputs(&[&dir_arg], InputOrder::Name)?),
+            ["drop-10.csv", "drop-100.csv", "drop-9.csv"]
+        );
+        assert_eq!(
+            names(&expand_inputs(&[&dir_arg], InputOrder::Sequence)?),
+            ["drop-9.csv", "drop-10.csv", "drop-100.csv"]
+        );
+
+        let glob = format!("{dir_arg}/drop-1*.csv");
+        let single = format!("{dir_arg}/drop-9.csv");
+        assert_eq!(
+            names(&expand_inputs(
+                &[&single, &glob, &dir_arg],
+                InputOrder::Name
+            )?),
+            ["drop-9.csv", "drop-10.csv", "drop-100.csv"]
+        );
+
+        let nothing = format!("{dir_arg}/*.parquet");
+        assert!(expand_inputs(&[&nothing], InputOrder::Name).is_err());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_order_from_str() -> Result<()> {
+        assert_eq!("name".parse::<InputOrder>()?, InputOrder::Name);
+        assert_eq!("mtime".parse::<InputOrder>()?, InputOrder::Modified);
+        assert_eq!("sequence".parse::<InputOrder>()?, InputOrder::Sequence);
+        assert!("size".parse::<InputOrder>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Append-only write-ahead journal of the transactions handed to the ledger.
+//!
+//! The journal starts with the `PWCJ` magic and a `u16` format version, followed by entries.
+//! Every entry is a `u32` payload length, the payload and a FNV-1a `u32` checksum of the payload.
+//! The payload holds the input offset just past the record the transaction was read from and
+//! the transaction itself, encoded the same way as in a snapshot.
+//!
+//! Replaying the journal in order re-applies exactly the same transactions and so reaches exactly
+//! the same accepted and rejected outcome. A torn or corrupt tail left by a crash is truncated on open.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::SnapshotError,
+    models::Transaction,
+    snapshot::{Decode, Encode},
+};
+use anyhow::Result;
+
+const MAGIC: &[u8; 4] = b"PWCJ";
+const VERSION: u16 = 1;
+
+/// Default number of entries appended between two `fsync` calls.
+pub const DEFAULT_SYNC_EVERY: usize = 1000;
+
+/// Write-ahead journal appending transactions before they are applied.
+pub struct Journal {
+    writer: BufWriter<File>,
+    sync_every: usize,
+    pending: usize,
+}
+
+impl Journal {
+    /// Opens or creates the journal at `path`, replaying every complete entry through `replay`.
+    ///
+    /// # Arguments
+    /// * `path` - The journal file.
+    /// * `sync_every` - Number of appended entries after which the journal is flushed and synced to disk.
+    /// * `replay` - Called with every journaled transaction, in the order it was appended.
+    ///
+    /// # Returns
+    /// The journal positioned for appending.
+    pub fn open(
+        path: impl AsRef<Path>,
+        sync_every: usize,
+        mut replay: impl FnMut(Transaction) -> Result<()>,
+    ) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            VERSION.encode(&mut file)?;
+            file.sync_data()?;
+        } else {
+            let valid_end = Self::replay(&mut file, &mut replay)?;
+            file.set_len(valid_end)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            sync_every: sync_every.max(1),
+            pending: 0,
+        })
+    }
+
+    /// Appends a transaction, syncing the journal to disk once `sync_every` entries are pending.
+    ///
+    /// # Arguments
+    /// * `transaction` - The transaction about to be applied to the ledger.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn append(&mut self, transaction: &Transaction) -> Result<()> {
+        let mut payload = Vec::new();
+        transaction.offset().encode(&mut payload)?;
+        transaction.encode(&mut payload)?;
+
+        (payload.len() as u32).encode(&mut self.writer)?;
+        self.writer.write_all(&payload)?;
+        checksum(&payload).encode(&mut self.writer)?;
+
+        self.pending += 1;
+        if self.pending >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered entries and syncs them to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Replays all complete entries and returns the byte offset just past the last one.
+    fn replay(file: &mut File, replay: &mut impl FnMut(Transaction) -> Result<()>) -> Result<u64> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            Err(SnapshotError::UnknownFormat)?
+        }
+        match u16::decode(&mut reader)? {
+            VERSION => (),
+            version => Err(SnapshotError::UnsupportedVersion(version))?,
+        }
+
+        let mut valid_end = reader.stream_position()?;
+        while let Some(transaction) = Self::read_entry(&mut reader, len - valid_end)? {
+            replay(transaction)?;
+            valid_end = reader.stream_position()?;
+        }
+        Ok(valid_end)
+    }
+
+    /// Reads the next entry, returning `None` at the end of the journal or at a torn or corrupt entry.
+    ///
+    /// An entry claiming a payload longer than the `remaining` bytes of the journal is torn; its length is
+    /// checked before anything is allocated, since a corrupt length can be up to 4 GiB.
+    fn read_entry(reader: &mut impl Read, remaining: u64) -> Result<Option<Transaction>> {
+        let len = match u32::decode(reader) {
+            Ok(len) => len,
+            Err(SnapshotError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            Err(err) => Err(err)?,
+        };
+        if u64::from(len) + 8 > remaining {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        let mut sum = [0u8; 4];
+        match reader
+            .read_exact(&mut payload)
+            .and_then(|_| reader.read_exact(&mut sum))
+        {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => Err(err)?,
+        }
+        if u32::from_le_bytes(sum) != checksum(&payload) {
+            return Ok(None);
+        }
+
+        let mut payload = payload.as_slice();
+        let offset = Option::<u64>::decode(&mut payload)?;
+        let transaction = Transaction::decode(&mut payload)?;
+        Ok(Some(match offset {
+            Some(offset) => transaction.with_offset(offset),
+            None => transaction,
+        }))
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// FNV-1a hash, enough to detect a torn or partially written entry.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+    use rust_decimal::prelude::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("journal-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10.5)))
+                .with_line(2)
+                .with_offset(40),
+            Transaction::new(TransactionType::Dispute, 1, 1, None)
+                .with_line(3)
+                .with_offset(53),
+        ]
+    }
+
+    fn replayed(path: &Path) -> Result<Vec<Transaction>> {
+        let mut replayed = Vec::new();
+        Journal::open(path, 1, |tx| {
+            replayed.push(tx);
+            Ok(())
+        })?;
+        Ok(replayed)
+    }
+
+    #[test]
+    fn test_replays_appended_entries() -> Result<()> {
+        let path = journal_path("replay");
+        {
+            let mut journal = Journal::open(&path, 10, |_| Ok(()))?;
+            for tx in transactions() {
+                journal.append(&tx)?;
+            }
+        }
+
+        assert_eq!(replayed(&path)?, transactions());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_tail_is_truncated() -> Result<()> {
+        let path = journal_path("torn");
+        {
+            let mut journal = Journal::open(&path, 1, |_| Ok(()))?;
+            for tx in transactions() {
+                journal.append(&tx)?;
+            }
+        }
+        let len = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 3)?;
+
+        assert_eq!(replayed(&path)?, transactions()[..1]);
+
+        {
+            let mut journal = Journal::open(&path, 1, |_| Ok(()))?;
+            journal.append(&transactions()[1])?;
+        }
+        assert_eq!(replayed(&path)?, transactions());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_entry_stops_replay() -> Result<()> {
+        let path = journal_path("corrupt");
+        {
+            let mut journal = Journal::open(&path, 1, |_| Ok(()))?;
+            for tx in transactions() {
+                journal.append(&tx)?;
+            }
+        }
+        let mut bytes = fs::read(&path)?;
+        let last = bytes.len() - 5;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes)?;
+
+        assert_eq!(replayed(&path)?, transactions()[..1]);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversize_length_is_a_torn_tail() -> Result<()> {
+        let path = journal_path("oversize");
+        {
+            let mut journal = Journal::open(&path, 1, |_| Ok(()))?;
+            journal.append(&transactions()[0])?;
+        }
+        let len = fs::metadata(&path)?.len();
+        let mut bytes = fs::read(&path)?;
+        bytes.extend_from_slice(&[0xf0, 0xff, 0xff, 0xff, 1, 2, 3]);
+        fs::write(&path, bytes)?;
+
+        assert_eq!(replayed(&path)?, transactions()[..1]);
+        assert_eq!(fs::metadata(&path)?.len(), len);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_foreign_file() -> Result<()> {
+        let path = journal_path("foreign");
+        fs::write(&path, b"type,client,tx,amount\n")?;
+        assert!(Journal::open(&path, 1, |_| Ok(())).is_err());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
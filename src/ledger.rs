@@ -1,134 +1,860 @@
 use crate::{
-    errors::LedgerError,
-    models::{ClientBalance, Transaction, TransactionType},
-    traits::{Accounting, CSVExport, CSVWrite},
+    errors::{AmountError, LedgerError},
+    fees::{FeePosting, FeeSchedule},
+    journal::Journal,
+    models::{
+        ClientBalance, Currency, CurrencyBalance, Rejection, Transaction, TransactionState,
+        TransactionType,
+    },
+    precision::{Precision, Residual},
+    snapshot::{self, Decode, Encode},
+    store::MemoryStore,
+    traits::{Accounting, CSVExport, RecordWrite, TransactionStore},
 };
-use anyhow::Result;
+use anyhow::{Result, anyhow, ensure};
+use rust_decimal::Decimal;
 use std::{
-    cell::{Cell, RefCell},
+    cell::RefCell,
+    cmp::Ordering,
     collections::{HashMap, hash_map::Entry},
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
 };
 
+/// ExportOrder decides the order in which client balances are exported.
+/// Ties are always broken by ascending client id so the output is deterministic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportOrder {
+    /// Ascending client id.
+    #[default]
+    Client,
+    /// Descending total balance.
+    Total,
+    /// Descending available balance.
+    Available,
+    /// Locked accounts first.
+    Locked,
+}
+
+impl FromStr for ExportOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "client" => Ok(Self::Client),
+            "total" => Ok(Self::Total),
+            "available" => Ok(Self::Available),
+            "locked" => Ok(Self::Locked),
+            _ => Err(anyhow!(
+                "unknown export order {s:?}, expected client, total, available or locked"
+            )),
+        }
+    }
+}
+
+/// Client balances keyed by client and currency.
+type Balances = HashMap<(u16, Option<Currency>), ClientBalance>;
+
+/// Accountant keeps the client balances and the stored transactions they were built from.
+///
+/// Every client has one balance per currency its transactions are in, and one for transactions without a currency.
+///
+/// Deposit, withdrawal and transfer amounts are brought to the precision policy scale before they are applied
+/// and stored. Disputes, resolves and chargebacks move those stored amounts, so every balance operation
+/// works on amounts of that scale and balances never carry digits the export would not show.
+///
+/// With a fee schedule every accepted deposit and withdrawal is charged its fee, which moves to the house
+/// account as a `FeePosting` linked to the transaction. A withdrawal must cover its amount and fee.
+/// A deposit is stored net of its fee, so a later dispute, resolve or chargeback moves only what the client
+/// was credited and the fee stays with the house account.
 pub struct Accountant {
-    clients: HashMap<u16, ClientBalance>,
-    transactions: HashMap<u32, Transaction>,
+    clients: Balances,
+    transactions: Box<dyn TransactionStore>,
     transaction_historical_order: Vec<u32>,
-    transactions_rejected: Vec<u32>,
+    transactions_rejected: Vec<Rejection>,
+    residuals: HashMap<Option<Currency>, Residual>,
+    fee_postings: Vec<FeePosting>,
+    precision: Precision,
+    fees: Option<FeeSchedule>,
+    export_order: ExportOrder,
+    journal: Option<Journal>,
 }
 
 impl Accountant {
+    /// Creates an empty ledger.
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
-            transactions: HashMap::new(),
+            transactions: Box::new(MemoryStore::new()),
             transaction_historical_order: Vec::new(),
             transactions_rejected: Vec::new(),
+            residuals: HashMap::new(),
+            fee_postings: Vec::new(),
+            precision: Precision::default(),
+            fees: None,
+            export_order: ExportOrder::default(),
+            journal: None,
+        }
+    }
+
+    /// Sets the precision policy amounts are rounded or refused under.
+    ///
+    /// Balances already kept, such as those of a restored snapshot, are rounded to the policy scale so every
+    /// balance is exported with the same number of decimal places.
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        for client in self.clients.values_mut() {
+            client.round(&precision);
+        }
+        self
+    }
+
+    /// Charges every deposit and withdrawal the fee `fees` decides and refuses transactions of its house account.
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    /// Sets the order in which client balances are exported.
+    pub fn with_export_order(mut self, export_order: ExportOrder) -> Self {
+        self.export_order = export_order;
+        self
+    }
+
+    /// Moves the stored transactions into `store` and keeps them there from then on.
+    ///
+    /// # Arguments
+    /// * `store` - The transaction store, for example a `SpillStore` bounded by a memory budget.
+    ///
+    /// # Returns
+    /// The accountant backed by `store`.
+    pub fn with_store(mut self, mut store: Box<dyn TransactionStore>) -> Result<Self> {
+        self.transactions
+            .drain(&mut |transaction| store.insert(transaction))?;
+        self.transactions = store;
+        Ok(self)
+    }
+
+    /// Writes the full ledger state to `writer` in the versioned binary snapshot format.
+    ///
+    /// The snapshot covers client balances, stored transactions with their dispute state,
+    /// the historical order, the rejections, the rounding residuals and the fee postings.
+    /// The precision policy, the fee schedule and the export order are configuration and are not stored.
+    ///
+    /// # Arguments
+    /// * `writer` - The destination of the snapshot.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn snapshot(&self, mut writer: impl Write) -> Result<()> {
+        let mut clients: Vec<&ClientBalance> = self.clients.values().collect();
+        clients.sort_unstable_by_key(|client| client.key());
+
+        snapshot::write_header(&mut writer)?;
+        clients.encode(&mut writer)?;
+        (self.transactions.len() as u64).encode(&mut writer)?;
+        self.transactions
+            .for_each(&mut |transaction| Ok(transaction.encode(&mut writer)?))?;
+        self.transaction_historical_order.encode(&mut writer)?;
+        self.transactions_rejected.encode(&mut writer)?;
+        self.residuals().encode(&mut writer)?;
+        self.fee_postings.encode(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Rebuilds an accountant from a snapshot written by `Accountant::snapshot`.
+    ///
+    /// # Arguments
+    /// * `reader` - The source of the snapshot.
+    ///
+    /// # Returns
+    /// The restored accountant or an error if the snapshot is malformed or of an unsupported version.
+    pub fn restore(reader: impl Read) -> Result<Self> {
+        Self::restore_with_store(reader, Box::new(MemoryStore::new()))
+    }
+
+    /// Rebuilds an accountant from a snapshot written by `Accountant::snapshot`, loading the stored
+    /// transactions straight into `store`.
+    ///
+    /// # Arguments
+    /// * `reader` - The source of the snapshot.
+    /// * `store` - The empty transaction store to load into.
+    ///
+    /// # Returns
+    /// The restored accountant or an error if the snapshot is malformed or of an unsupported version.
+    pub fn restore_with_store(
+        mut reader: impl Read,
+        mut store: Box<dyn TransactionStore>,
+    ) -> Result<Self> {
+        snapshot::read_header(&mut reader)?;
+        let clients = Vec::<ClientBalance>::decode(&mut reader)?;
+        for _ in 0..u64::decode(&mut reader)? {
+            store.insert(Transaction::decode(&mut reader)?)?;
         }
+
+        Ok(Self {
+            clients: clients
+                .into_iter()
+                .map(|client| (client.key(), client))
+                .collect(),
+            transactions: store,
+            transaction_historical_order: Vec::decode(&mut reader)?,
+            transactions_rejected: Vec::decode(&mut reader)?,
+            residuals: Vec::<Residual>::decode(&mut reader)?
+                .into_iter()
+                .map(|residual| (*residual.currency(), residual))
+                .collect(),
+            fee_postings: Vec::decode(&mut reader)?,
+            precision: Precision::default(),
+            fees: None,
+            export_order: ExportOrder::default(),
+            journal: None,
+        })
+    }
+
+    /// Opens the write-ahead journal at `path`, replays its entries on top of the current state
+    /// and from then on journals every transaction before it touches any balance.
+    ///
+    /// # Arguments
+    /// * `path` - The journal file, created if missing.
+    /// * `sync_every` - Number of journaled transactions between two syncs to disk.
+    ///
+    /// # Returns
+    /// The input offset just past the last replayed record, from which processing can resume.
+    pub fn attach_journal(
+        &mut self,
+        path: impl AsRef<Path>,
+        sync_every: usize,
+    ) -> Result<Option<u64>> {
+        let mut resume_offset = None;
+        let journal = Journal::open(path, sync_every, |transaction| {
+            resume_offset = transaction.offset().or(resume_offset);
+            self.apply(transaction)
+        })?;
+        self.journal = Some(journal);
+        Ok(resume_offset)
+    }
+
+    /// Syncs and detaches the write-ahead journal, if any.
+    pub fn close_journal(&mut self) -> Result<()> {
+        if let Some(mut journal) = self.journal.take() {
+            journal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the shard owning `client` when the ledger is split into `shards` shards.
+    pub(crate) fn shard_of(client: u16, shards: usize) -> usize {
+        usize::from(client) % shards
+    }
+
+    /// Moves the clients and stored transactions into one ledger per store, each owning the clients
+    /// `shard_of` assigns to it. The historical order, rejections, residuals, fee postings and the emptied store
+    /// stay here.
+    ///
+    /// # Arguments
+    /// * `stores` - One empty transaction store per shard.
+    ///
+    /// # Returns
+    /// The shards.
+    pub(crate) fn split(
+        &mut self,
+        stores: Vec<Box<dyn TransactionStore>>,
+    ) -> Result<Vec<Accountant>> {
+        let count = stores.len();
+        let mut parts: Vec<Accountant> = stores
+            .into_iter()
+            .map(|transactions| Accountant {
+                transactions,
+                precision: self.precision,
+                fees: self.fees.clone(),
+                ..Accountant::new()
+            })
+            .collect();
+        for (key, client) in self.clients.drain() {
+            parts[Self::shard_of(key.0, count)]
+                .clients
+                .insert(key, client);
+        }
+        self.transactions.drain(&mut |transaction| {
+            parts[Self::shard_of(*transaction.client(), count)]
+                .transactions
+                .insert(transaction)
+        })?;
+        Ok(parts)
+    }
+
+    /// Moves the clients and stored transactions of ledgers built by `split` back into this one.
+    ///
+    /// A client present in several shards keeps the balance of the shard owning it; the copies in other
+    /// shards are empty balances created by rows that were rejected there.
+    /// The house account is the exception: every shard credits its own copy with the fees it charged,
+    /// so the copies of every currency are added up. Every shard must charge the fees of this ledger.
+    /// The shards' own historical order, rejections and fee postings are dropped in favour of the given ones,
+    /// which are appended to this ledger's. The residuals of the shards are added to this ledger's.
+    pub(crate) fn merge(
+        &mut self,
+        shards: Vec<Accountant>,
+        transaction_historical_order: Vec<u32>,
+        transactions_rejected: Vec<Rejection>,
+        fee_postings: Vec<FeePosting>,
+    ) -> Result<()> {
+        let count = shards.len();
+        let house = self.fees.as_ref().map(|fees| *fees.house());
+        for (index, mut shard) in shards.into_iter().enumerate() {
+            ensure!(
+                shard.fees == self.fees,
+                "shard {index} charges other fees than the ledger it was split from"
+            );
+            for (key, client) in shard.clients.drain() {
+                if Some(key.0) == house {
+                    match self.clients.entry(key) {
+                        Entry::Occupied(mut collected) if *client.total() > Decimal::ZERO => {
+                            collected.get_mut().deposit(*client.total())?
+                        }
+                        Entry::Occupied(_) => {}
+                        Entry::Vacant(vacant) => {
+                            vacant.insert(client);
+                        }
+                    }
+                } else if Self::shard_of(key.0, count) == index {
+                    self.clients.insert(key, client);
+                } else {
+                    self.clients.entry(key).or_insert(client);
+                }
+            }
+            let transactions = &mut self.transactions;
+            shard
+                .transactions
+                .drain(&mut |transaction| transactions.insert(transaction))?;
+            for (currency, residual) in shard.residuals {
+                self.residuals
+                    .entry(currency)
+                    .or_insert_with(|| Residual::new(currency))
+                    .merge(&residual);
+            }
+        }
+        self.transaction_historical_order
+            .extend(transaction_historical_order);
+        self.transactions_rejected.extend(transactions_rejected);
+        self.fee_postings.extend(fee_postings);
+        Ok(())
+    }
+
+    /// Removes and returns the rejections recorded so far.
+    pub(crate) fn take_rejections(&mut self) -> Vec<Rejection> {
+        std::mem::take(&mut self.transactions_rejected)
+    }
+
+    /// Removes and returns the fee postings recorded so far.
+    pub(crate) fn take_fee_postings(&mut self) -> Vec<FeePosting> {
+        std::mem::take(&mut self.fee_postings)
+    }
+
+    /// Returns the ids of the stored deposits, withdrawals and transfers, with the receiving client of transfers.
+    pub(crate) fn transaction_ids(&self) -> Result<Vec<(u32, Option<u16>)>> {
+        let mut ids = Vec::with_capacity(self.transactions.len());
+        self.transactions.for_each(&mut |transaction| {
+            ids.push((*transaction.tx(), *transaction.to()));
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    /// Returns the balance of `client` in transactions without a currency, if the client has been seen.
+    pub fn client(&self, client: u16) -> Option<&ClientBalance> {
+        self.balance(client, None)
+    }
+
+    /// Returns the balance of `client` in `currency`, if the client has been seen in it.
+    ///
+    /// # Arguments
+    /// * `client` - The client id.
+    /// * `currency` - The currency, `None` for transactions without one.
+    pub fn balance(&self, client: u16, currency: Option<Currency>) -> Option<&ClientBalance> {
+        self.clients.get(&(client, currency))
+    }
+
+    /// Returns every balance of `client`, the one without a currency first and the others by currency.
+    pub fn balances(&self, client: u16) -> Vec<&ClientBalance> {
+        let mut balances: Vec<&ClientBalance> = self
+            .clients
+            .values()
+            .filter(|balance| *balance.client() == client)
+            .collect();
+        balances.sort_unstable_by_key(|balance| balance.key());
+        balances
+    }
+
+    /// Writes every balance of `client` the way `CSVExport::export` writes it.
+    ///
+    /// # Arguments
+    /// * `client` - The client id.
+    /// * `writer` - The destination of the balances.
+    ///
+    /// # Returns
+    /// Whether the client has been seen.
+    pub fn export_client(
+        &self,
+        client: u16,
+        writer: RefCell<&mut impl RecordWrite>,
+    ) -> Result<bool> {
+        let balances = self.balances(client);
+        self.write_balances(&balances, writer)?;
+        Ok(!balances.is_empty())
+    }
+
+    /// Writes `balances`, with a currency column on every row once any balance of the ledger has a currency.
+    fn write_balances(
+        &self,
+        balances: &[&ClientBalance],
+        writer: RefCell<&mut impl RecordWrite>,
+    ) -> Result<()> {
+        let with_currency = self.clients.keys().any(|(_, currency)| currency.is_some());
+        for balance in balances {
+            match with_currency {
+                true => writer
+                    .borrow_mut()
+                    .write_record(&CurrencyBalance::from(*balance))?,
+                false => writer.borrow_mut().write_record(balance)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the stored deposit or withdrawal `tx` with its dispute state, if it was accepted.
+    pub fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        self.transactions.get(tx)
+    }
+
+    /// Returns every transaction refused so far, in the order it was received.
+    pub fn rejections(&self) -> &[Rejection] {
+        &self.transactions_rejected
+    }
+
+    /// Returns what rounding took away from the accepted amounts, one residual per currency rounded in,
+    /// the one without a currency first and the others by currency.
+    pub fn residuals(&self) -> Vec<&Residual> {
+        let mut residuals: Vec<&Residual> = self.residuals.values().collect();
+        residuals.sort_unstable_by_key(|residual| *residual.currency());
+        residuals
+    }
+
+    /// Returns every fee charged so far, in the order the charged transactions were received.
+    pub fn fee_postings(&self) -> &[FeePosting] {
+        &self.fee_postings
+    }
+}
+
+impl Default for Accountant {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Accounting for Accountant {
     fn apply_bookkeeping(&mut self, transaction: Transaction) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.append(&transaction)?;
+        }
+        self.apply(transaction)
+    }
+
+    fn precision(&self) -> Precision {
+        self.precision
+    }
+}
+
+impl CSVExport for Accountant {
+    fn export(&self, writer: RefCell<&mut impl RecordWrite>) -> Result<()> {
+        let mut clients: Vec<&ClientBalance> = self.clients.values().collect();
+        clients.sort_unstable_by(|a, b| {
+            let by_order = match self.export_order {
+                ExportOrder::Client => Ordering::Equal,
+                ExportOrder::Total => b.total().cmp(a.total()),
+                ExportOrder::Available => b.available().cmp(a.available()),
+                ExportOrder::Locked => b.locked().cmp(a.locked()),
+            };
+            by_order.then_with(|| a.key().cmp(&b.key()))
+        });
+
+        self.write_balances(&clients, writer)
+    }
+}
+
+impl Accountant {
+    /// Applies a transaction to the ledger, recording it as rejected if it cannot be applied.
+    fn apply(&mut self, transaction: Transaction) -> Result<()> {
+        self.apply_with(None, transaction)
+    }
+
+    /// Applies a transaction to the ledger, recording it as rejected if it cannot be applied.
+    ///
+    /// A transfer, or a dispute, resolve or chargeback of one, moves funds between two clients.
+    /// Both balances are updated on copies that replace the originals only once every step succeeded,
+    /// so either both clients change or neither does.
+    ///
+    /// # Arguments
+    /// * `counterpart` - The ledger holding the receiving client of a transfer, this one when `None`.
+    /// * `transaction` - The transaction to apply.
+    pub(crate) fn apply_with(
+        &mut self,
+        mut counterpart: Option<&mut Accountant>,
+        transaction: Transaction,
+    ) -> Result<()> {
         let transaction_id = *transaction.tx();
+        let is_stored = matches!(
+            transaction.type_(),
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        );
+        self.transaction_historical_order.push(transaction_id);
 
-        match self.transactions.entry(transaction_id) {
-            Entry::Vacant(entry) => {
-                entry.insert(transaction.clone());
-                self.transaction_historical_order.push(transaction_id);
+        if let Some(reason) =
+            Self::check_destination(&transaction).or_else(|| self.check_house(&transaction))
+        {
+            self.transactions_rejected
+                .push(Rejection::new(&transaction, reason));
+            return Ok(());
+        }
+        if is_stored && self.transactions.contains(transaction_id)? {
+            self.transactions_rejected.push(Rejection::new(
+                &transaction,
+                LedgerError::TxDuplicated(transaction_id),
+            ));
+            return Ok(());
+        }
+        let received = transaction.amount().filter(|_| is_stored);
+        let transaction = match received.map(|amount| self.precision.apply(amount)) {
+            None => transaction,
+            Some(Ok(rounded)) => transaction.with_amount(rounded),
+            Some(Err(error)) => {
+                let (tx, max_scale) = (transaction_id, *self.precision.scale());
+                let reason = match error {
+                    AmountError::TooLarge { .. } => LedgerError::TooLarge { tx, max_scale },
+                    _ => LedgerError::TooPrecise { tx, max_scale },
+                };
+                self.transactions_rejected
+                    .push(Rejection::new(&transaction, reason));
+                return Ok(());
+            }
+        };
+        let referenced = match is_stored {
+            true => None,
+            false => self.transactions.get(transaction_id)?,
+        };
+
+        let client_id = *transaction.client();
+        let currency = match &referenced {
+            Some(referenced) => *referenced.currency(),
+            None => *transaction.currency(),
+        };
+        let fee = match self.fee_of(&transaction) {
+            Ok(fee) => fee,
+            Err(reason) => {
+                self.transactions_rejected
+                    .push(Rejection::new(&transaction, reason));
+                return Ok(());
             }
-            Entry::Occupied(_) => match transaction.type_() {
-                TransactionType::Deposit | TransactionType::Withdrawal => {
-                    Err(LedgerError::TxDuplicated(transaction_id))?
+        };
+        let receiver_id = match transaction.type_() {
+            TransactionType::Transfer => *transaction.to(),
+            TransactionType::Deposit | TransactionType::Withdrawal => self
+                .fees
+                .as_ref()
+                .filter(|_| fee > Decimal::ZERO)
+                .map(|fees| *fees.house()),
+            _ => referenced.as_ref().and_then(|referenced| *referenced.to()),
+        };
+        let scale = *self.precision.scale();
+        let Some(receiver_id) = receiver_id else {
+            let client = Self::balance_of(&mut self.clients, client_id, currency, scale);
+            match Self::update_client_balance(client, None, &transaction, referenced, fee) {
+                Ok(moved) => {
+                    self.add_residual(currency, received, &transaction);
+                    self.transactions.insert(moved.unwrap_or(transaction))?
                 }
-                _ => (),
-            },
+                Err(reason) => self
+                    .transactions_rejected
+                    .push(Rejection::new(&transaction, reason)),
+            }
+            return Ok(());
+        };
+
+        let receivers = Self::receivers(&mut self.clients, counterpart.as_deref_mut());
+        let mut receiver = Self::copy_of(receivers, receiver_id, currency, scale);
+        let mut client = Self::balance_of(&mut self.clients, client_id, currency, scale).clone();
+
+        match Self::update_client_balance(
+            &mut client,
+            Some(&mut receiver),
+            &transaction,
+            referenced,
+            fee,
+        ) {
+            Ok(moved) => {
+                self.clients.insert(client.key(), client);
+                Self::receivers(&mut self.clients, counterpart).insert(receiver.key(), receiver);
+                self.add_residual(currency, received, &transaction);
+                if fee > Decimal::ZERO {
+                    self.fee_postings.push(FeePosting::new(
+                        transaction_id,
+                        client_id,
+                        receiver_id,
+                        currency,
+                        fee,
+                    ));
+                }
+                self.transactions
+                    .insert(moved.unwrap_or_else(|| Self::net_of_fee(transaction, fee)))?
+            }
+            Err(reason) => self
+                .transactions_rejected
+                .push(Rejection::new(&transaction, reason)),
         }
-        self.transaction_historical_order.push(transaction_id);
+        Ok(())
+    }
 
-        let client_id = *transaction.client();
+    /// Records what rounding took from the `received` amount of the accepted `transaction`.
+    fn add_residual(
+        &mut self,
+        currency: Option<Currency>,
+        received: Option<Decimal>,
+        transaction: &Transaction,
+    ) {
+        if let (Some(received), Some(rounded)) = (received, *transaction.amount()) {
+            self.residuals
+                .entry(currency)
+                .or_insert_with(|| Residual::new(currency))
+                .add(received, rounded);
+        }
+    }
+
+    /// Returns the fee of a deposit or withdrawal rounded to the precision policy, zero without a fee schedule.
+    fn fee_of(&self, tx: &Transaction) -> Result<Decimal, LedgerError> {
+        match (&self.fees, tx.type_(), tx.amount()) {
+            (Some(fees), TransactionType::Deposit | TransactionType::Withdrawal, Some(amount)) => {
+                let fee = fees.fee(*tx.type_(), *tx.client(), *tx.currency(), *amount);
+                Ok(self.precision.round(fee.ok_or(LedgerError::ValueOverflow)?))
+            }
+            _ => Ok(Decimal::ZERO),
+        }
+    }
 
-        let clients = RefCell::new(&mut self.clients);
-        let transactions_rejected = RefCell::new(&mut self.transactions_rejected);
-        let transactions = Cell::new(&self.transactions);
+    /// Returns why `tx` is refused if it is made by or sent to the house account collecting fees.
+    fn check_house(&self, tx: &Transaction) -> Option<LedgerError> {
+        let house = *self.fees.as_ref()?.house();
+        (*tx.client() == house || *tx.to() == Some(house))
+            .then_some(LedgerError::HouseAccount(house))
+    }
 
+    /// Returns the balance of `client` in `currency`, created empty with `scale` decimal places if missing.
+    fn balance_of(
+        clients: &mut Balances,
+        client: u16,
+        currency: Option<Currency>,
+        scale: u32,
+    ) -> &mut ClientBalance {
+        clients.entry((client, currency)).or_insert_with(|| {
+            ClientBalance::new(client)
+                .with_currency(currency)
+                .with_scale(scale)
+        })
+    }
+
+    /// Returns a copy of the balance of `client` in `currency`, or a new one when it has none,
+    /// without adding it to `clients`.
+    fn copy_of(
+        clients: &Balances,
+        client: u16,
+        currency: Option<Currency>,
+        scale: u32,
+    ) -> ClientBalance {
         clients
-            .borrow_mut()
-            .entry(client_id)
-            .and_modify(|client| {
-                match Self::update_client_balance(transactions.clone(), client, &transaction) {
-                    Ok(_) => (),
-                    Err(_) => transactions_rejected.borrow_mut().push(transaction_id),
-                }
+            .get(&(client, currency))
+            .cloned()
+            .unwrap_or_else(|| {
+                ClientBalance::new(client)
+                    .with_currency(currency)
+                    .with_scale(scale)
             })
-            .or_insert_with(|| {
-                let mut client = ClientBalance::new(client_id);
-                match Self::update_client_balance(transactions, &mut client, &transaction) {
-                    Ok(_) => (),
-                    Err(_) => transactions_rejected.borrow_mut().push(transaction_id),
-                }
-                client
-            });
+    }
 
-        Ok(())
+    /// Returns the clients of `counterpart`, or `clients` when there is none.
+    fn receivers<'a>(
+        clients: &'a mut Balances,
+        counterpart: Option<&'a mut Accountant>,
+    ) -> &'a mut Balances {
+        match counterpart {
+            Some(counterpart) => &mut counterpart.clients,
+            None => clients,
+        }
     }
-}
 
-impl CSVExport for Accountant {
-    fn export(&self, writer: RefCell<&mut impl CSVWrite>) -> Result<()> {
-        for client in self.clients.values() {
-            writer.borrow_mut().write_record(client)?;
+    /// Returns why the receiving client named on `tx` is refused, if it is.
+    fn check_destination(tx: &Transaction) -> Option<LedgerError> {
+        match (tx.type_(), tx.to()) {
+            (TransactionType::Transfer, Some(to)) if to == tx.client() => {
+                Some(LedgerError::TransferToSelf(*tx.tx()))
+            }
+            (TransactionType::Transfer, _) | (_, None) => None,
+            (_, Some(_)) => Some(LedgerError::UnexpectedDestination(*tx.tx())),
         }
-        Ok(())
     }
-}
 
-impl Accountant {
+    /// Applies `tx` to the client balance, and to the `receiver` balance for transfers.
+    ///
+    /// A deposit or withdrawal charged a `fee` moves it from the client to the `receiver`, the house account.
+    /// A deposit only credits the client its amount net of the fee and is refused if nothing would be left.
+    /// Disputing a transfer holds the funds at the receiving client, resolving it releases them and
+    /// charging it back returns them to the sending client and locks the receiving account.
+    /// Both accounts must be unlocked for every step.
+    ///
+    /// # Returns
+    /// The `referenced` transaction moved to its next state for disputes, resolves and chargebacks,
+    /// nothing for deposits, withdrawals and transfers.
     fn update_client_balance(
-        transactions: Cell<&HashMap<u32, Transaction>>,
         client: &mut ClientBalance,
+        receiver: Option<&mut ClientBalance>,
         tx: &Transaction,
-    ) -> Result<()> {
-        match tx.type_() {
-            TransactionType::Deposit => client.deposit(tx.amount()),
-            TransactionType::Withdrawal => client.withdraw(tx.amount()),
-            TransactionType::Dispute => client.dispute(
-                transactions
-                    .get()
-                    .get(tx.tx())
-                    .ok_or(LedgerError::TxNotFound(*tx.tx()))?
-                    .amount(),
-            ),
-            TransactionType::Resolve => client.resolve(
-                transactions
-                    .get()
-                    .get(tx.tx())
-                    .ok_or(LedgerError::TxNotFound(*tx.tx()))?
-                    .amount(),
-            ),
-            TransactionType::Chargeback => client.chargeback(
-                transactions
-                    .get()
-                    .get(tx.tx())
-                    .ok_or(LedgerError::TxNotFound(*tx.tx()))?
-                    .amount(),
-            ),
-        }?;
-        Ok(())
+        referenced: Option<Transaction>,
+        fee: Decimal,
+    ) -> Result<Option<Transaction>, LedgerError> {
+        match (tx.type_(), receiver) {
+            (TransactionType::Deposit, house) => {
+                let amount = Self::amount_of(tx)?;
+                if fee > Decimal::ZERO && fee >= amount {
+                    return Err(LedgerError::FeeExceedsAmount { tx: *tx.tx(), fee });
+                }
+                client.deposit(amount - fee)?;
+                house
+                    .map_or(Ok(()), |house| house.deposit(fee))
+                    .map(|_| None)
+            }
+            (TransactionType::Withdrawal, house) => {
+                let amount = Self::amount_of(tx)?;
+                let charged = amount.checked_add(fee).ok_or(LedgerError::ValueOverflow)?;
+                client.withdraw(charged)?;
+                house
+                    .map_or(Ok(()), |house| house.deposit(fee))
+                    .map(|_| None)
+            }
+            (TransactionType::Transfer, None) => Err(LedgerError::MissingDestination(*tx.tx())),
+            (TransactionType::Transfer, Some(receiver)) => {
+                let amount = Self::amount_of(tx)?;
+                client.withdraw(amount)?;
+                receiver.deposit(amount).map(|_| None)
+            }
+            (TransactionType::Dispute, None) => {
+                Self::move_referenced(referenced, tx, TransactionState::Disputed, |amount| {
+                    client.dispute(amount)
+                })
+            }
+            (TransactionType::Dispute, Some(receiver)) => {
+                Self::move_referenced(referenced, tx, TransactionState::Disputed, |amount| {
+                    Self::check_unlocked(client)?;
+                    receiver.dispute(amount)
+                })
+            }
+            (TransactionType::Resolve, None) => {
+                Self::move_referenced(referenced, tx, TransactionState::Resolved, |amount| {
+                    client.resolve(amount)
+                })
+            }
+            (TransactionType::Resolve, Some(receiver)) => {
+                Self::move_referenced(referenced, tx, TransactionState::Resolved, |amount| {
+                    Self::check_unlocked(client)?;
+                    receiver.resolve(amount)
+                })
+            }
+            (TransactionType::Chargeback, None) => {
+                Self::move_referenced(referenced, tx, TransactionState::ChargedBack, |amount| {
+                    client.chargeback(amount)
+                })
+            }
+            (TransactionType::Chargeback, Some(receiver)) => {
+                Self::move_referenced(referenced, tx, TransactionState::ChargedBack, |amount| {
+                    client.deposit(amount)?;
+                    receiver.chargeback(amount)
+                })
+            }
+        }
+    }
+
+    fn check_unlocked(client: &ClientBalance) -> Result<(), LedgerError> {
+        match client.locked() {
+            true => Err(LedgerError::AccountLocked(*client.client())),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns `tx` as stored for later disputes: a deposit is kept net of its `fee`, the part the client
+    /// was credited, so disputing it never holds the fee the house account already collected.
+    fn net_of_fee(tx: Transaction, fee: Decimal) -> Transaction {
+        match (tx.type_(), *tx.amount()) {
+            (TransactionType::Deposit, Some(amount)) if fee > Decimal::ZERO => {
+                tx.with_amount(amount - fee)
+            }
+            _ => tx,
+        }
+    }
+
+    fn amount_of(tx: &Transaction) -> Result<Decimal, LedgerError> {
+        tx.amount().ok_or(LedgerError::MissingAmount(*tx.tx()))
+    }
+
+    /// Moves the transaction referenced by `tx` to the `to` state, applying `apply` to the client balance.
+    /// Ownership, currency and the transition are validated before the balance is touched,
+    /// so a rejected move leaves both untouched.
+    fn move_referenced(
+        referenced: Option<Transaction>,
+        tx: &Transaction,
+        to: TransactionState,
+        apply: impl FnOnce(Decimal) -> Result<(), LedgerError>,
+    ) -> Result<Option<Transaction>, LedgerError> {
+        let mut referenced = referenced.ok_or(LedgerError::TxNotFound(*tx.tx()))?;
+
+        if referenced.client() != tx.client() {
+            return Err(LedgerError::ClientMismatch {
+                tx: *tx.tx(),
+                expected: *referenced.client(),
+                got: *tx.client(),
+            });
+        }
+        if let Some(got) = *tx.currency()
+            && Some(got) != *referenced.currency()
+        {
+            return Err(LedgerError::CurrencyMismatch {
+                tx: *tx.tx(),
+                expected: *referenced.currency(),
+                got,
+            });
+        }
+        referenced.state().transition(*tx.tx(), to)?;
+        apply(Self::amount_of(&referenced)?)?;
+        referenced.transition(to)?;
+        Ok(Some(referenced))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        fees::FeeRule,
+        precision::{ExcessPrecision, MAX_SCALE, Rounding},
+    };
     use anyhow::{Error as E, Result, anyhow};
     use rust_decimal::prelude::*;
     use std::cell::RefCell;
 
-    struct MockCSVWriter {
+    struct MockWriter {
         records: Vec<String>,
     }
 
-    impl MockCSVWriter {
+    impl MockWriter {
         fn new() -> Self {
             Self {
                 records: Vec::new(),
@@ -136,7 +862,7 @@ mod tests {
         }
     }
 
-    impl CSVWrite for MockCSVWriter {
+    impl RecordWrite for MockWriter {
         fn write_record<T: serde::Serialize>(&mut self, record: &T) -> Result<()> {
             let line = serde_json::to_string(record)?;
             self.records.push(line);
@@ -147,10 +873,10 @@ mod tests {
     #[test]
     fn test_accountant_export_writes_all_clients() -> Result<()> {
         let mut accountant = Accountant::new();
-        accountant.clients.insert(1, ClientBalance::new(1));
-        accountant.clients.insert(2, ClientBalance::new(2));
+        accountant.clients.insert((1, None), ClientBalance::new(1));
+        accountant.clients.insert((2, None), ClientBalance::new(2));
 
-        let mut mock_writer = MockCSVWriter::new();
+        let mut mock_writer = MockWriter::new();
         let writer_ref = RefCell::new(&mut mock_writer);
 
         accountant.export(writer_ref)?;
@@ -182,6 +908,14 @@ mod tests {
         Ok(transaction)
     }
 
+    fn rejected_ids(accountant: &Accountant) -> Vec<u32> {
+        accountant
+            .rejections()
+            .iter()
+            .map(|rejection| *rejection.tx())
+            .collect()
+    }
+
     #[test]
     fn test_apply_deposit() -> Result<()> {
         let mut accountant = Accountant::new();
@@ -189,7 +923,7 @@ mod tests {
 
         accountant.apply_bookkeeping(tx)?;
 
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.available(), dec!(100.0));
         assert_eq!(*client.total(), dec!(100.0));
         Ok(())
@@ -201,7 +935,7 @@ mod tests {
         accountant.apply_bookkeeping(create_transaction(1, 1, "200.0", "deposit")?)?;
         accountant.apply_bookkeeping(create_transaction(2, 1, "50.0", "withdrawal")?)?;
 
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.available(), dec!(150.0));
         assert_eq!(*client.total(), dec!(150.0));
         Ok(())
@@ -214,13 +948,13 @@ mod tests {
         accountant.apply_bookkeeping(create_transaction(2, 1, "100.0", "deposit")?)?;
         accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
 
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.available(), dec!(100.0));
         assert_eq!(*client.held(), dec!(300.0));
         assert_eq!(*client.total(), dec!(400.0));
 
         accountant.apply_bookkeeping(create_transaction(1, 1, "", "resolve")?)?;
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.available(), dec!(400.0));
         assert_eq!(*client.total(), dec!(400.0));
         assert_eq!(*client.held(), dec!(0.0));
@@ -236,7 +970,7 @@ mod tests {
         accountant.apply_bookkeeping(create_transaction(2, 1, "", "dispute")?)?;
         accountant.apply_bookkeeping(create_transaction(2, 1, "", "chargeback")?)?;
 
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.total(), dec!(400.0));
         assert_eq!(*client.held(), dec!(400.0));
         assert_eq!(*client.available(), dec!(0.0));
@@ -253,7 +987,7 @@ mod tests {
         accountant.apply_bookkeeping(create_transaction(2, 1, "", "dispute")?)?;
         accountant.apply_bookkeeping(create_transaction(2, 1, "", "chargeback")?)?;
 
-        let client = accountant.clients.get(&1).unwrap();
+        let client = accountant.client(1).unwrap();
         assert_eq!(*client.total(), dec!(400.0));
         assert_eq!(*client.held(), dec!(400.0));
         assert_eq!(*client.available(), dec!(0.0));
@@ -261,7 +995,968 @@ mod tests {
 
         accountant.apply_bookkeeping(create_transaction(3, 1, "500.0", "deposit")?)?;
         assert_eq!(accountant.transactions_rejected.len(), 1);
-        assert_eq!(accountant.transactions_rejected[0], 3);
+        assert_eq!(*accountant.transactions_rejected[0].tx(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cannot_dispute_twice() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "300.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.available(), dec!(0.0));
+        assert_eq!(*client.held(), dec!(300.0));
+        assert_eq!(rejected_ids(&accountant), vec![1]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Disputed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cannot_resolve_or_chargeback_undisputed() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "300.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "resolve")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "chargeback")?)?;
+
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.available(), dec!(300.0));
+        assert_eq!(*client.held(), dec!(0.0));
+        assert!(!*client.locked());
+        assert_eq!(rejected_ids(&accountant), vec![1, 1]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Processed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cannot_dispute_or_chargeback_after_resolve() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "300.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "resolve")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "chargeback")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.available(), dec!(300.0));
+        assert_eq!(*client.held(), dec!(0.0));
+        assert_eq!(*client.total(), dec!(300.0));
+        assert!(!*client.locked());
+        assert_eq!(rejected_ids(&accountant), vec![1, 1]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Resolved
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_of_unknown_tx_is_rejected_and_not_stored() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(5, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(5, 1, "10.0", "deposit")?)?;
+
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.available(), dec!(10.0));
+        assert_eq!(rejected_ids(&accountant), vec![5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cannot_dispute_other_client_transaction() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 2, "500.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 7, "500.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 7, "", "dispute")?)?;
+
+        let attacker = accountant.client(7).unwrap();
+        assert_eq!(*attacker.available(), dec!(500.0));
+        assert_eq!(*attacker.held(), dec!(0.0));
+
+        let victim = accountant.client(2).unwrap();
+        assert_eq!(*victim.available(), dec!(500.0));
+        assert_eq!(*victim.held(), dec!(0.0));
+
+        assert_eq!(rejected_ids(&accountant), vec![1]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Processed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cannot_resolve_or_chargeback_other_client_transaction() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 2, "500.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 2, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 7, "", "resolve")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 7, "", "chargeback")?)?;
+
+        let victim = accountant.client(2).unwrap();
+        assert_eq!(*victim.held(), dec!(500.0));
+        assert!(!*victim.locked());
+        assert!(!*accountant.client(7).unwrap().locked());
+        assert_eq!(rejected_ids(&accountant), vec![1, 1]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Disputed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_mismatch_error() -> Result<()> {
+        let mut client = ClientBalance::new(7);
+
+        let err = Accountant::update_client_balance(
+            &mut client,
+            None,
+            &create_transaction(1, 7, "", "dispute")?,
+            Some(create_transaction(1, 2, "500.0", "deposit")?),
+            Decimal::ZERO,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            LedgerError::ClientMismatch {
+                tx: 1,
+                expected: 2,
+                got: 7
+            }
+        );
+        Ok(())
+    }
+
+    fn transfer(tx: u32, client: u16, to: u16, amount: Decimal) -> Transaction {
+        Transaction::new(TransactionType::Transfer, client, tx, Some(amount)).with_to(to)
+    }
+
+    fn funded(deposits: &[(u16, Decimal)]) -> Result<Accountant> {
+        let mut accountant = Accountant::new();
+        for (tx, (client, amount)) in deposits.iter().enumerate() {
+            accountant.apply_bookkeeping(Transaction::new(
+                TransactionType::Deposit,
+                *client,
+                tx as u32 + 1,
+                Some(*amount),
+            ))?;
+        }
+        Ok(accountant)
+    }
+
+    fn balance(accountant: &Accountant, client: u16) -> (Decimal, Decimal, Decimal, bool) {
+        let balance = &accountant.clients[&(client, None)];
+        (
+            *balance.available(),
+            *balance.held(),
+            *balance.total(),
+            *balance.locked(),
+        )
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_between_clients() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(100))])?;
+        accountant.apply_bookkeeping(transfer(10, 1, 2, dec!(30.5)))?;
+
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(69.5), dec!(0), dec!(69.5), false)
+        );
+        assert_eq!(
+            balance(&accountant, 2),
+            (dec!(30.5), dec!(0), dec!(30.5), false)
+        );
+        assert_eq!(
+            accountant.transaction(10)?.map(|tx| *tx.to()),
+            Some(Some(2))
+        );
+        assert!(accountant.rejections().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_transfer_changes_neither_client() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(100))])?;
+        let locked = ClientBalance::from_parts(2, None, dec!(5), dec!(0), dec!(5), true);
+        accountant.clients.insert((2, None), locked);
+
+        accountant.apply_bookkeeping(transfer(10, 1, 2, dec!(30)))?;
+        accountant.apply_bookkeeping(transfer(11, 1, 3, dec!(300)))?;
+        accountant.apply_bookkeeping(transfer(12, 2, 1, dec!(1)))?;
+
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(100), dec!(0), dec!(100), false)
+        );
+        assert_eq!(balance(&accountant, 2), (dec!(5), dec!(0), dec!(5), true));
+        assert_eq!(accountant.balance(3, None), None);
+        let reasons: Vec<&LedgerError> =
+            accountant.rejections().iter().map(|r| r.reason()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                &LedgerError::AccountLocked(2),
+                &LedgerError::InsufficientFunds {
+                    amount: dec!(300),
+                    balance: dec!(-200)
+                },
+                &LedgerError::AccountLocked(2),
+            ]
+        );
+        assert_eq!(accountant.transaction(10)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_destination_is_checked() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(100))])?;
+        accountant.apply_bookkeeping(transfer(10, 1, 1, dec!(1)))?;
+        accountant.apply_bookkeeping(Transaction::new(
+            TransactionType::Transfer,
+            1,
+            11,
+            Some(dec!(1)),
+        ))?;
+        accountant.apply_bookkeeping(
+            Transaction::new(TransactionType::Deposit, 1, 12, Some(dec!(1))).with_to(2),
+        )?;
+
+        let reasons: Vec<&LedgerError> =
+            accountant.rejections().iter().map(|r| r.reason()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                &LedgerError::TransferToSelf(10),
+                &LedgerError::MissingDestination(11),
+                &LedgerError::UnexpectedDestination(12),
+            ]
+        );
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(100), dec!(0), dec!(100), false)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_is_disputed_as_a_unit() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(100))])?;
+        accountant.apply_bookkeeping(transfer(10, 1, 2, dec!(40)))?;
+
+        accountant.apply_bookkeeping(create_transaction(10, 2, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(10, 1, "", "dispute")?)?;
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(60), dec!(0), dec!(60), false)
+        );
+        assert_eq!(
+            balance(&accountant, 2),
+            (dec!(0), dec!(40), dec!(40), false)
+        );
+
+        accountant.apply_bookkeeping(create_transaction(10, 1, "", "resolve")?)?;
+        assert_eq!(
+            balance(&accountant, 2),
+            (dec!(40), dec!(0), dec!(40), false)
+        );
+
+        accountant.apply_bookkeeping(transfer(11, 1, 2, dec!(10)))?;
+        accountant.apply_bookkeeping(create_transaction(11, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(11, 1, "", "chargeback")?)?;
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(60), dec!(0), dec!(60), false)
+        );
+        assert_eq!(balance(&accountant, 2), (dec!(40), dec!(0), dec!(40), true));
+        assert_eq!(
+            accountant.transaction(11)?.map(|tx| *tx.state()),
+            Some(TransactionState::ChargedBack)
+        );
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::ClientMismatch {
+                tx: 10,
+                expected: 1,
+                got: 2
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_dispute_respects_locked_sender() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(100))])?;
+        accountant.apply_bookkeeping(transfer(10, 1, 2, dec!(40)))?;
+        let locked = ClientBalance::from_parts(1, None, dec!(60), dec!(0), dec!(60), true);
+        accountant.clients.insert((1, None), locked);
+
+        accountant.apply_bookkeeping(create_transaction(10, 1, "", "dispute")?)?;
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::AccountLocked(1)
+        );
+        assert_eq!(
+            balance(&accountant, 2),
+            (dec!(40), dec!(0), dec!(40), false)
+        );
+        assert_eq!(
+            accountant.transaction(10)?.map(|tx| *tx.state()),
+            Some(TransactionState::Processed)
+        );
+        Ok(())
+    }
+
+    fn in_currency(transaction: Transaction, currency: &str) -> Result<Transaction> {
+        Ok(transaction.with_currency(currency.parse()?))
+    }
+
+    #[test]
+    fn test_balances_are_kept_per_currency() -> Result<()> {
+        let mut accountant = funded(&[(1, dec!(5))])?;
+        for transaction in [
+            in_currency(create_transaction(2, 1, "10.0", "deposit")?, "EUR")?,
+            in_currency(create_transaction(3, 1, "7.0", "deposit")?, "gbp")?,
+            in_currency(create_transaction(4, 1, "8.0", "withdrawal")?, "GBP")?,
+            in_currency(transfer(5, 1, 2, dec!(4)), "EUR")?,
+        ] {
+            accountant.apply_bookkeeping(transaction)?;
+        }
+
+        let eur = Some("EUR".parse()?);
+        assert_eq!(accountant.client(1).map(|c| *c.total()), Some(dec!(5)));
+        assert_eq!(
+            accountant.balance(1, eur).map(|c| *c.total()),
+            Some(dec!(6))
+        );
+        assert_eq!(
+            accountant.balance(2, eur).map(|c| *c.total()),
+            Some(dec!(4))
+        );
+        assert_eq!(accountant.balances(1).len(), 3);
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::InsufficientFunds {
+                amount: dec!(8),
+                balance: dec!(-1)
+            }
+        );
+
+        let mut writer = MockWriter::new();
+        accountant.export(RefCell::new(&mut writer))?;
+        assert_eq!(
+            writer.records,
+            vec![
+                r#"{"client":1,"currency":null,"available":"5.0000","held":"0.0000","total":"5.0000","locked":false}"#,
+                r#"{"client":1,"currency":"EUR","available":"6.0000","held":"0.0000","total":"6.0000","locked":false}"#,
+                r#"{"client":1,"currency":"GBP","available":"7.0000","held":"0.0000","total":"7.0000","locked":false}"#,
+                r#"{"client":2,"currency":"EUR","available":"4.0000","held":"0.0000","total":"4.0000","locked":false}"#,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_currency_must_match() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(in_currency(
+            create_transaction(1, 1, "10.0", "deposit")?,
+            "EUR",
+        )?)?;
+        accountant.apply_bookkeeping(in_currency(
+            create_transaction(1, 1, "", "dispute")?,
+            "GBP",
+        )?)?;
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::CurrencyMismatch {
+                tx: 1,
+                expected: Some("EUR".parse()?),
+                got: "GBP".parse()?
+            }
+        );
+
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(in_currency(
+            create_transaction(1, 1, "", "resolve")?,
+            "eur",
+        )?)?;
+        let eur = accountant.balance(1, Some("EUR".parse()?));
+        assert_eq!(eur.map(|c| *c.available()), Some(dec!(10)));
+        assert_eq!(accountant.rejections().len(), 1);
+        assert!(accountant.client(1).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_amounts_follow_the_precision_policy() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "1.00001", "deposit")?)?;
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::TooPrecise {
+                tx: 1,
+                max_scale: 4
+            }
+        );
+        assert!(accountant.residuals().is_empty());
+
+        let precision = Precision::default()
+            .with_scale(2)?
+            .with_rounding(Rounding::HalfUp)
+            .with_excess(ExcessPrecision::Round);
+        let mut accountant = Accountant::new().with_precision(precision);
+        accountant.apply_bookkeeping(create_transaction(1, 1, "1.005", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 1, "0.5", "deposit")?)?;
+        accountant.apply_bookkeeping(in_currency(
+            create_transaction(3, 1, "2.994", "deposit")?,
+            "EUR",
+        )?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(4, 1, "9.999", "withdrawal")?)?;
+
+        assert_eq!(rejected_ids(&accountant), vec![4]);
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().amount(),
+            Some(dec!(1.01))
+        );
+        let mut mock_writer = MockWriter::new();
+        accountant.export(RefCell::new(&mut mock_writer))?;
+        assert_eq!(
+            mock_writer.records,
+            vec![
+                r#"{"client":1,"currency":null,"available":"0.50","held":"1.01","total":"1.51","locked":false}"#,
+                r#"{"client":1,"currency":"EUR","available":"2.99","held":"0.00","total":"2.99","locked":false}"#,
+            ]
+        );
+
+        let residuals: Vec<(Option<Currency>, u64, Decimal)> = accountant
+            .residuals()
+            .iter()
+            .map(|residual| {
+                (
+                    *residual.currency(),
+                    *residual.rounded(),
+                    *residual.residual(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            residuals,
+            vec![
+                (None, 1, dec!(-0.005)),
+                (Some("EUR".parse()?), 1, dec!(0.004))
+            ]
+        );
+
+        let mut buf = Vec::new();
+        accountant.snapshot(&mut buf)?;
+        assert_eq!(
+            Accountant::restore(buf.as_slice())?.residuals(),
+            accountant.residuals()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_amounts_too_large_for_the_scale_are_rejected() -> Result<()> {
+        let mut accountant =
+            Accountant::new().with_precision(Precision::default().with_scale(MAX_SCALE)?);
+        accountant.apply_bookkeeping(create_transaction(1, 1, "1000", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 1, "2.5", "deposit")?)?;
+
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::TooLarge {
+                tx: 1,
+                max_scale: MAX_SCALE
+            }
+        );
+        assert_eq!(balance(&accountant, 1).2.scale(), MAX_SCALE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restored_balances_follow_the_precision_policy() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "1.2345", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 2, "2.5", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        let mut buf = Vec::new();
+        accountant.snapshot(&mut buf)?;
+
+        for (scale, expected) in [
+            (
+                2,
+                [
+                    r#"{"client":1,"available":"0.00","held":"1.23","total":"1.23","locked":false}"#,
+                    r#"{"client":2,"available":"2.50","held":"0.00","total":"2.50","locked":false}"#,
+                ],
+            ),
+            (
+                6,
+                [
+                    r#"{"client":1,"available":"0.000000","held":"1.234500","total":"1.234500","locked":false}"#,
+                    r#"{"client":2,"available":"2.500000","held":"0.000000","total":"2.500000","locked":false}"#,
+                ],
+            ),
+        ] {
+            let restored = Accountant::restore(buf.as_slice())?
+                .with_precision(Precision::default().with_scale(scale)?);
+            let mut mock_writer = MockWriter::new();
+            restored.export(RefCell::new(&mut mock_writer))?;
+            assert_eq!(mock_writer.records, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fees_move_to_the_house_account() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_percent(dec!(1)))?
+            .with_rule(FeeRule::new(TransactionType::Withdrawal).with_flat(dec!(1)))?;
+        let mut accountant = Accountant::new().with_fees(fees);
+        accountant.apply_bookkeeping(create_transaction(1, 1, "100.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 1, "98.5", "withdrawal")?)?;
+        accountant.apply_bookkeeping(create_transaction(3, 1, "50.0", "withdrawal")?)?;
+        accountant.apply_bookkeeping(create_transaction(4, 0, "5.0", "deposit")?)?;
+        accountant.apply_bookkeeping(transfer(5, 1, 0, dec!(1)))?;
+
+        let reasons: Vec<&LedgerError> = accountant
+            .rejections()
+            .iter()
+            .map(|rejection| rejection.reason())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                &LedgerError::InsufficientFunds {
+                    amount: dec!(99.5),
+                    balance: dec!(-0.5)
+                },
+                &LedgerError::HouseAccount(0),
+                &LedgerError::HouseAccount(0),
+            ]
+        );
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(48), dec!(0), dec!(48), false)
+        );
+        let postings: Vec<(u32, u16, u16, Decimal)> = accountant
+            .fee_postings()
+            .iter()
+            .map(|posting| {
+                (
+                    *posting.tx(),
+                    *posting.client(),
+                    *posting.house(),
+                    *posting.fee(),
+                )
+            })
+            .collect();
+        assert_eq!(postings, vec![(1, 1, 0, dec!(1)), (3, 1, 0, dec!(1))]);
+
+        let mut mock_writer = MockWriter::new();
+        accountant.export(RefCell::new(&mut mock_writer))?;
+        assert_eq!(
+            mock_writer.records,
+            vec![
+                r#"{"client":0,"available":"2.0000","held":"0.0000","total":"2.0000","locked":false}"#,
+                r#"{"client":1,"available":"48.0000","held":"0.0000","total":"48.0000","locked":false}"#,
+            ]
+        );
+
+        let mut buf = Vec::new();
+        accountant.snapshot(&mut buf)?;
+        assert_eq!(
+            Accountant::restore(buf.as_slice())?.fee_postings(),
+            accountant.fee_postings()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fee_charged_deposit_is_disputed_net_of_its_fee() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_percent(dec!(1)))?;
+        let mut accountant = Accountant::new().with_fees(fees);
+        accountant.apply_bookkeeping(create_transaction(1, 1, "100.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(0), dec!(99), dec!(99), false)
+        );
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "resolve")?)?;
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(99), dec!(0), dec!(99), false)
+        );
+
+        accountant.apply_bookkeeping(create_transaction(2, 1, "200.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 1, "", "dispute")?)?;
+        assert_eq!(
+            *accountant.transaction(2)?.unwrap().amount(),
+            Some(dec!(198))
+        );
+        accountant.apply_bookkeeping(create_transaction(2, 1, "", "chargeback")?)?;
+
+        assert!(accountant.rejections().is_empty());
+        assert_eq!(balance(&accountant, 1), (dec!(99), dec!(0), dec!(99), true));
+        assert_eq!(balance(&accountant, 0), (dec!(3), dec!(0), dec!(3), false));
+        assert_eq!(accountant.fee_postings().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_adds_up_house_rows_of_every_currency() -> Result<()> {
+        let eur: Currency = "EUR".parse()?;
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_flat(dec!(1)))?
+            .with_rule(
+                FeeRule::new(TransactionType::Deposit)
+                    .with_currency(eur)
+                    .with_flat(dec!(1)),
+            )?;
+        let mut accountant = Accountant::new().with_fees(fees);
+        let stores: Vec<Box<dyn TransactionStore>> =
+            vec![Box::new(MemoryStore::new()), Box::new(MemoryStore::new())];
+        let mut shards = accountant.split(stores)?;
+        for (tx, client) in [(1, 1), (2, 2), (3, 3)] {
+            let deposit = Transaction::new(TransactionType::Deposit, client, tx, Some(dec!(10)));
+            shards[Accountant::shard_of(client, 2)]
+                .apply_bookkeeping(deposit.with_currency(eur))?;
+        }
+        shards[1].apply_bookkeeping(Transaction::new(
+            TransactionType::Deposit,
+            5,
+            4,
+            Some(dec!(10)),
+        ))?;
+
+        accountant.merge(shards, Vec::new(), Vec::new(), Vec::new())?;
+        let house = |currency| accountant.balance(0, currency).map(|house| *house.total());
+        assert_eq!(house(Some(eur)), Some(dec!(3)));
+        assert_eq!(house(None), Some(dec!(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_refuses_shards_charging_other_fees() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_flat(dec!(1)))?;
+        let mut accountant = Accountant::new().with_fees(fees);
+        let stores: Vec<Box<dyn TransactionStore>> =
+            vec![Box::new(MemoryStore::new()), Box::new(MemoryStore::new())];
+        let mut shards = accountant.split(stores)?;
+        shards[1].fees = None;
+
+        assert!(
+            accountant
+                .merge(shards, Vec::new(), Vec::new(), Vec::new())
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deposit_not_covering_its_fee_is_refused() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_flat(dec!(5)))?;
+        let mut accountant = Accountant::new().with_fees(fees);
+        accountant.apply_bookkeeping(create_transaction(1, 1, "15.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 1, "5.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(3, 1, "4.0", "deposit")?)?;
+
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::FeeExceedsAmount {
+                tx: 2,
+                fee: dec!(5)
+            }
+        );
+        assert_eq!(rejected_ids(&accountant), vec![2, 3]);
+        assert_eq!(
+            balance(&accountant, 1),
+            (dec!(10), dec!(0), dec!(10), false)
+        );
+        assert_eq!(balance(&accountant, 0), (dec!(5), dec!(0), dec!(5), false));
+        assert!(accountant.transaction(2)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fee_overflow_is_rejected() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_percent(dec!(50)))?;
+        let mut accountant = Accountant::new()
+            .with_precision(Precision::default().with_scale(0)?)
+            .with_fees(fees);
+        accountant.apply_bookkeeping(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::MAX),
+        ))?;
+
+        assert_eq!(
+            accountant.rejections()[0].reason(),
+            &LedgerError::ValueOverflow
+        );
+        assert!(accountant.balance(1, None).is_none());
+        assert!(accountant.fee_postings().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejection_keeps_reason_and_line() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "10.0", "deposit")?.with_line(2))?;
+        accountant
+            .apply_bookkeeping(create_transaction(2, 1, "50.0", "withdrawal")?.with_line(3))?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "20.0", "deposit")?.with_line(4))?;
+
+        let rejections = accountant.rejections();
+        assert_eq!(rejections.len(), 2);
+
+        assert_eq!(*rejections[0].tx(), 2);
+        assert_eq!(*rejections[0].client(), 1);
+        assert_eq!(*rejections[0].type_(), TransactionType::Withdrawal);
+        assert_eq!(*rejections[0].input_line(), Some(3));
+        assert!(matches!(
+            rejections[0].reason(),
+            LedgerError::InsufficientFunds { .. }
+        ));
+
+        assert_eq!(*rejections[1].reason(), LedgerError::TxDuplicated(1));
+        assert_eq!(*rejections[1].input_line(), Some(4));
+
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.available(), dec!(10.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejection_serializes_reason_as_text() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(9, 3, "", "dispute")?.with_line(5))?;
+
+        let mut mock_writer = MockWriter::new();
+        mock_writer.write_record(&accountant.rejections()[0])?;
+
+        assert_eq!(
+            mock_writer.records,
+            vec![
+                r#"{"tx":9,"client":3,"type":"dispute","reason":"transaction 9 not found","input_line":5}"#
+            ]
+        );
+        Ok(())
+    }
+
+    fn exported_ids(accountant: &Accountant) -> Result<Vec<u16>> {
+        let mut mock_writer = MockWriter::new();
+        accountant.export(RefCell::new(&mut mock_writer))?;
+        mock_writer
+            .records
+            .iter()
+            .map(|record| {
+                let value: serde_json::Value = serde_json::from_str(record)?;
+                value["client"]
+                    .as_u64()
+                    .map(|id| id as u16)
+                    .ok_or(anyhow!("missing client"))
+            })
+            .collect()
+    }
+
+    fn accountant_for_ordering() -> Result<Accountant> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 30, "5.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 10, "50.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(3, 20, "50.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(4, 40, "70.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(4, 40, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(5, 50, "1.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(5, 50, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(5, 50, "", "chargeback")?)?;
+        Ok(accountant)
+    }
+
+    #[test]
+    fn test_export_is_sorted_by_client_by_default() -> Result<()> {
+        let accountant = accountant_for_ordering()?;
+        assert_eq!(exported_ids(&accountant)?, vec![10, 20, 30, 40, 50]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_order_by_total_available_and_locked() -> Result<()> {
+        let accountant = accountant_for_ordering()?.with_export_order(ExportOrder::Total);
+        assert_eq!(exported_ids(&accountant)?, vec![40, 10, 20, 30, 50]);
+
+        let accountant = accountant_for_ordering()?.with_export_order(ExportOrder::Available);
+        assert_eq!(exported_ids(&accountant)?, vec![10, 20, 30, 40, 50]);
+
+        let accountant = accountant_for_ordering()?.with_export_order(ExportOrder::Locked);
+        assert_eq!(exported_ids(&accountant)?, vec![50, 10, 20, 30, 40]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_order_from_str() -> Result<()> {
+        assert_eq!("client".parse::<ExportOrder>()?, ExportOrder::Client);
+        assert_eq!("total".parse::<ExportOrder>()?, ExportOrder::Total);
+        assert_eq!("available".parse::<ExportOrder>()?, ExportOrder::Available);
+        assert_eq!("locked".parse::<ExportOrder>()?, ExportOrder::Locked);
+        assert!("held".parse::<ExportOrder>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_round_trip() -> Result<()> {
+        let mut accountant = accountant_for_ordering()?;
+        accountant.apply_bookkeeping(create_transaction(6, 20, "20.0", "withdrawal")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 10, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 10, "", "resolve")?)?;
+        accountant.apply_bookkeeping(create_transaction(7, 50, "3.0", "deposit")?.with_line(9))?;
+        accountant.apply_bookkeeping(create_transaction(3, 10, "", "dispute")?)?;
+
+        let mut buf = Vec::new();
+        accountant.snapshot(&mut buf)?;
+        let restored = Accountant::restore(buf.as_slice())?;
+
+        assert_eq!(restored.clients, accountant.clients);
+        assert_eq!(stored(&restored)?, stored(&accountant)?);
+        assert_eq!(
+            restored.transaction_historical_order,
+            accountant.transaction_historical_order
+        );
+        assert_eq!(
+            restored.transactions_rejected,
+            accountant.transactions_rejected
+        );
+        assert_eq!(
+            *restored.transaction(2)?.unwrap().state(),
+            TransactionState::Resolved
+        );
+        assert_eq!(restored.rejections().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restored_state_keeps_processing() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 1, "100.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+
+        let mut buf = Vec::new();
+        accountant.snapshot(&mut buf)?;
+        let mut restored = Accountant::restore(buf.as_slice())?;
+
+        restored.apply_bookkeeping(create_transaction(1, 1, "100.0", "deposit")?)?;
+        restored.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?)?;
+        restored.apply_bookkeeping(create_transaction(1, 1, "", "chargeback")?)?;
+
+        let client = restored.client(1).unwrap();
+        assert_eq!(*client.total(), dec!(0.0));
+        assert!(*client.locked());
+        assert_eq!(rejected_ids(&restored), vec![1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_is_deterministic() -> Result<()> {
+        let mut first = Vec::new();
+        accountant_for_ordering()?.snapshot(&mut first)?;
+        let mut second = Vec::new();
+        accountant_for_ordering()?.snapshot(&mut second)?;
+        assert_eq!(first, second);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_rejects_truncated_snapshot() -> Result<()> {
+        let mut buf = Vec::new();
+        accountant_for_ordering()?.snapshot(&mut buf)?;
+        buf.truncate(buf.len() - 1);
+        assert!(Accountant::restore(buf.as_slice()).is_err());
+        Ok(())
+    }
+
+    fn stored(accountant: &Accountant) -> Result<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+        accountant.transactions.for_each(&mut |transaction| {
+            transactions.push(transaction);
+            Ok(())
+        })?;
+        Ok(transactions)
+    }
+
+    fn journal_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ledger-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_journal_replay_reaches_pre_crash_state() -> Result<()> {
+        let path = journal_path("replay");
+        let mut crashed = Accountant::new();
+        assert_eq!(crashed.attach_journal(&path, 2)?, None);
+        crashed.apply_bookkeeping(create_transaction(1, 1, "100.0", "deposit")?.with_offset(30))?;
+        crashed
+            .apply_bookkeeping(create_transaction(2, 1, "500.0", "withdrawal")?.with_offset(50))?;
+        crashed.apply_bookkeeping(create_transaction(1, 1, "", "dispute")?.with_offset(63))?;
+        drop(crashed.journal.take());
+
+        let mut recovered = Accountant::new();
+        assert_eq!(recovered.attach_journal(&path, 2)?, Some(63));
+
+        assert_eq!(recovered.clients, crashed.clients);
+        assert_eq!(stored(&recovered)?, stored(&crashed)?);
+        assert_eq!(
+            recovered.transactions_rejected,
+            crashed.transactions_rejected
+        );
+        assert_eq!(
+            recovered.transaction_historical_order,
+            crashed.transaction_historical_order
+        );
+
+        recovered.apply_bookkeeping(create_transaction(1, 1, "", "resolve")?.with_offset(76))?;
+        recovered.close_journal()?;
+
+        let mut again = Accountant::new();
+        assert_eq!(again.attach_journal(&path, 2)?, Some(76));
+        assert_eq!(*again.clients[&(1, None)].available(), dec!(100.0));
+        again.close_journal()?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_client_and_transaction() -> Result<()> {
+        let mut accountant = Accountant::new();
+        accountant.apply_bookkeeping(create_transaction(1, 4, "10.0", "deposit")?)?;
+        accountant.apply_bookkeeping(create_transaction(1, 4, "", "dispute")?)?;
+        accountant.apply_bookkeeping(create_transaction(2, 4, "99.0", "withdrawal")?)?;
+
+        assert_eq!(*accountant.client(4).unwrap().held(), dec!(10.0));
+        assert!(accountant.client(5).is_none());
+        assert_eq!(
+            *accountant.transaction(1)?.unwrap().state(),
+            TransactionState::Disputed
+        );
+        assert!(accountant.transaction(2)?.is_none());
         Ok(())
     }
 }
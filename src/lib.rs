@@ -0,0 +1,67 @@
+//! Payment engine that reads a stream of client transactions, keeps per-client balances
+//! and exports the resulting accounts.
+//!
+//! The public surface is the set of items re-exported from the crate root and follows semantic
+//! versioning; see `CHANGELOG.md` for the history of changes to it.
+//!
+//! ```
+//! use play_with_csv::{Accountant, Accounting, Transaction, TransactionType};
+//! use rust_decimal::Decimal;
+//!
+//! let mut accountant = Accountant::new();
+//! accountant
+//!     .apply_bookkeeping(Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)))
+//!     .unwrap();
+//!
+//! assert_eq!(*accountant.client(1).unwrap().available(), Decimal::TEN);
+//! assert!(accountant.transaction(1).unwrap().is_some());
+//! ```
+
+#![warn(missing_docs)]
+
+mod columnar;
+mod compression;
+mod errors;
+mod fees;
+mod http;
+mod inputs;
+mod journal;
+mod ledger;
+mod models;
+mod parallel;
+mod precision;
+mod processor;
+mod reader;
+mod server;
+mod snapshot;
+mod store;
+mod streaming;
+mod traits;
+mod writer;
+
+pub use columnar::{ColumnMapping, ParquetReader, ParquetWriter};
+pub use compression::{Compression, decompress};
+pub use errors::{
+    AmountError, InputError, InvalidCurrency, LedgerError, SnapshotError, UnknownTransactionType,
+};
+pub use fees::{DEFAULT_HOUSE_ACCOUNT, FeePosting, FeeRule, FeeSchedule};
+pub use http::HttpServer;
+pub use inputs::{InputOrder, expand_inputs};
+pub use journal::{DEFAULT_SYNC_EVERY, Journal};
+pub use ledger::{Accountant, ExportOrder};
+pub use models::{
+    ClientBalance, Currency, DEFAULT_MAX_SCALE, MAX_CURRENCY_LEN, Rejection, Transaction,
+    TransactionState, TransactionType,
+};
+pub use parallel::ShardedAccountant;
+pub use precision::{ExcessPrecision, MAX_SCALE, Precision, Residual, Rounding};
+pub use processor::{Engine, ErrorPolicy, IngestReport, MalformedRow};
+pub use reader::{InputFormat, JsonLinesReader, ReaderConfig};
+pub use server::Server;
+pub use store::{DiskStore, MemoryStore, SpillStore};
+pub use streaming::{AsyncEngine, BalanceUpdate, transaction_stream};
+pub use traits::{Accounting, CSVExport, RecordRead, RecordWrite, TransactionStore};
+pub use writer::{
+    AtomicFile, CsvDialect, FileCSVWriter, FileRecordWriter, LineTerminator, OutputFormat,
+    QuoteStyle, RecordWriter, StdOutCSVWriter,
+};
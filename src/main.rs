@@ -1,35 +1,938 @@
-use anyhow::{Result, anyhow};
-use csv::ReaderBuilder;
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::fs::File;
-use std::io::{self};
-
-mod errors;
-mod ledger;
-mod models;
-mod processor;
-mod traits;
-mod writer;
-
-const DEFAULT_HAS_HEADERS: bool = true;
-
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let input: Box<dyn io::Read> = match args.len() {
-        0 | 1 => Box::new(io::stdin()),
-        2 => Box::new(File::open(&args[1])?),
-        _ => Err(anyhow!("not implemented"))?,
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Stdout};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use play_with_csv::{
+    Accountant, Accounting, AtomicFile, CSVExport, ColumnMapping, Compression, CsvDialect,
+    DEFAULT_HOUSE_ACCOUNT, DEFAULT_MAX_SCALE, DEFAULT_SYNC_EVERY, DiskStore, Engine, ErrorPolicy,
+    ExcessPrecision, ExportOrder, FeeSchedule, FileRecordWriter, HttpServer, InputFormat,
+    InputOrder, LineTerminator, MAX_SCALE, MemoryStore, OutputFormat, ParquetReader, Precision,
+    QuoteStyle, ReaderConfig, RecordRead, RecordWrite, RecordWriter, Rejection, Rounding, Server,
+    ShardedAccountant, SpillStore, Transaction, TransactionStore, decompress, expand_inputs,
+};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+const DEFAULT_HTTP_LISTEN: &str = "127.0.0.1:8080";
+
+/// Exit code of a run that stopped on an error, its output is incomplete.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code of a run that completed with findings, see `EXIT_CODES`.
+const EXIT_PARTIAL: u8 = 3;
+
+const EXIT_CODES: &str = "Exit codes:
+  0  Success.
+  1  Hard failure: the run stopped on an error and its output is incomplete.
+  2  Invalid command line.
+  3  Partial failure: the run completed, but skipped malformed rows (process, validate), refused
+     transactions (validate), missed clients or transactions (query) or found differences (diff).";
+
+/// Applies deposits, withdrawals, disputes, resolves and chargebacks to client accounts.
+///
+/// Without a subcommand the arguments are those of `process`.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true, after_help = EXIT_CODES)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Applies the inputs to one ledger and writes the balances.
+    Process(ProcessArgs),
+    /// Applies the inputs without writing balances, reporting malformed rows and refused transactions.
+    Validate(ValidateArgs),
+    /// Rebuilds the ledger left by a crashed run from its journal and writes the balances.
+    Replay(ReplayArgs),
+    /// Looks up balances or stored transactions in a ledger snapshot.
+    Query(QueryArgs),
+    /// Applies transactions streamed by TCP connections or HTTP requests until ctrl-c.
+    Serve(ServeArgs),
+    /// Compares two CSV balance files and writes the fields that differ.
+    Diff(DiffArgs),
+}
+
+/// How input rows are read and validated.
+#[derive(Args)]
+struct ReadArgs {
+    /// Input format: csv, jsonl or parquet.
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    input_format: InputFormat,
+    /// Parquet columns of the transaction fields, such as type=kind,amount=value.
+    #[arg(long, value_name = "MAPPING")]
+    columns: Option<ColumnMapping>,
+    /// CSV field delimiter, \t for tabs.
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter, default_value = ",")]
+    delimiter: u8,
+    /// The CSV input has no header row, its columns are type, client, tx and amount.
+    #[arg(long)]
+    no_headers: bool,
+    /// Keeps whitespace around CSV fields instead of trimming it.
+    #[arg(long)]
+    no_trim: bool,
+    /// Requires every CSV row to have as many columns as the header.
+    #[arg(long)]
+    strict_columns: bool,
+    #[command(flatten)]
+    precision: PrecisionArgs,
+    /// What to do with malformed rows: fail-fast, skip or skip-up-to:<n>.
+    #[arg(long, value_name = "POLICY", default_value = "fail-fast")]
+    error_policy: ErrorPolicy,
+}
+
+/// How many decimal places amounts and balances keep.
+#[derive(Args)]
+struct PrecisionArgs {
+    /// Number of decimal places of amounts and balances, at most 28.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_MAX_SCALE,
+        value_parser = clap::value_parser!(u32).range(..=i64::from(MAX_SCALE))
+    )]
+    max_scale: u32,
+    /// How amounts are rounded to --max-scale: bankers, half-up or truncate.
+    #[arg(long, value_name = "MODE", default_value = "bankers")]
+    rounding: Rounding,
+    /// What to do with amounts with more decimal places than --max-scale: reject or round.
+    #[arg(long, value_name = "POLICY", default_value = "reject")]
+    excess_precision: ExcessPrecision,
+}
+
+/// The fees charged on deposits and withdrawals.
+#[derive(Args)]
+struct FeeArgs {
+    /// Charges deposits and withdrawals the fees of the CSV schedule at PATH.
+    #[arg(long, value_name = "PATH")]
+    fees: Option<PathBuf>,
+    /// Client id of the account collecting the fees, refused as a client of transactions.
+    #[arg(long, value_name = "CLIENT", default_value_t = DEFAULT_HOUSE_ACCOUNT, requires = "fees")]
+    house_account: u16,
+}
+
+/// Where and how records are written.
+#[derive(Args)]
+struct OutputArgs {
+    /// Writes to PATH instead of stdout.
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Output format: csv, jsonl, json or parquet.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "csv",
+        alias = "output-format"
+    )]
+    format: OutputFormat,
+    /// Compresses the output and the rejections with gzip or zstd, by default only paths ending in .gz or .zst are.
+    #[arg(long, value_name = "CODEC")]
+    compress: Option<Compression>,
+    #[command(flatten)]
+    dialect: DialectArgs,
+}
+
+/// How CSV records are written.
+#[derive(Args)]
+struct DialectArgs {
+    /// CSV output field delimiter, \t for tabs.
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter, default_value = ",")]
+    output_delimiter: u8,
+    /// Which CSV output fields are quoted: necessary, always, non-numeric or never.
+    #[arg(long, value_name = "STYLE", default_value = "necessary")]
+    quote_style: QuoteStyle,
+    /// End of every CSV output record: lf or crlf.
+    #[arg(long, value_name = "TERMINATOR", default_value = "lf")]
+    line_terminator: LineTerminator,
+    /// Leaves out the header row of CSV output.
+    #[arg(long)]
+    no_output_headers: bool,
+}
+
+/// The ledger a run starts from and what is kept of it when the run ends.
+#[derive(Args)]
+struct LedgerArgs {
+    /// Starts from a ledger snapshot instead of an empty ledger.
+    #[arg(long, value_name = "PATH")]
+    load_state: Option<PathBuf>,
+    /// Writes the ledger snapshot when the run ends, so the next run can continue from it.
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<PathBuf>,
+    /// Writes every refused transaction to PATH as CSV.
+    #[arg(long, value_name = "PATH")]
+    rejections: Option<PathBuf>,
+    /// Writes what rounding took away from the accepted amounts of every currency to PATH as CSV.
+    #[arg(long, value_name = "PATH")]
+    residuals: Option<PathBuf>,
+    /// Writes every fee charged, with the transaction it was charged on, to PATH as CSV.
+    #[arg(long, value_name = "PATH")]
+    fee_postings: Option<PathBuf>,
+    #[command(flatten)]
+    fees: FeeArgs,
+    /// Balance order: client, total, available or locked.
+    #[arg(long, value_name = "ORDER", default_value = "client")]
+    order: ExportOrder,
+    /// Bounds the memory taken by stored transactions, spilling them to disk beyond it. Accepts a k, m or g suffix.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    memory_budget: Option<usize>,
+    /// Directory of the spill files, the system temporary directory by default.
+    #[arg(long, value_name = "PATH")]
+    spill_dir: Option<PathBuf>,
+}
+
+/// The write-ahead journal of a run.
+#[derive(Args)]
+struct JournalArgs {
+    /// Journals every transaction before it touches a balance, resuming a crashed run if PATH exists.
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+    /// Number of journaled transactions between two syncs to disk.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_SYNC_EVERY)]
+    journal_sync_every: usize,
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    /// Files, directories or glob patterns, stdin when none is given.
+    #[arg(value_name = "INPUT")]
+    inputs: Vec<String>,
+    /// Order of the files of a directory or glob: name, mtime or sequence.
+    #[arg(long, value_name = "ORDER", default_value = "name")]
+    input_order: InputOrder,
+    /// Applies transactions on N worker threads sharded by client.
+    #[arg(long, value_name = "N", conflicts_with = "journal")]
+    threads: Option<usize>,
+    #[command(flatten)]
+    read: ReadArgs,
+    #[command(flatten)]
+    output: OutputArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    #[command(flatten)]
+    journal: JournalArgs,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Files, directories or glob patterns, stdin when none is given.
+    #[arg(value_name = "INPUT")]
+    inputs: Vec<String>,
+    /// Order of the files of a directory or glob: name, mtime or sequence.
+    #[arg(long, value_name = "ORDER", default_value = "name")]
+    input_order: InputOrder,
+    /// Validates against a ledger snapshot instead of an empty ledger.
+    #[arg(long, value_name = "PATH")]
+    load_state: Option<PathBuf>,
+    /// Writes the transactions refused by this run to PATH as CSV.
+    #[arg(long, value_name = "PATH")]
+    rejections: Option<PathBuf>,
+    /// Compresses the rejections with gzip or zstd, by default only paths ending in .gz or .zst are.
+    #[arg(long, value_name = "CODEC", requires = "rejections")]
+    compress: Option<Compression>,
+    #[command(flatten)]
+    dialect: DialectArgs,
+    #[command(flatten)]
+    fees: FeeArgs,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// The journal left by the crashed run.
+    #[arg(value_name = "JOURNAL")]
+    journal: PathBuf,
+    #[command(flatten)]
+    precision: PrecisionArgs,
+    #[command(flatten)]
+    output: OutputArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// The ledger snapshot, as written by --save-state.
+    #[arg(value_name = "SNAPSHOT")]
+    snapshot: PathBuf,
+    /// Writes the balance of CLIENT, may be repeated. Every balance is written when neither --client nor --tx is given.
+    #[arg(long, value_name = "CLIENT", conflicts_with = "tx")]
+    client: Vec<u16>,
+    /// Writes the stored deposit or withdrawal TX with its dispute state, may be repeated.
+    #[arg(long, value_name = "TX")]
+    tx: Vec<u32>,
+    /// Balance order: client, total, available or locked.
+    #[arg(long, value_name = "ORDER", default_value = "client")]
+    order: ExportOrder,
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Serves the HTTP/JSON API instead of CSV over TCP.
+    #[arg(long)]
+    http: bool,
+    /// Address to listen on, 127.0.0.1:7878 by default or 127.0.0.1:8080 with --http.
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<String>,
+    #[command(flatten)]
+    read: ReadArgs,
+    #[command(flatten)]
+    output: OutputArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    #[command(flatten)]
+    journal: JournalArgs,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// The balances before, such as the output of a previous release.
+    #[arg(value_name = "LEFT")]
+    left: PathBuf,
+    /// The balances after.
+    #[arg(value_name = "RIGHT")]
+    right: PathBuf,
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+/// Whether a run that did not fail completed cleanly.
+enum Outcome {
+    Complete,
+    Partial,
+}
+
+impl Outcome {
+    fn partial_if(partial: bool) -> Self {
+        if partial {
+            Self::Partial
+        } else {
+            Self::Complete
+        }
+    }
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Complete => ExitCode::SUCCESS,
+            Outcome::Partial => ExitCode::from(EXIT_PARTIAL),
+        }
+    }
+}
+
+/// Parses a single byte field delimiter, `\t` standing for a tab.
+fn parse_delimiter(delimiter: &str) -> Result<u8> {
+    match delimiter.as_bytes() {
+        [byte] => Ok(*byte),
+        _ if delimiter == "\\t" => Ok(b'\t'),
+        _ => Err(anyhow!("the delimiter must be a single byte character")),
+    }
+}
+
+/// Parses a byte size with an optional binary `k`, `m` or `g` suffix.
+fn parse_size(size: &str) -> Result<usize> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((at, suffix)) if suffix.is_ascii_alphabetic() => (&size[..at], suffix),
+        _ => (size, 'b'),
+    };
+    let shift = match unit.to_ascii_lowercase() {
+        'b' => 0,
+        'k' => 10,
+        'm' => 20,
+        'g' => 30,
+        _ => Err(anyhow!("unknown size unit {unit:?}, expected k, m or g"))?,
     };
+    digits
+        .parse::<usize>()?
+        .checked_mul(1 << shift)
+        .ok_or(anyhow!("size {size} is too large"))
+}
 
-    let reader = ReaderBuilder::new()
-        .has_headers(DEFAULT_HAS_HEADERS)
-        .from_reader(input);
-    let writer = writer::StdOutCSVWriter::new();
-    let accountant = ledger::Accountant::new();
+impl ReadArgs {
+    fn reader_config(&self) -> ReaderConfig {
+        ReaderConfig::default()
+            .with_format(self.input_format)
+            .with_headers(!self.no_headers)
+            .with_trim(!self.no_trim)
+            .with_flexible(!self.strict_columns)
+            .with_delimiter(self.delimiter)
+    }
 
-    let mut engine = processor::Engine::new(writer, reader, accountant);
+    /// Opens the input at `path`, or stdin when there is none.
+    fn open(&self, path: Option<&Path>) -> Result<Box<dyn RecordRead>> {
+        let columns = self.columns.clone().unwrap_or_default();
+        Ok(match (path, self.input_format) {
+            (Some(path), InputFormat::Parquet) => {
+                Box::new(ParquetReader::with_columns(File::open(path)?, columns)?)
+            }
+            (None, InputFormat::Parquet) => {
+                let mut input = Vec::new();
+                decompress(io::stdin())?.read_to_end(&mut input)?;
+                Box::new(ParquetReader::with_columns(Bytes::from(input), columns)?)
+            }
+            (Some(path), _) => self.reader_config().open(Box::new(File::open(path)?))?,
+            (None, _) => self.reader_config().open(Box::new(io::stdin()))?,
+        })
+    }
+}
+
+impl PrecisionArgs {
+    fn precision(&self) -> Result<Precision> {
+        Ok(Precision::default()
+            .with_scale(self.max_scale)?
+            .with_rounding(self.rounding)
+            .with_excess(self.excess_precision))
+    }
+}
+
+impl FeeArgs {
+    /// Charges the fees of the schedule, if one is given, to `accountant`.
+    fn apply(&self, accountant: Accountant) -> Result<Accountant> {
+        Ok(match &self.fees {
+            None => accountant,
+            Some(path) => {
+                accountant.with_fees(FeeSchedule::open(path)?.with_house(self.house_account))
+            }
+        })
+    }
+}
+
+impl DialectArgs {
+    fn dialect(&self) -> CsvDialect {
+        CsvDialect::default()
+            .with_delimiter(self.output_delimiter)
+            .with_quote_style(self.quote_style)
+            .with_terminator(self.line_terminator)
+            .with_headers(!self.no_output_headers)
+    }
+}
+
+impl OutputArgs {
+    /// The codec of the output at `path`, or of stdout when there is none.
+    fn compression(&self, path: Option<&Path>) -> Compression {
+        self.compress
+            .unwrap_or_else(|| path.map(Compression::from_extension).unwrap_or_default())
+    }
 
-    engine.run()?;
+    fn dialect(&self) -> CsvDialect {
+        self.dialect.dialect()
+    }
+
+    fn writer(&self) -> Result<Output> {
+        let compression = self.compression(self.output.as_deref());
+        Ok(match (&self.output, self.format) {
+            (None, OutputFormat::Csv) => Output::Stdout(RecordWriter::csv(
+                io::stdout(),
+                self.dialect(),
+                compression,
+            )?),
+            (None, format) => {
+                Output::Stdout(RecordWriter::compressed(io::stdout(), format, compression)?)
+            }
+            (Some(path), OutputFormat::Csv) => {
+                Output::File(FileRecordWriter::csv(path, self.dialect(), compression)?)
+            }
+            (Some(path), format) => {
+                Output::File(FileRecordWriter::create(path, format, compression)?)
+            }
+        })
+    }
+}
+
+/// The destination of the records of a run: stdout, or a file that appears once complete.
+enum Output {
+    Stdout(RecordWriter<Stdout>),
+    File(FileRecordWriter),
+}
+
+impl RecordWrite for Output {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        match self {
+            Self::Stdout(writer) => writer.write_record(record),
+            Self::File(writer) => writer.write_record(record),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            Self::Stdout(writer) => writer.finish(),
+            Self::File(writer) => writer.finish(),
+        }
+    }
+}
+
+impl LedgerArgs {
+    /// Builds a store spilling to a scratch file once it holds `budget` bytes of transactions.
+    fn spill_store(&self, budget: usize, name: usize) -> Result<Box<dyn TransactionStore>> {
+        let dir = self.spill_dir.clone().unwrap_or_else(env::temp_dir);
+        let path = dir.join(format!("play-with-csv-{}-{name}.spill", std::process::id()));
+        Ok(Box::new(SpillStore::new(budget, DiskStore::create(path)?)))
+    }
+
+    /// Builds the ledger the run starts from, its memory budget shared by `shards` workers.
+    fn accountant(&self, shards: usize, precision: Precision) -> Result<Accountant> {
+        let store = match self.memory_budget {
+            None => Box::new(MemoryStore::new()) as Box<dyn TransactionStore>,
+            Some(budget) => self.spill_store(budget / shards, shards)?,
+        };
+        let accountant = match &self.load_state {
+            None => Accountant::new().with_store(store)?,
+            Some(path) => Accountant::restore_with_store(BufReader::new(File::open(path)?), store)?,
+        };
+        self.fees.apply(
+            accountant
+                .with_precision(precision)
+                .with_export_order(self.order),
+        )
+    }
+
+    /// Splits `accountant` over `threads` workers.
+    fn sharded(&self, accountant: Accountant, threads: usize) -> Result<ShardedAccountant> {
+        let shards = threads.max(1);
+        match self.memory_budget {
+            None => ShardedAccountant::new(accountant, threads),
+            Some(budget) => ShardedAccountant::with_stores(
+                accountant,
+                (0..shards)
+                    .map(|index| self.spill_store(budget / shards, index))
+                    .collect::<Result<_>>()?,
+            ),
+        }
+    }
+
+    /// Writes the rejections, the residuals, the fee postings and the snapshot of the ledger the run ended with.
+    fn save(&self, accountant: &Accountant, output: &OutputArgs) -> Result<()> {
+        if let Some(path) = &self.rejections {
+            write_rejections(
+                accountant.rejections(),
+                path,
+                output.dialect(),
+                output.compression(Some(path)),
+            )?;
+        }
+        if let Some(path) = &self.residuals {
+            let mut writer =
+                FileRecordWriter::csv(path, output.dialect(), output.compression(Some(path)))?;
+            for residual in accountant.residuals() {
+                writer.write_record(residual)?;
+            }
+            writer.finish()?;
+        }
+        if let Some(path) = &self.fee_postings {
+            let mut writer =
+                FileRecordWriter::csv(path, output.dialect(), output.compression(Some(path)))?;
+            for posting in accountant.fee_postings() {
+                writer.write_record(posting)?;
+            }
+            writer.finish()?;
+        }
+        if let Some(path) = &self.save_state {
+            let mut file = AtomicFile::create(path)?;
+            accountant.snapshot(BufWriter::new(&mut file))?;
+            file.commit()?;
+        }
+        Ok(())
+    }
+}
+
+impl JournalArgs {
+    /// Attaches the journal, replaying the entries a crashed run left.
+    ///
+    /// # Returns
+    /// The input offset to resume from.
+    fn attach(&self, accountant: &mut Accountant) -> Result<u64> {
+        Ok(match &self.journal {
+            None => None,
+            Some(path) => accountant.attach_journal(path, self.journal_sync_every)?,
+        }
+        .unwrap_or(0))
+    }
+
+    /// Closes and removes the journal of a run that completed.
+    fn close(&self, accountant: &mut Accountant) -> Result<()> {
+        if let Some(path) = &self.journal {
+            accountant.close_journal()?;
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_rejections(
+    rejections: &[Rejection],
+    path: &Path,
+    dialect: CsvDialect,
+    compression: Compression,
+) -> Result<()> {
+    let mut writer = FileRecordWriter::csv(path, dialect, compression)?;
+    for rejection in rejections {
+        writer.write_record(rejection)?;
+    }
+    writer.finish()
+}
+
+fn export(accountant: &impl CSVExport, mut writer: impl RecordWrite) -> Result<()> {
+    accountant.export(RefCell::new(&mut writer))?;
+    writer.finish()
+}
+
+/// Applies every input in order to one ledger, then exports the balances to `writer`.
+/// Reads stdin when `inputs` is empty.
+///
+/// # Returns
+/// The accountant and the number of malformed rows skipped.
+fn ingest<A>(
+    read: &ReadArgs,
+    inputs: &[PathBuf],
+    writer: impl RecordWrite,
+    accountant: A,
+    resume_offset: u64,
+) -> Result<(A, usize)>
+where
+    A: Accounting + CSVExport,
+{
+    let sources: Vec<Option<&Path>> = match inputs {
+        [] => vec![None],
+        _ => inputs.iter().map(|path| Some(path.as_path())).collect(),
+    };
+    let several = sources.len() > 1;
+
+    let mut engine = Engine::new(writer, read.open(sources[0])?, accountant)
+        .with_error_policy(read.error_policy)
+        .with_resume_offset(resume_offset);
+
+    let mut malformed = 0;
+    for (index, source) in sources.iter().enumerate() {
+        let name = source.map_or("stdin".into(), |path| path.display().to_string());
+        if index > 0 {
+            engine.set_reader(read.open(*source).with_context(|| name.clone())?);
+        }
+        let report = engine.ingest().with_context(|| name.clone())?;
+        malformed += report.malformed().len();
+
+        let prefix = if several {
+            format!("{name}: ")
+        } else {
+            String::new()
+        };
+        for row in report.malformed() {
+            eprintln!(
+                "{prefix}skipped malformed row {} (byte {}) {:?}: {}",
+                row.line(),
+                row.byte(),
+                row.raw(),
+                row.error()
+            );
+        }
+        if several {
+            eprintln!(
+                "{name}: {} rows read, {} malformed",
+                report.rows_read(),
+                report.malformed().len()
+            );
+        }
+    }
+    engine.export()?;
+
+    Ok((engine.into_accountant(), malformed))
+}
+
+fn process(args: &ProcessArgs) -> Result<Outcome> {
+    let shards = args.threads.unwrap_or(1).max(1);
+    let mut accountant = args
+        .ledger
+        .accountant(shards, args.read.precision.precision()?)?;
+
+    let inputs = expand_inputs(&args.inputs, args.input_order)?;
+    if inputs.len() > 1 && args.journal.journal.is_some() {
+        Err(anyhow!("--journal can only resume a single input"))?
+    }
+    let resume_offset = args.journal.attach(&mut accountant)?;
+
+    let writer = args.output.writer()?;
+    let (mut accountant, malformed) = match args.threads {
+        None => ingest(&args.read, &inputs, writer, accountant, resume_offset)?,
+        Some(threads) => {
+            let sharded = args.ledger.sharded(accountant, threads)?;
+            let (sharded, malformed) = ingest(&args.read, &inputs, writer, sharded, 0)?;
+            (sharded.into_accountant()?, malformed)
+        }
+    };
+
+    args.ledger.save(&accountant, &args.output)?;
+    args.journal.close(&mut accountant)?;
+    Ok(Outcome::partial_if(malformed > 0))
+}
+
+fn validate(args: &ValidateArgs) -> Result<Outcome> {
+    let accountant = args.fees.apply(
+        match &args.load_state {
+            None => Accountant::new(),
+            Some(path) => Accountant::restore(BufReader::new(File::open(path)?))?,
+        }
+        .with_precision(args.read.precision.precision()?),
+    )?;
+    let previous = accountant.rejections().len();
+
+    let inputs = expand_inputs(&args.inputs, args.input_order)?;
+    let discard = RecordWriter::new(io::sink(), OutputFormat::Csv);
+    let (accountant, malformed) = ingest(&args.read, &inputs, discard, accountant, 0)?;
+
+    let refused = &accountant.rejections()[previous..];
+    for rejection in refused {
+        eprintln!(
+            "refused {} {} of client {} (line {}): {}",
+            rejection.type_(),
+            rejection.tx(),
+            rejection.client(),
+            rejection
+                .input_line()
+                .map_or("unknown".into(), |line| line.to_string()),
+            rejection.reason()
+        );
+    }
+    eprintln!(
+        "{malformed} malformed rows, {} refused transactions",
+        refused.len()
+    );
+    if let Some(path) = &args.rejections {
+        write_rejections(
+            refused,
+            path,
+            args.dialect.dialect(),
+            args.compress
+                .unwrap_or_else(|| Compression::from_extension(path)),
+        )?;
+    }
+    Ok(Outcome::partial_if(malformed > 0 || !refused.is_empty()))
+}
+
+fn replay(args: &ReplayArgs) -> Result<Outcome> {
+    if !args.journal.exists() {
+        Err(anyhow!("journal {} does not exist", args.journal.display()))?
+    }
+    let mut accountant = args.ledger.accountant(1, args.precision.precision()?)?;
+    accountant.attach_journal(&args.journal, DEFAULT_SYNC_EVERY)?;
+    accountant.close_journal()?;
+
+    export(&accountant, args.output.writer()?)?;
+    args.ledger.save(&accountant, &args.output)?;
+    Ok(Outcome::Complete)
+}
+
+/// A stored transaction as written by `query --tx`.
+#[derive(Serialize)]
+struct TransactionRow {
+    tx: u32,
+    client: u16,
+    #[serde(rename = "type")]
+    type_: String,
+    amount: Option<String>,
+    to: Option<u16>,
+    currency: Option<String>,
+    state: String,
+}
+
+impl From<Transaction> for TransactionRow {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            tx: *transaction.tx(),
+            client: *transaction.client(),
+            type_: transaction.type_().to_string(),
+            amount: transaction.amount().map(|amount| amount.to_string()),
+            to: *transaction.to(),
+            currency: transaction.currency().map(|currency| currency.to_string()),
+            state: transaction.state().to_string(),
+        }
+    }
+}
+
+fn query(args: &QueryArgs) -> Result<Outcome> {
+    let accountant = Accountant::restore(BufReader::new(File::open(&args.snapshot)?))?
+        .with_export_order(args.order);
+    if args.client.is_empty() && args.tx.is_empty() {
+        export(&accountant, args.output.writer()?)?;
+        return Ok(Outcome::Complete);
+    }
+
+    let mut writer = args.output.writer()?;
+    let mut missing = false;
+    for client in &args.client {
+        if !accountant.export_client(*client, RefCell::new(&mut writer))? {
+            eprintln!("client {client} not found");
+            missing = true;
+        }
+    }
+    for tx in &args.tx {
+        match accountant.transaction(*tx)? {
+            Some(transaction) => writer.write_record(&TransactionRow::from(transaction))?,
+            None => {
+                eprintln!("transaction {tx} not found");
+                missing = true;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(Outcome::partial_if(missing))
+}
+
+/// Serves connections or HTTP requests until ctrl-c, then writes the balances.
+fn serve(args: &ServeArgs) -> Result<Outcome> {
+    if args.read.input_format == InputFormat::Parquet {
+        Err(anyhow!("parquet input cannot be streamed to serve"))?
+    }
+    let mut accountant = args
+        .ledger
+        .accountant(1, args.read.precision.precision()?)?;
+    args.journal.attach(&mut accountant)?;
+
+    let reader_config = args.read.reader_config();
+    let shutdown = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let mut accountant = runtime.block_on(async {
+        if args.http {
+            let listen = args.listen.as_deref().unwrap_or(DEFAULT_HTTP_LISTEN);
+            let server = HttpServer::bind(listen, accountant)
+                .await?
+                .with_reader_config(reader_config)
+                .with_error_policy(args.read.error_policy);
+            eprintln!("listening on http://{}", server.local_addr()?);
+            server.run_until(shutdown).await
+        } else {
+            let listen = args.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+            let server = Server::bind(listen, accountant)
+                .await?
+                .with_reader_config(reader_config)
+                .with_error_policy(args.read.error_policy);
+            eprintln!("listening on {}", server.local_addr()?);
+            server.run_until(shutdown).await
+        }
+    })?;
+
+    export(&accountant, args.output.writer()?)?;
+    args.ledger.save(&accountant, &args.output)?;
+    args.journal.close(&mut accountant)?;
+    Ok(Outcome::Complete)
+}
+
+/// A balance row as read back by `diff`, values kept as written.
+#[derive(Deserialize)]
+struct BalanceRow {
+    client: u16,
+    #[serde(default)]
+    currency: String,
+    available: String,
+    held: String,
+    total: String,
+    locked: String,
+}
+
+impl BalanceRow {
+    fn fields(&self) -> [(&'static str, &str); 4] {
+        [
+            ("available", &self.available),
+            ("held", &self.held),
+            ("total", &self.total),
+            ("locked", &self.locked),
+        ]
+    }
+}
+
+/// A balance field that differs between two balance files, empty on the side missing the client.
+#[derive(Serialize)]
+struct BalanceDiff<'a> {
+    client: u16,
+    currency: &'a str,
+    field: &'static str,
+    left: &'a str,
+    right: &'a str,
+}
+
+fn read_balances(path: &Path) -> Result<BTreeMap<(u16, String), BalanceRow>> {
+    let mut reader = ReaderConfig::default().build(decompress(File::open(path)?)?);
+    let mut balances = BTreeMap::new();
+    for row in reader.deserialize::<BalanceRow>() {
+        let row = row.with_context(|| path.display().to_string())?;
+        balances.insert((row.client, row.currency.to_ascii_uppercase()), row);
+    }
+    Ok(balances)
+}
+
+/// Whether two written values are equal, amounts compared by value so `1.5` equals `1.5000`.
+fn same(left: &str, right: &str) -> bool {
+    match (left.parse::<Decimal>(), right.parse::<Decimal>()) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+fn diff(args: &DiffArgs) -> Result<Outcome> {
+    let left = read_balances(&args.left)?;
+    let right = read_balances(&args.right)?;
+    let keys: BTreeSet<&(u16, String)> = left.keys().chain(right.keys()).collect();
+
+    let mut writer = args.output.writer()?;
+    let mut differs = false;
+    let missing = [
+        ("available", ""),
+        ("held", ""),
+        ("total", ""),
+        ("locked", ""),
+    ];
+    for key @ (client, currency) in keys {
+        let left_fields = left.get(key).map_or(missing, BalanceRow::fields);
+        let right_fields = right.get(key).map_or(missing, BalanceRow::fields);
+        for ((field, left), (_, right)) in left_fields.into_iter().zip(right_fields) {
+            if !same(left, right) {
+                differs = true;
+                writer.write_record(&BalanceDiff {
+                    client: *client,
+                    currency,
+                    field,
+                    left,
+                    right,
+                })?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(Outcome::partial_if(differs))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let outcome = match &cli.command {
+        None => process(&cli.process),
+        Some(Command::Process(args)) => process(args),
+        Some(Command::Validate(args)) => validate(args),
+        Some(Command::Replay(args)) => replay(args),
+        Some(Command::Query(args)) => query(args),
+        Some(Command::Serve(args)) => serve(args),
+        Some(Command::Diff(args)) => diff(args),
+    };
 
-    Ok(())
+    match outcome {
+        Ok(outcome) => outcome.into(),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
 }
@@ -1,39 +1,170 @@
-use crate::errors::LedgerError;
+use crate::errors::{AmountError, InvalidCurrency, LedgerError, UnknownTransactionType};
+use crate::precision::{FIXED_POINT, Precision};
 use anyhow::Result;
 use getset::Getters;
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 const ZERO: Decimal = dec!(0.0000);
 
-fn round_four_decimals<S>(x: &Decimal, s: S) -> Result<S::Ok, S::Error>
+/// Default number of decimal places accepted on input amounts.
+pub const DEFAULT_MAX_SCALE: u32 = 4;
+
+/// Longest currency code accepted.
+pub const MAX_CURRENCY_LEN: usize = 8;
+
+fn deserialize_amount<'de, D>(d: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(d)? {
+        None => Ok(None),
+        Some(raw) if raw.is_empty() => Ok(None),
+        Some(raw) => Decimal::from_str_exact(&raw)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid amount {raw:?}"))),
+    }
+}
+
+fn deserialize_client<'de, D>(d: D) -> Result<Option<u16>, D::Error>
 where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(d)? {
+        None => Ok(None),
+        Some(raw) if raw.is_empty() => Ok(None),
+        Some(raw) => raw
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid client {raw:?}"))),
+    }
+}
+
+fn deserialize_currency<'de, D>(d: D) -> Result<Option<Currency>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(d)? {
+        None => Ok(None),
+        Some(raw) if raw.is_empty() => Ok(None),
+        Some(raw) => raw.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+fn display<T, S>(x: &T, s: S) -> Result<S::Ok, S::Error>
+where
+    T: fmt::Display,
     S: serde::Serializer,
 {
-    s.serialize_str(&format!("{x:.4}"))
+    s.collect_str(x)
 }
 
+fn fixed_point<S>(x: &Decimal, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    s.serialize_newtype_struct(FIXED_POINT, &x.to_string())
+}
+
+/// A currency or asset code such as `EUR`, `GBP` or `USDC`.
+///
+/// Codes are one to eight ASCII letters or digits and are matched case-insensitively, so `eur` and `EUR` are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Currency([u8; MAX_CURRENCY_LEN]);
+
+impl Currency {
+    /// Returns the upper-case code.
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(MAX_CURRENCY_LEN);
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+
+    /// Returns the code padded with zero bytes, as stored in a snapshot.
+    pub(crate) fn to_bytes(self) -> [u8; MAX_CURRENCY_LEN] {
+        self.0
+    }
+
+    /// Rebuilds a currency from the bytes returned by `to_bytes`.
+    pub(crate) fn from_bytes(bytes: [u8; MAX_CURRENCY_LEN]) -> Self {
+        Currency(bytes)
+    }
+}
+
+impl FromStr for Currency {
+    type Err = InvalidCurrency;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty()
+            || s.len() > MAX_CURRENCY_LEN
+            || !s.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        {
+            return Err(InvalidCurrency(s.to_string()));
+        }
+        let mut code = [0; MAX_CURRENCY_LEN];
+        code[..s.len()].copy_from_slice(s.to_ascii_uppercase().as_bytes());
+        Ok(Currency(code))
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = InvalidCurrency;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.as_str())
+    }
+}
+
+/// Balance of a single client account in one currency.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Getters)]
 pub struct ClientBalance {
+    /// The client id.
     #[getset(get = "pub")]
     client: u16,
+    /// The currency of the balance, `None` for transactions without one.
+    #[getset(get = "pub")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    /// Funds available for withdrawal.
     #[getset(get = "pub")]
-    #[serde(serialize_with = "round_four_decimals")]
+    #[serde(serialize_with = "fixed_point")]
     available: Decimal,
+    /// Funds held by open disputes.
     #[getset(get = "pub")]
-    #[serde(serialize_with = "round_four_decimals")]
+    #[serde(serialize_with = "fixed_point")]
     held: Decimal,
+    /// Available plus held funds.
     #[getset(get = "pub")]
-    #[serde(serialize_with = "round_four_decimals")]
+    #[serde(serialize_with = "fixed_point")]
     total: Decimal,
+    /// Whether the account is locked after a chargeback.
     #[getset(get = "pub")]
     locked: bool,
 }
 
 impl ClientBalance {
+    /// Creates an empty, unlocked balance for `client`.
     pub fn new(client: u16) -> Self {
         ClientBalance {
             client,
+            currency: None,
             available: dec!(0.0000),
             held: dec!(0.0000),
             total: dec!(0.0000),
@@ -41,6 +172,58 @@ impl ClientBalance {
         }
     }
 
+    /// Rebuilds a balance from previously stored parts, used when restoring a snapshot.
+    pub(crate) fn from_parts(
+        client: u16,
+        currency: Option<Currency>,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        locked: bool,
+    ) -> Self {
+        ClientBalance {
+            client,
+            currency,
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+
+    /// Returns the client and currency the balance is kept under.
+    pub(crate) fn key(&self) -> (u16, Option<Currency>) {
+        (self.client, self.currency)
+    }
+
+    /// Sets the currency of the balance.
+    ///
+    /// # Arguments
+    /// * `currency` - The currency, `None` for transactions without one.
+    pub fn with_currency(mut self, currency: Option<Currency>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Pads the amounts of the balance to `scale` decimal places, so they are written with exactly that many.
+    ///
+    /// # Arguments
+    /// * `scale` - The number of decimal places of the precision policy.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        for amount in [&mut self.available, &mut self.held, &mut self.total] {
+            amount.rescale(scale);
+        }
+        self
+    }
+
+    /// Rounds the amounts of the balance to the scale of `precision` with its rounding mode, keeping the total
+    /// the sum of the available and held amounts.
+    pub(crate) fn round(&mut self, precision: &Precision) {
+        self.available = precision.round(self.available);
+        self.held = precision.round(self.held);
+        self.total = self.available + self.held;
+    }
+
     /// Deposits an amount into the client's balance.
     ///
     /// # Arguments
@@ -48,9 +231,9 @@ impl ClientBalance {
     ///
     /// # Returns
     /// A Result indicating success or failure.
-    pub fn deposit(&mut self, amount: &str) -> Result<()> {
+    pub fn deposit(&mut self, amount: Decimal) -> Result<(), LedgerError> {
         self.validate_is_unlocked()?;
-        let amount = amount.parse::<Decimal>()?;
+        Self::validate_is_positive(amount)?;
 
         self.available = self
             .available
@@ -64,9 +247,16 @@ impl ClientBalance {
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: &str) -> Result<()> {
+    /// Withdraws an amount from the client's available balance.
+    ///
+    /// # Arguments
+    /// * `amount` - The amount to withdraw.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), LedgerError> {
         self.validate_is_unlocked()?;
-        let amount = amount.parse::<Decimal>()?;
+        Self::validate_is_positive(amount)?;
 
         let available =
             self.available
@@ -110,9 +300,9 @@ impl ClientBalance {
     ///
     /// # Returns
     /// A Result indicating success or failure.
-    pub fn dispute(&mut self, amount: &str) -> Result<()> {
+    pub fn dispute(&mut self, amount: Decimal) -> Result<(), LedgerError> {
         self.validate_is_unlocked()?;
-        let amount = amount.parse::<Decimal>()?;
+        Self::validate_is_positive(amount)?;
         let available =
             self.available
                 .checked_sub(amount)
@@ -144,9 +334,9 @@ impl ClientBalance {
     ///
     /// # Returns
     /// A Result indicating success or failure.
-    pub fn resolve(&mut self, amount: &str) -> Result<()> {
+    pub fn resolve(&mut self, amount: Decimal) -> Result<(), LedgerError> {
         self.validate_is_unlocked()?;
-        let amount = amount.parse::<Decimal>()?;
+        Self::validate_is_positive(amount)?;
         let held = self
             .held
             .checked_sub(amount)
@@ -177,9 +367,9 @@ impl ClientBalance {
     ///
     /// # Returns
     /// A Result indicating success or failure.
-    pub fn chargeback(&mut self, amount: &str) -> Result<()> {
+    pub fn chargeback(&mut self, amount: Decimal) -> Result<(), LedgerError> {
         self.validate_is_unlocked()?;
-        let amount = amount.parse::<Decimal>()?;
+        Self::validate_is_positive(amount)?;
         let held = self
             .held
             .checked_sub(amount)
@@ -214,7 +404,14 @@ impl ClientBalance {
         Ok(())
     }
 
-    fn validate_is_unlocked(&self) -> Result<()> {
+    fn validate_is_positive(amount: Decimal) -> Result<(), LedgerError> {
+        if amount <= ZERO {
+            return Err(LedgerError::NonPositiveAmount(amount))?;
+        }
+        Ok(())
+    }
+
+    fn validate_is_unlocked(&self) -> Result<(), LedgerError> {
         if self.locked {
             return Err(LedgerError::AccountLocked(self.client))?;
         }
@@ -222,53 +419,373 @@ impl ClientBalance {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+/// A balance written with its currency column, used for every balance of a ledger holding currencies
+/// so all rows have the same columns.
+#[derive(Serialize)]
+pub(crate) struct CurrencyBalance {
+    client: u16,
+    currency: Option<Currency>,
+    #[serde(serialize_with = "fixed_point")]
+    available: Decimal,
+    #[serde(serialize_with = "fixed_point")]
+    held: Decimal,
+    #[serde(serialize_with = "fixed_point")]
+    total: Decimal,
+    locked: bool,
+}
+
+impl From<&ClientBalance> for CurrencyBalance {
+    fn from(balance: &ClientBalance) -> Self {
+        CurrencyBalance {
+            client: balance.client,
+            currency: balance.currency,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+            locked: balance.locked,
+        }
+    }
+}
+
+/// Transaction type names are matched case-insensitively, so `deposit`, `Deposit` and `DEPOSIT` are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+#[non_exhaustive]
 pub enum TransactionType {
+    /// Credits the client account.
     #[serde(rename = "deposit")]
     Deposit,
+    /// Debits the client account.
     #[serde(rename = "withdrawal")]
     Withdrawal,
+    /// Holds the funds of a previous transaction.
     #[serde(rename = "dispute")]
     Dispute,
+    /// Releases the funds held by a dispute.
     #[serde(rename = "resolve")]
     Resolve,
+    /// Reverses a disputed transaction and locks the account.
     #[serde(rename = "chargeback")]
     Chargeback,
+    /// Moves funds from the client account to the account named in the `to` column.
+    #[serde(rename = "transfer")]
+    Transfer,
+}
+
+impl FromStr for TransactionType {
+    type Err = UnknownTransactionType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "deposit" => Ok(Self::Deposit),
+            "withdrawal" => Ok(Self::Withdrawal),
+            "dispute" => Ok(Self::Dispute),
+            "resolve" => Ok(Self::Resolve),
+            "chargeback" => Ok(Self::Chargeback),
+            "transfer" => Ok(Self::Transfer),
+            _ => Err(UnknownTransactionType(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for TransactionType {
+    type Error = UnknownTransactionType;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Transfer => "transfer",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Lifecycle of a stored deposit, withdrawal or transfer.
+///
+/// Legal transitions are `Processed -> Disputed` and `Disputed -> Resolved | ChargedBack`.
+/// `Resolved` and `ChargedBack` are terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransactionState {
+    /// Applied and never disputed.
+    #[default]
+    Processed,
+    /// Under an open dispute, its funds are held.
+    Disputed,
+    /// The dispute was resolved and the funds released.
+    Resolved,
+    /// The dispute ended in a chargeback.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Returns the state reached by moving from `self` to `to`.
+    ///
+    /// # Arguments
+    /// * `tx` - The transaction id, used for error reporting.
+    /// * `to` - The requested next state.
+    ///
+    /// # Returns
+    /// The next state or `LedgerError::IllegalTransition` if the move is not allowed.
+    pub fn transition(self, tx: u32, to: TransactionState) -> Result<Self, LedgerError> {
+        match (self, to) {
+            (Self::Processed, Self::Disputed)
+            | (Self::Disputed, Self::Resolved)
+            | (Self::Disputed, Self::ChargedBack) => Ok(to),
+            (from, to) => Err(LedgerError::IllegalTransition { tx, from, to }),
+        }
+    }
 }
 
+impl fmt::Display for TransactionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Processed => "processed",
+            Self::Disputed => "disputed",
+            Self::Resolved => "resolved",
+            Self::ChargedBack => "charged back",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A single input row: a deposit, withdrawal or transfer, or a dispute, resolve or chargeback referencing one.
+///
+/// The optional `to` column names the receiving client of a transfer and the optional `currency` column
+/// the currency of the amount, so rows of the four-column `type,client,tx,amount` schema still parse.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Getters)]
 pub struct Transaction {
+    /// The transaction id, or the referenced transaction id for disputes, resolves and chargebacks.
     #[getset(get = "pub")]
     tx: u32,
+    /// The client id, the sending client of a transfer.
     #[getset(get = "pub")]
     client: u16,
+    /// The amount of a deposit, withdrawal or transfer.
+    #[getset(get = "pub")]
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    amount: Option<Decimal>,
+    /// The receiving client of a transfer.
+    #[getset(get = "pub")]
+    #[serde(default, deserialize_with = "deserialize_client")]
+    to: Option<u16>,
+    /// The currency of the amount, or for disputes, resolves and chargebacks the currency they expect
+    /// the referenced transaction to be in.
+    #[getset(get = "pub")]
+    #[serde(default, deserialize_with = "deserialize_currency")]
+    currency: Option<Currency>,
+    /// The transaction type.
+    #[getset(get = "pub")]
+    #[serde(rename = "type")]
+    type_: TransactionType,
+    /// The lifecycle state of a stored deposit or withdrawal.
+    #[getset(get = "pub")]
+    #[serde(skip)]
+    state: TransactionState,
+    /// The input line the transaction was read from.
+    #[getset(get = "pub")]
+    #[serde(skip)]
+    line: Option<u64>,
+    /// The input byte offset just past the record the transaction was read from.
+    #[getset(get = "pub")]
+    #[serde(skip)]
+    offset: Option<u64>,
+}
+
+impl Transaction {
+    /// Creates a transaction in the `Processed` state.
+    pub fn new(type_: TransactionType, client: u16, tx: u32, amount: Option<Decimal>) -> Self {
+        Transaction {
+            tx,
+            client,
+            amount,
+            to: None,
+            currency: None,
+            type_,
+            state: TransactionState::default(),
+            line: None,
+            offset: None,
+        }
+    }
+
+    /// Attaches the input byte offset just past the record the transaction was read from.
+    ///
+    /// # Arguments
+    /// * `offset` - The byte offset of the next record in the input.
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Names the receiving client of a transfer.
+    ///
+    /// # Arguments
+    /// * `to` - The receiving client id.
+    pub fn with_to(mut self, to: u16) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    /// Sets the currency of the transaction.
+    ///
+    /// # Arguments
+    /// * `currency` - The currency code.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    /// Replaces the amount, used when the ledger rounds it.
+    pub(crate) fn with_amount(mut self, amount: Decimal) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    /// Sets the lifecycle state directly, bypassing transition checks; used when restoring a snapshot.
+    pub(crate) fn with_state(mut self, state: TransactionState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Validates the amount against the transaction type.
+    ///
+    /// Deposits, withdrawals and transfers require a strictly positive amount that `precision` accepts
+    /// and that stays positive once rounded, disputes, resolves and chargebacks must not carry an amount.
+    /// The amount itself is left as received, the ledger rounds it when applying the transaction.
+    ///
+    /// # Arguments
+    /// * `precision` - The precision policy of amounts.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn validate(&self, precision: Precision) -> Result<(), AmountError> {
+        match (self.type_, self.amount) {
+            (
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer,
+                None,
+            ) => Err(AmountError::Missing(self.type_)),
+            (
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer,
+                Some(amount),
+            ) => {
+                if amount <= ZERO {
+                    return Err(AmountError::NotPositive(amount));
+                }
+                let rounded = precision.apply(amount)?;
+                if rounded <= ZERO {
+                    return Err(AmountError::NotPositive(rounded));
+                }
+                Ok(())
+            }
+            (_, Some(_)) => Err(AmountError::Unexpected(self.type_)),
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// Attaches the input line the transaction was read from.
+    ///
+    /// # Arguments
+    /// * `line` - The line number in the input, starting at 1.
+    pub fn with_line(mut self, line: u64) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Moves the transaction to the next lifecycle state.
+    ///
+    /// # Arguments
+    /// * `to` - The requested next state.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn transition(&mut self, to: TransactionState) -> Result<(), LedgerError> {
+        self.state = self.state.transition(self.tx, to)?;
+        Ok(())
+    }
+}
+
+/// A transaction refused by the ledger together with the reason it was refused.
+#[derive(Debug, Clone, PartialEq, Serialize, Getters)]
+pub struct Rejection {
+    /// The refused transaction id.
     #[getset(get = "pub")]
-    amount: String,
+    tx: u32,
+    /// The client named on the refused row.
+    #[getset(get = "pub")]
+    client: u16,
+    /// The refused transaction type.
     #[getset(get = "pub")]
     #[serde(rename = "type")]
     type_: TransactionType,
+    /// Why the transaction was refused.
+    #[getset(get = "pub")]
+    #[serde(serialize_with = "display")]
+    reason: LedgerError,
+    /// The input line the refused transaction was read from.
+    #[getset(get = "pub")]
+    input_line: Option<u64>,
+}
+
+impl Rejection {
+    /// Creates a rejection of `transaction` for `reason`.
+    pub fn new(transaction: &Transaction, reason: LedgerError) -> Self {
+        Rejection {
+            tx: transaction.tx,
+            client: transaction.client,
+            type_: transaction.type_,
+            reason,
+            input_line: transaction.line,
+        }
+    }
+
+    /// Rebuilds a rejection from previously stored parts, used when restoring a snapshot.
+    pub(crate) fn from_parts(
+        tx: u32,
+        client: u16,
+        type_: TransactionType,
+        reason: LedgerError,
+        input_line: Option<u64>,
+    ) -> Self {
+        Rejection {
+            tx,
+            client,
+            type_,
+            reason,
+            input_line,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::precision::ExcessPrecision;
 
     #[test]
     fn test_deposit_increases_balance() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("100.1234")?;
+        client.deposit(dec!(100.1234))?;
         assert_eq!(client.available, dec!(100.1234));
         assert_eq!(client.total, dec!(100.1234));
 
-        client.deposit("0.1")?;
+        client.deposit(dec!(0.1))?;
         assert_eq!(client.available, dec!(100.2234));
         assert_eq!(client.total, dec!(100.2234));
 
-        client.deposit("1.0")?;
+        client.deposit(dec!(1.0))?;
         assert_eq!(client.available, dec!(101.2234));
         assert_eq!(client.total, dec!(101.2234));
 
-        client.deposit("1.1")?;
+        client.deposit(dec!(1.1))?;
         assert_eq!(client.available, dec!(102.3234));
         assert_eq!(client.total, dec!(102.3234));
         Ok(())
@@ -277,8 +794,8 @@ mod tests {
     #[test]
     fn test_withdraw_decreases_balance() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("200.5000")?;
-        client.withdraw("50.5000")?;
+        client.deposit(dec!(200.5000))?;
+        client.withdraw(dec!(50.5000))?;
         assert_eq!(client.available, dec!(150.0000));
         assert_eq!(client.total, dec!(150.0000));
         Ok(())
@@ -287,8 +804,8 @@ mod tests {
     #[test]
     fn test_withdraw_insufficient_funds() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("50")?;
-        let res = client.withdraw("100");
+        client.deposit(dec!(50))?;
+        let res = client.withdraw(dec!(100));
         assert!(res.is_err());
         Ok(())
     }
@@ -296,8 +813,8 @@ mod tests {
     #[test]
     fn test_dispute_moves_funds_to_held() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("150")?;
-        client.dispute("50")?;
+        client.deposit(dec!(150))?;
+        client.dispute(dec!(50))?;
         assert_eq!(client.available, dec!(100));
         assert_eq!(client.held, dec!(50));
         assert_eq!(client.total, dec!(150));
@@ -307,9 +824,9 @@ mod tests {
     #[test]
     fn test_resolve_returns_held_to_available() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("200")?;
-        client.dispute("80")?;
-        client.resolve("80")?;
+        client.deposit(dec!(200))?;
+        client.dispute(dec!(80))?;
+        client.resolve(dec!(80))?;
         assert_eq!(client.available, dec!(200));
         assert_eq!(client.held, dec!(0));
         assert_eq!(client.total, dec!(200));
@@ -319,9 +836,9 @@ mod tests {
     #[test]
     fn test_cannot_resolve_returns_not_enough_held() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("200")?;
-        client.dispute("80")?;
-        let res = client.resolve("90");
+        client.deposit(dec!(200))?;
+        client.dispute(dec!(80))?;
+        let res = client.resolve(dec!(90));
         assert!(res.is_err());
         Ok(())
     }
@@ -329,9 +846,9 @@ mod tests {
     #[test]
     fn test_chargeback_locks_account() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("120")?;
-        client.dispute("50")?;
-        client.chargeback("50")?;
+        client.deposit(dec!(120))?;
+        client.dispute(dec!(50))?;
+        client.chargeback(dec!(50))?;
         assert_eq!(client.total, dec!(70));
         assert_eq!(client.held, dec!(0));
         assert!(client.locked);
@@ -341,9 +858,9 @@ mod tests {
     #[test]
     fn test_chargeback_not_enough_held_negative_balance() -> Result<()> {
         let mut client = ClientBalance::new(1);
-        client.deposit("120")?;
-        client.dispute("50")?;
-        let res = client.chargeback("51");
+        client.deposit(dec!(120))?;
+        client.dispute(dec!(50))?;
+        let res = client.chargeback(dec!(51));
         assert!(res.is_err());
         Ok(())
     }
@@ -352,7 +869,7 @@ mod tests {
     fn test_cannot_deposit_when_locked() {
         let mut client = ClientBalance::new(1);
         client.locked = true;
-        let res = client.deposit("100");
+        let res = client.deposit(dec!(100));
         assert!(res.is_err());
     }
 
@@ -360,7 +877,188 @@ mod tests {
     fn test_cannot_withdraw_when_locked() {
         let mut client = ClientBalance::new(1);
         client.locked = true;
-        let res = client.withdraw("50");
+        let res = client.withdraw(dec!(50));
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_state_legal_transitions() -> Result<()> {
+        let disputed = TransactionState::Processed.transition(1, TransactionState::Disputed)?;
+        assert_eq!(disputed, TransactionState::Disputed);
+        assert_eq!(
+            disputed.transition(1, TransactionState::Resolved)?,
+            TransactionState::Resolved
+        );
+        assert_eq!(
+            disputed.transition(1, TransactionState::ChargedBack)?,
+            TransactionState::ChargedBack
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_illegal_transitions() {
+        use TransactionState::*;
+        let illegal = [
+            (Processed, Resolved),
+            (Processed, ChargedBack),
+            (Disputed, Disputed),
+            (Resolved, Disputed),
+            (Resolved, ChargedBack),
+            (ChargedBack, Disputed),
+            (ChargedBack, Resolved),
+        ];
+        for (from, to) in illegal {
+            let res = from.transition(7, to);
+            assert!(matches!(
+                res,
+                Err(LedgerError::IllegalTransition { tx: 7, from: f, to: t }) if f == from && t == to
+            ));
+        }
+    }
+
+    #[test]
+    fn test_non_positive_amount_is_rejected() {
+        let mut client = ClientBalance::new(1);
+        assert_eq!(
+            client.deposit(dec!(-5)),
+            Err(LedgerError::NonPositiveAmount(dec!(-5)))
+        );
+        assert_eq!(
+            client.withdraw(dec!(0)),
+            Err(LedgerError::NonPositiveAmount(dec!(0)))
+        );
+        assert_eq!(client.available, dec!(0));
+    }
+
+    fn parse(row: &str) -> Result<Transaction> {
+        let file_str = format!("type,client,tx,amount\n{row}\n");
+        let mut reader = csv::ReaderBuilder::new().from_reader(file_str.as_bytes());
+        let transaction = reader
+            .deserialize::<Transaction>()
+            .next()
+            .ok_or(anyhow::anyhow!("no record"))??;
+        Ok(transaction)
+    }
+
+    #[test]
+    fn test_amount_is_parsed_once() -> Result<()> {
+        assert_eq!(*parse("deposit,1,1,1.5")?.amount(), Some(dec!(1.5)));
+        assert_eq!(*parse("dispute,1,1,")?.amount(), None);
+        assert!(parse("deposit,1,1,abc").is_err());
+        assert!(parse("deposit,1,1,1e3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_type_is_case_insensitive() -> Result<()> {
+        assert_eq!(*parse("Deposit,1,1,1.0")?.type_(), TransactionType::Deposit);
+        assert_eq!(
+            *parse("WITHDRAWAL,1,1,1.0")?.type_(),
+            TransactionType::Withdrawal
+        );
+        assert_eq!(
+            *parse("ChargeBack,1,1,")?.type_(),
+            TransactionType::Chargeback
+        );
+        assert!(parse("payout,1,1,1.0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_names_receiving_client() -> Result<()> {
+        let input = "type,client,tx,amount,to\ntransfer,1,7,2.5,2\ndeposit,1,8,1.0,\n";
+        let mut reader = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let rows = reader
+            .deserialize::<Transaction>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(*rows[0].type_(), TransactionType::Transfer);
+        assert_eq!(*rows[0].to(), Some(2));
+        assert_eq!(*rows[1].to(), None);
+
+        assert_eq!(*parse("deposit,1,1,1.0")?.to(), None);
+        assert_eq!(
+            parse("transfer,1,1,")?.validate(Precision::default()),
+            Err(AmountError::Missing(TransactionType::Transfer))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_currency_codes() -> Result<()> {
+        let eur: Currency = "eur".parse()?;
+        assert_eq!(eur, "EUR".parse()?);
+        assert_eq!(eur.to_string(), "EUR");
+        assert_eq!("usdc".parse::<Currency>()?.as_str(), "USDC");
+        for invalid in ["", "EURO-1", "TOOLONGXX", "€"] {
+            assert_eq!(
+                invalid.parse::<Currency>(),
+                Err(InvalidCurrency(invalid.to_string()))
+            );
+        }
+
+        let input = "type,client,tx,amount,to,currency\ndeposit,1,1,2.5,,gbp\ndeposit,1,2,1.0,,\n";
+        let mut reader = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let rows = reader
+            .deserialize::<Transaction>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(*rows[0].currency(), Some("GBP".parse()?));
+        assert_eq!(*rows[1].currency(), None);
+        assert!(parse("deposit,1,1,1.0").is_ok_and(|tx| tx.currency().is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_amount() -> Result<()> {
+        assert_eq!(
+            parse("deposit,1,1,1.1234")?.validate(Precision::default()),
+            Ok(())
+        );
+        assert_eq!(
+            parse("withdrawal,1,1,2")?.validate(Precision::default()),
+            Ok(())
+        );
+        assert_eq!(
+            parse("dispute,1,1,")?.validate(Precision::default()),
+            Ok(())
+        );
+        assert_eq!(
+            parse("deposit,1,1,")?.validate(Precision::default()),
+            Err(AmountError::Missing(TransactionType::Deposit))
+        );
+        assert_eq!(
+            parse("deposit,1,1,-1.0")?.validate(Precision::default()),
+            Err(AmountError::NotPositive(dec!(-1.0)))
+        );
+        assert_eq!(
+            parse("withdrawal,1,1,0")?.validate(Precision::default()),
+            Err(AmountError::NotPositive(dec!(0)))
+        );
+        assert_eq!(
+            parse("deposit,1,1,1.12345")?.validate(Precision::default()),
+            Err(AmountError::TooPrecise {
+                amount: dec!(1.12345),
+                max_scale: 4
+            })
+        );
+        assert_eq!(
+            parse("deposit,1,1,1.12345")?.validate(Precision::default().with_scale(5)?),
+            Ok(())
+        );
+        assert_eq!(
+            parse("deposit,1,1,1.10000")?.validate(Precision::default()),
+            Ok(())
+        );
+        let rounding = Precision::default().with_excess(ExcessPrecision::Round);
+        assert_eq!(parse("deposit,1,1,1.12345")?.validate(rounding), Ok(()));
+        assert_eq!(
+            parse("deposit,1,1,0.00001")?.validate(rounding),
+            Err(AmountError::NotPositive(dec!(0.0000)))
+        );
+        assert_eq!(
+            parse("chargeback,1,1,3.0")?.validate(Precision::default()),
+            Err(AmountError::Unexpected(TransactionType::Chargeback))
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,624 @@
+//! Client-sharded, multi-threaded bookkeeping.
+//!
+//! `ShardedAccountant` is used in place of an `Accountant` by the `Engine`: the engine thread keeps
+//! reading and parsing the input while each transaction is routed to one of N worker threads.
+//! Every worker owns an `Accountant` holding a disjoint set of clients, so transactions of one client
+//! are always applied by the same worker in input order.
+//!
+//! Deposits, withdrawals and transfers are routed by client. Disputes, resolves and chargebacks are routed
+//! to the shard owning the referenced transaction, so a reference to another client's transaction is
+//! rejected with the same reason as in the single-threaded ledger. A deposit, withdrawal or transfer
+//! reusing a transaction id owned by another shard is rejected as duplicated by the router itself.
+//!
+//! A transfer to a client of another shard, and any dispute, resolve or chargeback of such a transfer,
+//! touches two shards. Both workers drain their queued transactions and lend their ledger to the engine
+//! thread, which applies the row across the two ledgers and hands them back, so only those two shards
+//! pause while every other keeps working.
+//!
+//! The only difference from the single-threaded ledger is that such a reused id is rejected even when
+//! its first use was itself rejected.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, hash_map::Entry},
+    mem,
+    sync::mpsc::{Receiver, SyncSender, sync_channel},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    errors::LedgerError,
+    ledger::Accountant,
+    models::{Rejection, Transaction, TransactionType},
+    precision::Precision,
+    store::MemoryStore,
+    traits::{Accounting, CSVExport, RecordWrite, TransactionStore},
+};
+
+const BATCH_SIZE: usize = 1024;
+const CHANNEL_DEPTH: usize = 16;
+
+type Batch = Vec<(u64, Transaction)>;
+/// A finished shard, with the sequences of the rows it rejected and of the rows it charged a fee.
+type ShardResult = Result<(Accountant, Vec<u64>, Vec<u64>)>;
+
+/// A lent ledger handed back, with the sequence of the row it rejected while lent.
+type Returned = (Accountant, Option<u64>);
+
+enum Work {
+    /// Transactions to apply in order.
+    Apply(Batch),
+    /// Sends the ledger on the first channel and waits for it to come back on the second.
+    Lend(SyncSender<Accountant>, Receiver<Returned>),
+}
+
+struct Shard {
+    sender: SyncSender<Work>,
+    pending: Batch,
+    handle: JoinHandle<ShardResult>,
+}
+
+enum State {
+    Running(Vec<Shard>),
+    Finished,
+}
+
+/// ShardedAccountant applies transactions on several worker threads, each owning a shard of the clients.
+pub struct ShardedAccountant {
+    base: Accountant,
+    state: State,
+    /// The shard owning every deposit, withdrawal and transfer id, one entry each outside any memory budget.
+    owners: HashMap<u32, u16>,
+    spanning: HashMap<u32, usize>,
+    transaction_historical_order: Vec<u32>,
+    transactions_rejected: Vec<(u64, Rejection)>,
+    next_sequence: u64,
+}
+
+impl ShardedAccountant {
+    /// Splits `accountant` into `shards` worker threads and starts them.
+    ///
+    /// # Arguments
+    /// * `accountant` - The starting state, usually empty or restored from a snapshot.
+    /// * `shards` - The number of worker threads, at least one.
+    ///
+    /// # Returns
+    /// The running sharded accountant.
+    pub fn new(accountant: Accountant, shards: usize) -> Result<Self> {
+        let stores = (0..shards.clamp(1, usize::from(u16::MAX)))
+            .map(|_| Box::new(MemoryStore::new()) as Box<dyn TransactionStore>)
+            .collect();
+        Self::with_stores(accountant, stores)
+    }
+
+    /// Splits `accountant` into one worker thread per transaction store and starts them.
+    ///
+    /// # Arguments
+    /// * `accountant` - The starting state, usually empty or restored from a snapshot.
+    /// * `stores` - The empty transaction store of every shard, at least one and at most `u16::MAX`.
+    ///
+    /// # Returns
+    /// The running sharded accountant.
+    pub fn with_stores(
+        mut accountant: Accountant,
+        stores: Vec<Box<dyn TransactionStore>>,
+    ) -> Result<Self> {
+        if stores.is_empty() || stores.len() > usize::from(u16::MAX) {
+            Err(anyhow!("expected between 1 and {} shards", u16::MAX))?
+        }
+        let parts = accountant.split(stores)?;
+
+        let count = parts.len();
+        let mut owners = HashMap::new();
+        let mut spanning = HashMap::new();
+        let mut workers = Vec::with_capacity(count);
+        for (index, part) in parts.into_iter().enumerate() {
+            for (tx, to) in part.transaction_ids()? {
+                owners.insert(tx, index as u16);
+                if let Some(receiver) = to
+                    .map(|to| Accountant::shard_of(to, count))
+                    .filter(|receiver| *receiver != index)
+                {
+                    spanning.insert(tx, receiver);
+                }
+            }
+            let (sender, receiver) = sync_channel(CHANNEL_DEPTH);
+            let handle = thread::Builder::new()
+                .name(format!("shard-{index}"))
+                .spawn(move || Self::work(part, receiver))?;
+            workers.push(Shard {
+                sender,
+                pending: Vec::with_capacity(BATCH_SIZE),
+                handle,
+            });
+        }
+
+        Ok(Self {
+            base: accountant,
+            state: State::Running(workers),
+            owners,
+            spanning,
+            transaction_historical_order: Vec::new(),
+            transactions_rejected: Vec::new(),
+            next_sequence: 0,
+        })
+    }
+
+    /// Waits for all shards and returns the merged ledger.
+    pub fn into_accountant(mut self) -> Result<Accountant> {
+        self.finish()?;
+        Ok(self.base)
+    }
+
+    fn work(mut accountant: Accountant, receiver: Receiver<Work>) -> ShardResult {
+        let mut rejected = Vec::new();
+        let mut charged = Vec::new();
+        for work in receiver {
+            match work {
+                Work::Apply(batch) => {
+                    for (sequence, transaction) in batch {
+                        let before = accountant.rejections().len();
+                        let postings = accountant.fee_postings().len();
+                        accountant.apply_bookkeeping(transaction)?;
+                        if accountant.rejections().len() > before {
+                            rejected.push(sequence);
+                        }
+                        if accountant.fee_postings().len() > postings {
+                            charged.push(sequence);
+                        }
+                    }
+                }
+                Work::Lend(lend, back) => {
+                    lend.send(accountant)
+                        .map_err(|_| anyhow!("sharded accountant stopped unexpectedly"))?;
+                    let sequence;
+                    (accountant, sequence) = back
+                        .recv()
+                        .map_err(|_| anyhow!("lent ledger was not handed back"))?;
+                    rejected.extend(sequence);
+                }
+            }
+        }
+        Ok((accountant, rejected, charged))
+    }
+
+    fn route(&mut self, transaction: &Transaction, shards: usize) -> Option<usize> {
+        let home = Accountant::shard_of(*transaction.client(), shards);
+        match transaction.type_() {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer => {
+                match self.owners.entry(*transaction.tx()) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(home as u16);
+                        Some(home)
+                    }
+                    Entry::Occupied(entry) if usize::from(*entry.get()) == home => Some(home),
+                    Entry::Occupied(_) => None,
+                }
+            }
+            _ => Some(
+                self.owners
+                    .get(transaction.tx())
+                    .map_or(home, |owner| usize::from(*owner)),
+            ),
+        }
+    }
+
+    /// Returns the shard holding the receiving client when `transaction` moves funds from shard `index`
+    /// to another shard.
+    fn counterpart(&self, transaction: &Transaction, index: usize, shards: usize) -> Option<usize> {
+        let receiver = match transaction.type_() {
+            TransactionType::Transfer => {
+                transaction.to().map(|to| Accountant::shard_of(to, shards))
+            }
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+            _ => self.spanning.get(transaction.tx()).copied(),
+        };
+        receiver.filter(|receiver| *receiver != index)
+    }
+
+    fn send(shard: &mut Shard) -> Result<()> {
+        let batch = mem::replace(&mut shard.pending, Vec::with_capacity(BATCH_SIZE));
+        shard
+            .sender
+            .send(Work::Apply(batch))
+            .map_err(|_| anyhow!("shard worker stopped unexpectedly"))
+    }
+
+    /// Asks the worker of `shard` for its ledger once its queued transactions are applied.
+    fn lend(shard: &mut Shard) -> Result<(Receiver<Accountant>, SyncSender<Returned>)> {
+        if !shard.pending.is_empty() {
+            Self::send(shard)?;
+        }
+        let (lend, lent) = sync_channel(1);
+        let (back, returned) = sync_channel(1);
+        shard
+            .sender
+            .send(Work::Lend(lend, returned))
+            .map_err(|_| anyhow!("shard worker stopped unexpectedly"))?;
+        Ok((lent, back))
+    }
+
+    /// Applies `transaction` across the ledgers of shard `index` and of the receiving shard `counterpart`.
+    fn apply_across(
+        &mut self,
+        index: usize,
+        counterpart: usize,
+        sequence: u64,
+        transaction: Transaction,
+    ) -> Result<()> {
+        let State::Running(workers) = &mut self.state else {
+            unreachable!("only called while running");
+        };
+        let (lent, back) = Self::lend(&mut workers[index])?;
+        let (receiver_lent, receiver_back) = Self::lend(&mut workers[counterpart])?;
+        let stopped = |_| anyhow!("shard worker stopped unexpectedly");
+        let mut accountant = lent.recv().map_err(stopped)?;
+        let mut receiver = receiver_lent.recv().map_err(stopped)?;
+
+        let (tx, is_transfer) = (
+            *transaction.tx(),
+            *transaction.type_() == TransactionType::Transfer,
+        );
+        let before = accountant.rejections().len();
+        accountant.apply_with(Some(&mut receiver), transaction)?;
+        let rejected = accountant.rejections().len() > before;
+        if is_transfer && !rejected {
+            self.spanning.insert(tx, counterpart);
+        }
+
+        let stopped = |_| anyhow!("shard worker stopped unexpectedly");
+        back.send((accountant, rejected.then_some(sequence)))
+            .map_err(stopped)?;
+        receiver_back.send((receiver, None)).map_err(stopped)
+    }
+}
+
+impl Accounting for ShardedAccountant {
+    fn apply_bookkeeping(&mut self, transaction: Transaction) -> Result<()> {
+        let shards = match &self.state {
+            State::Running(shards) => shards.len(),
+            State::Finished => Err(anyhow!("sharded accountant is already finished"))?,
+        };
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.transaction_historical_order.push(*transaction.tx());
+
+        let Some(index) = self.route(&transaction, shards) else {
+            let reason = LedgerError::TxDuplicated(*transaction.tx());
+            self.transactions_rejected
+                .push((sequence, Rejection::new(&transaction, reason)));
+            return Ok(());
+        };
+
+        if let Some(counterpart) = self.counterpart(&transaction, index, shards) {
+            return self.apply_across(index, counterpart, sequence, transaction);
+        }
+        let State::Running(workers) = &mut self.state else {
+            unreachable!("checked above");
+        };
+        let shard = &mut workers[index];
+        shard.pending.push((sequence, transaction));
+        if shard.pending.len() >= BATCH_SIZE {
+            Self::send(shard)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let workers = match &mut self.state {
+            State::Running(workers) => mem::take(workers),
+            State::Finished => return Ok(()),
+        };
+
+        let mut handles = Vec::with_capacity(workers.len());
+        for mut shard in workers {
+            if !shard.pending.is_empty() {
+                Self::send(&mut shard)?;
+            }
+            drop(shard.sender);
+            handles.push(shard.handle);
+        }
+
+        let mut parts = Vec::with_capacity(handles.len());
+        let mut fee_postings = Vec::new();
+        for handle in handles {
+            let (mut part, rejected, charged) = handle
+                .join()
+                .map_err(|_| anyhow!("shard worker panicked"))??;
+            self.transactions_rejected
+                .extend(rejected.into_iter().zip(part.take_rejections()));
+            fee_postings.extend(charged.into_iter().zip(part.take_fee_postings()));
+            parts.push(part);
+        }
+        self.transactions_rejected
+            .sort_by_key(|(sequence, _)| *sequence);
+        fee_postings.sort_by_key(|(sequence, _)| *sequence);
+
+        self.base.merge(
+            parts,
+            mem::take(&mut self.transaction_historical_order),
+            mem::take(&mut self.transactions_rejected)
+                .into_iter()
+                .map(|(_, rejection)| rejection)
+                .collect(),
+            fee_postings
+                .into_iter()
+                .map(|(_, posting)| posting)
+                .collect(),
+        )?;
+        self.state = State::Finished;
+        Ok(())
+    }
+
+    fn precision(&self) -> Precision {
+        self.base.precision()
+    }
+}
+
+impl CSVExport for ShardedAccountant {
+    fn export(&self, writer: RefCell<&mut impl RecordWrite>) -> Result<()> {
+        match &self.state {
+            State::Finished => self.base.export(writer),
+            State::Running(_) => Err(anyhow!("shards are still running, call finish first")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fees::{FeeRule, FeeSchedule},
+        ledger::ExportOrder,
+        models::TransactionType,
+        precision::{ExcessPrecision, Precision},
+        reader::ReaderConfig,
+    };
+    use std::fs::File;
+
+    struct MockWriter {
+        records: Vec<String>,
+    }
+
+    impl RecordWrite for MockWriter {
+        fn write_record<T: serde::Serialize>(&mut self, record: &T) -> Result<()> {
+            self.records.push(serde_json::to_string(record)?);
+            Ok(())
+        }
+    }
+
+    fn read(input: &str) -> Result<Vec<Transaction>> {
+        let mut reader = ReaderConfig::default().build(Box::new(File::open(input)?));
+        let mut transactions = Vec::new();
+        for (line, transaction) in reader.deserialize::<Transaction>().enumerate() {
+            transactions.push(transaction?.with_line(line as u64 + 2));
+        }
+        Ok(transactions)
+    }
+
+    fn inline(rows: &[&str]) -> Result<Vec<Transaction>> {
+        let input = format!("type,client,tx,amount\n{}\n", rows.join("\n"));
+        let mut reader = ReaderConfig::default().build(Box::new(std::io::Cursor::new(input)));
+        Ok(reader
+            .deserialize::<Transaction>()
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn export(accountant: &impl CSVExport) -> Result<Vec<String>> {
+        let mut writer = MockWriter {
+            records: Vec::new(),
+        };
+        accountant.export(RefCell::new(&mut writer))?;
+        Ok(writer.records)
+    }
+
+    fn assert_same_as_sequential(transactions: Vec<Transaction>, shards: usize) -> Result<()> {
+        assert_same_as_sequential_with(Accountant::new, transactions, shards)
+    }
+
+    fn assert_same_as_sequential_with(
+        base: impl Fn() -> Accountant,
+        transactions: Vec<Transaction>,
+        shards: usize,
+    ) -> Result<()> {
+        let ledger = || {
+            base()
+                .with_precision(Precision::default().with_excess(ExcessPrecision::Round))
+                .with_export_order(ExportOrder::Total)
+        };
+        let mut sequential = ledger();
+        let mut sharded = ShardedAccountant::new(ledger(), shards)?;
+        for transaction in transactions {
+            sequential.apply_bookkeeping(transaction.clone())?;
+            sharded.apply_bookkeeping(transaction)?;
+        }
+        sharded.finish()?;
+
+        assert_eq!(export(&sharded)?, export(&sequential)?);
+        let merged = sharded.into_accountant()?;
+        assert_eq!(merged.rejections(), sequential.rejections());
+        assert_eq!(merged.residuals(), sequential.residuals());
+        assert_eq!(merged.fee_postings(), sequential.fee_postings());
+        Ok(())
+    }
+
+    #[test]
+    fn test_artefacts_match_sequential_processing() -> Result<()> {
+        for file in [
+            "test_transactions_small.csv",
+            "test_transactions_large.csv",
+            "test_transactions_with_all_types.csv",
+        ] {
+            let path = format!("{}/artefacts/{file}", env!("CARGO_MANIFEST_DIR"));
+            for shards in [1, 2, 3, 8] {
+                assert_same_as_sequential(read(&path)?, shards)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_client_references_match_sequential_processing() -> Result<()> {
+        let rows = [
+            "deposit,1,1,100.0",
+            "deposit,2,2,50.0",
+            "dispute,2,1,",
+            "deposit,3,1,10.0",
+            "dispute,1,1,",
+            "chargeback,4,1,",
+            "chargeback,1,1,",
+            "deposit,1,3,1.0",
+            "withdrawal,2,4,20.0",
+            "resolve,2,9,",
+        ];
+        assert_same_as_sequential(inline(&rows)?, 2)?;
+        assert_same_as_sequential(inline(&rows)?, 4)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfers_and_currencies_across_shards_match_sequential_processing() -> Result<()> {
+        let input = "type,client,tx,amount,to,currency
+deposit,1,1,100.0,
+deposit,2,2,50.0,
+transfer,1,3,30.0,2
+transfer,2,4,500.0,3
+transfer,2,4,5.0,3
+transfer,1,3,1.0,4
+dispute,1,3,,
+withdrawal,2,5,70.0,
+resolve,1,3,,
+dispute,2,4,,
+chargeback,2,4,,
+transfer,1,6,1.0,3
+transfer,4,7,1.0,1
+deposit,3,8,1.0,
+transfer,5,9,1.0,5
+deposit,1,20,5.0,,EUR
+transfer,1,21,2.0,2,EUR
+transfer,2,22,1.0,1,GBP
+dispute,1,21,,,GBP
+dispute,1,21,,,
+chargeback,1,21,,,eur
+deposit,6,23,1.00005,,EUR
+transfer,6,24,0.00015,1,EUR
+deposit,7,25,2.00005,
+";
+        let mut reader = ReaderConfig::default().build(Box::new(std::io::Cursor::new(input)));
+        let transactions = reader
+            .deserialize::<Transaction>()
+            .collect::<Result<Vec<_>, _>>()?;
+        for shards in [1, 2, 3, 5] {
+            assert_same_as_sequential(transactions.clone(), shards)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fees_across_shards_match_sequential_processing() -> Result<()> {
+        let fees = FeeSchedule::new()
+            .with_house(9)
+            .with_rule(FeeRule::new(TransactionType::Deposit).with_percent(1.into()))?
+            .with_rule(FeeRule::new(TransactionType::Withdrawal).with_flat(1.into()))?
+            .with_rule(
+                FeeRule::new(TransactionType::Withdrawal)
+                    .with_client(2)
+                    .with_from(10.into())
+                    .with_flat(3.into()),
+            )?;
+        let rows = [
+            "deposit,1,1,100.0",
+            "deposit,2,2,50.0",
+            "withdrawal,1,3,99.0",
+            "withdrawal,2,4,20.0",
+            "withdrawal,2,5,5.0",
+            "deposit,9,6,5.0",
+            "dispute,1,1,",
+            "deposit,3,7,0.00001",
+            "deposit,4,8,12.345",
+            "deposit,5,10,50.0",
+            "dispute,5,10,",
+            "chargeback,5,10,",
+        ];
+        for shards in [1, 2, 3, 5] {
+            assert_same_as_sequential_with(
+                || Accountant::new().with_fees(fees.clone()),
+                inline(&rows)?,
+                shards,
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_continues_from_restored_state() -> Result<()> {
+        let mut base = Accountant::new();
+        for transaction in inline(&["deposit,1,1,100.0", "deposit,2,2,50.0"])? {
+            base.apply_bookkeeping(transaction)?;
+        }
+        let mut snapshot = Vec::new();
+        base.snapshot(&mut snapshot)?;
+
+        let mut sharded = ShardedAccountant::new(Accountant::restore(snapshot.as_slice())?, 3)?;
+        for transaction in inline(&["dispute,1,1,", "dispute,3,2,", "deposit,2,1,5.0"])? {
+            sharded.apply_bookkeeping(transaction)?;
+        }
+        let merged = sharded.into_accountant()?;
+
+        assert_eq!(merged.client(1).map(|c| *c.held()), Some(100.into()));
+        assert_eq!(merged.client(3).map(|c| *c.total()), Some(0.into()));
+        let reasons: Vec<&LedgerError> = merged.rejections().iter().map(|r| r.reason()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                &LedgerError::ClientMismatch {
+                    tx: 2,
+                    expected: 2,
+                    got: 3
+                },
+                &LedgerError::TxDuplicated(1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restored_transfers_across_shards_match_sequential_processing() -> Result<()> {
+        let input = "type,client,tx,amount,to
+deposit,1,1,10.0,
+transfer,1,2,4.0,2
+transfer,1,3,1.0,3
+";
+        let mut reader = ReaderConfig::default().build(Box::new(std::io::Cursor::new(input)));
+        let mut base = Accountant::new();
+        for transaction in reader.deserialize::<Transaction>() {
+            base.apply_bookkeeping(transaction?)?;
+        }
+        let mut snapshot = Vec::new();
+        base.snapshot(&mut snapshot)?;
+
+        let rows = [
+            "dispute,1,2,",
+            "resolve,1,2,",
+            "dispute,1,3,",
+            "chargeback,1,3,",
+        ];
+        for shards in [2, 3] {
+            assert_same_as_sequential_with(
+                || Accountant::restore(snapshot.as_slice()).expect("snapshot restores"),
+                inline(&rows)?,
+                shards,
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_before_finish_fails() -> Result<()> {
+        let sharded = ShardedAccountant::new(Accountant::new(), 2)?;
+        assert!(export(&sharded).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+//! Precision and rounding policy of monetary amounts.
+//!
+//! Every amount entering the ledger is checked against the policy scale: amounts with more decimal places
+//! are refused or rounded with the policy rounding mode, and what rounding takes away is kept per currency
+//! as a residual so exported balances can be reconciled with the input.
+
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use getset::Getters;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Serialize;
+
+use crate::{
+    errors::AmountError,
+    models::{Currency, DEFAULT_MAX_SCALE},
+};
+
+/// Largest number of decimal places a `Decimal`, and so a precision policy, can keep.
+pub const MAX_SCALE: u32 = 28;
+
+/// Name of the newtype struct balance amounts are serialized as, formatted with every decimal place they carry.
+///
+/// Text formats see through it to the formatted string, the Parquet writer stores it as a decimal.
+pub(crate) const FIXED_POINT: &str = "play_with_csv::FixedPoint";
+
+/// Rounding is how an amount is brought down to the policy scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Half to even, so ties are rounded up and down equally often.
+    #[default]
+    Bankers,
+    /// Half away from zero.
+    HalfUp,
+    /// Drops the extra decimal places.
+    Truncate,
+}
+
+impl FromStr for Rounding {
+    type Err = anyhow::Error;
+
+    /// Parses `bankers`, `half-up` or `truncate`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bankers" => Ok(Self::Bankers),
+            "half-up" => Ok(Self::HalfUp),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(anyhow!(
+                "unknown rounding mode {s:?}, expected bankers, half-up or truncate"
+            )),
+        }
+    }
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Self::Bankers => RoundingStrategy::MidpointNearestEven,
+            Self::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Self::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+/// ExcessPrecision decides what happens to amounts with more decimal places than the policy scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcessPrecision {
+    /// Refuses the amount.
+    #[default]
+    Reject,
+    /// Rounds the amount to the scale and keeps the difference as a residual.
+    Round,
+}
+
+impl FromStr for ExcessPrecision {
+    type Err = anyhow::Error;
+
+    /// Parses `reject` or `round`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "round" => Ok(Self::Round),
+            _ => Err(anyhow!(
+                "unknown excess precision policy {s:?}, expected reject or round"
+            )),
+        }
+    }
+}
+
+/// Precision is the number of decimal places monetary amounts are kept with and how they get there.
+///
+/// The default keeps four decimal places and refuses anything more precise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Getters)]
+pub struct Precision {
+    /// Number of decimal places of amounts and balances.
+    #[getset(get = "pub")]
+    scale: u32,
+    /// How amounts are rounded to the scale.
+    #[getset(get = "pub")]
+    rounding: Rounding,
+    /// What happens to amounts with more decimal places than the scale.
+    #[getset(get = "pub")]
+    excess: ExcessPrecision,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            scale: DEFAULT_MAX_SCALE,
+            rounding: Rounding::default(),
+            excess: ExcessPrecision::default(),
+        }
+    }
+}
+
+impl Precision {
+    /// Sets the number of decimal places of amounts and balances.
+    ///
+    /// # Returns
+    /// The policy, or an error if `scale` is above `MAX_SCALE`.
+    pub fn with_scale(mut self, scale: u32) -> Result<Self> {
+        if scale > MAX_SCALE {
+            Err(anyhow!(
+                "scale {scale} is above the {MAX_SCALE} decimal places an amount can keep"
+            ))?
+        }
+        self.scale = scale;
+        Ok(self)
+    }
+
+    /// Sets how amounts are rounded to the scale.
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Sets what happens to amounts with more decimal places than the scale.
+    pub fn with_excess(mut self, excess: ExcessPrecision) -> Self {
+        self.excess = excess;
+        self
+    }
+
+    /// Rounds `amount` to the scale with the rounding mode, padding it to `scale` decimal places as far as
+    /// its 96-bit mantissa allows; `apply` refuses the amounts it cannot pad.
+    pub fn round(&self, amount: Decimal) -> Decimal {
+        let mut rounded = amount.round_dp_with_strategy(self.scale, self.rounding.strategy());
+        rounded.rescale(self.scale);
+        rounded
+    }
+
+    /// Brings `amount` to the scale under the policy.
+    ///
+    /// # Arguments
+    /// * `amount` - The amount as received.
+    ///
+    /// # Returns
+    /// The amount with exactly `scale` decimal places, `AmountError::TooLarge` if it is too large to have
+    /// that many, or `AmountError::TooPrecise` if it has more and the policy refuses them.
+    pub fn apply(&self, amount: Decimal) -> Result<Decimal, AmountError> {
+        let rounded = self.round(amount);
+        if rounded.scale() < self.scale {
+            return Err(AmountError::TooLarge {
+                amount,
+                max_scale: self.scale,
+            });
+        }
+        if rounded != amount && self.excess == ExcessPrecision::Reject {
+            return Err(AmountError::TooPrecise {
+                amount,
+                max_scale: self.scale,
+            });
+        }
+        Ok(rounded)
+    }
+}
+
+/// What rounding took away from the accepted amounts of one currency.
+#[derive(Debug, Clone, PartialEq, Serialize, Getters)]
+pub struct Residual {
+    /// The currency, `None` for transactions without one.
+    #[getset(get = "pub")]
+    currency: Option<Currency>,
+    /// Number of amounts that were rounded.
+    #[getset(get = "pub")]
+    rounded: u64,
+    /// Sum of the received amounts minus the sum of the rounded ones, negative when rounding added more than it took.
+    #[getset(get = "pub")]
+    residual: Decimal,
+}
+
+impl Residual {
+    /// Creates the empty residual of `currency`.
+    pub(crate) fn new(currency: Option<Currency>) -> Self {
+        Self::from_parts(currency, 0, Decimal::ZERO)
+    }
+
+    /// Rebuilds a residual from previously stored parts, used when restoring a snapshot.
+    pub(crate) fn from_parts(currency: Option<Currency>, rounded: u64, residual: Decimal) -> Self {
+        Self {
+            currency,
+            rounded,
+            residual,
+        }
+    }
+
+    /// Records that `amount` was accepted as `rounded`.
+    pub(crate) fn add(&mut self, amount: Decimal, rounded: Decimal) {
+        if amount != rounded {
+            self.rounded += 1;
+            self.residual += amount - rounded;
+        }
+    }
+
+    /// Adds the counts of `other`, a residual of the same currency.
+    pub(crate) fn merge(&mut self, other: &Residual) {
+        self.rounded += other.rounded;
+        self.residual += other.residual;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::prelude::*;
+
+    #[test]
+    fn test_rounding_modes() -> Result<()> {
+        let precision = Precision::default().with_scale(2)?;
+        let cases = [
+            (dec!(1.005), dec!(1.00), dec!(1.01), dec!(1.00)),
+            (dec!(1.015), dec!(1.02), dec!(1.02), dec!(1.01)),
+            (dec!(1.019), dec!(1.02), dec!(1.02), dec!(1.01)),
+            (dec!(1.5), dec!(1.50), dec!(1.50), dec!(1.50)),
+        ];
+        for (amount, bankers, half_up, truncate) in cases {
+            assert_eq!(precision.round(amount), bankers);
+            assert_eq!(
+                precision.with_rounding(Rounding::HalfUp).round(amount),
+                half_up
+            );
+            assert_eq!(
+                precision.with_rounding(Rounding::Truncate).round(amount),
+                truncate
+            );
+        }
+        assert_eq!(precision.round(dec!(1.5)).to_string(), "1.50");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale_is_bounded() -> Result<()> {
+        assert_eq!(*Precision::default().with_scale(MAX_SCALE)?.scale(), 28);
+        assert!(Precision::default().with_scale(MAX_SCALE + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_amounts_too_large_for_the_scale_are_refused() -> Result<()> {
+        let precision = Precision::default().with_scale(MAX_SCALE)?;
+        assert_eq!(precision.apply(dec!(7.5))?.scale(), MAX_SCALE);
+        assert_eq!(
+            precision.apply(dec!(1000)),
+            Err(AmountError::TooLarge {
+                amount: dec!(1000),
+                max_scale: MAX_SCALE
+            })
+        );
+        assert_eq!(
+            Precision::default().apply(Decimal::MAX),
+            Err(AmountError::TooLarge {
+                amount: Decimal::MAX,
+                max_scale: 4
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_excess_precision_is_rejected_or_rounded() -> Result<()> {
+        let precision = Precision::default();
+        assert_eq!(precision.apply(dec!(1.2345))?.to_string(), "1.2345");
+        assert_eq!(precision.apply(dec!(1.50000))?.to_string(), "1.5000");
+        assert_eq!(
+            precision.apply(dec!(1.23456)),
+            Err(AmountError::TooPrecise {
+                amount: dec!(1.23456),
+                max_scale: 4
+            })
+        );
+
+        let rounding = precision.with_excess(ExcessPrecision::Round);
+        assert_eq!(rounding.apply(dec!(1.23456))?, dec!(1.2346));
+        assert_eq!(rounding.apply(dec!(0.00001))?, Decimal::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_residual_counts_rounded_amounts() {
+        let mut residual = Residual::new(None);
+        residual.add(dec!(1.25), dec!(1.2));
+        residual.add(dec!(1.35), dec!(1.4));
+        residual.add(dec!(2), dec!(2.00));
+        assert_eq!(*residual.rounded(), 2);
+        assert_eq!(*residual.residual(), dec!(0.00));
+
+        let mut other = Residual::new(None);
+        other.add(dec!(0.019), dec!(0.01));
+        residual.merge(&other);
+        assert_eq!(*residual.rounded(), 3);
+        assert_eq!(*residual.residual(), dec!(0.009));
+    }
+
+    #[test]
+    fn test_policies_parse() -> Result<()> {
+        assert_eq!("half-up".parse::<Rounding>()?, Rounding::HalfUp);
+        assert_eq!("round".parse::<ExcessPrecision>()?, ExcessPrecision::Round);
+        assert!("ceiling".parse::<Rounding>().is_err());
+        assert!("ignore".parse::<ExcessPrecision>().is_err());
+        Ok(())
+    }
+}
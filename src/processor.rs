@@ -1,41 +1,440 @@
-use std::{cell::RefCell, io::Read};
+use std::{cell::RefCell, str::FromStr, sync::LazyLock};
 
 use crate::{
+    errors::InputError,
     models::Transaction,
-    traits::{Accounting, CSVExport, CSVWrite},
+    precision::Precision,
+    traits::{Accounting, CSVExport, RecordRead, RecordWrite},
 };
-use anyhow::Result;
-use csv::Reader;
+use anyhow::{Result, anyhow};
+use csv::{Position, StringRecord};
+use getset::Getters;
 
+/// ErrorPolicy decides what happens to rows that cannot be parsed into a valid transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stops on the first malformed row.
+    #[default]
+    FailFast,
+    /// Skips every malformed row and keeps it in the report.
+    Skip,
+    /// Skips malformed rows until more than the given number were seen, then stops.
+    SkipUpTo(usize),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `fail-fast`, `skip` or `skip-up-to:<n>`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail-fast" => Ok(Self::FailFast),
+            "skip" => Ok(Self::Skip),
+            _ => match s.strip_prefix("skip-up-to:") {
+                Some(limit) => Ok(Self::SkipUpTo(limit.parse()?)),
+                None => Err(anyhow!(
+                    "unknown error policy {s:?}, expected fail-fast, skip or skip-up-to:<n>"
+                )),
+            },
+        }
+    }
+}
+
+/// A row skipped during ingestion.
+#[derive(Debug, Getters)]
+pub struct MalformedRow {
+    /// The input line of the row.
+    #[getset(get = "pub")]
+    line: u64,
+    /// The input byte offset of the row.
+    #[getset(get = "pub")]
+    byte: u64,
+    /// The row fields joined by commas, or the JSON line, empty if the row could not be read at all.
+    #[getset(get = "pub")]
+    raw: String,
+    /// Why the row was skipped.
+    #[getset(get = "pub")]
+    error: InputError,
+}
+
+/// Summary of a single `Engine::run`.
+#[derive(Debug, Default, Getters)]
+pub struct IngestReport {
+    /// Number of rows read, malformed rows that could not be read at all are not counted.
+    #[getset(get = "pub")]
+    pub(crate) rows_read: u64,
+    /// Rows skipped under the error policy.
+    #[getset(get = "pub")]
+    malformed: Vec<MalformedRow>,
+}
+
+impl MalformedRow {
+    pub(crate) fn new(position: &Position, raw: String, error: InputError) -> Self {
+        Self::at(position.line(), position.byte(), raw, error)
+    }
+
+    pub(crate) fn at(line: u64, byte: u64, raw: String, error: InputError) -> Self {
+        Self {
+            line,
+            byte,
+            raw,
+            error,
+        }
+    }
+
+    pub(crate) fn into_error(self) -> InputError {
+        self.error
+    }
+}
+
+impl IngestReport {
+    /// Keeps `row` in the report if `error_policy` allows skipping it.
+    ///
+    /// # Returns
+    /// The row error, or `InputError::TooManyMalformed`, when the row cannot be skipped.
+    pub(crate) fn skip(
+        &mut self,
+        error_policy: ErrorPolicy,
+        row: MalformedRow,
+    ) -> Result<(), InputError> {
+        match error_policy {
+            ErrorPolicy::FailFast => return Err(row.error),
+            ErrorPolicy::SkipUpTo(limit) if self.malformed.len() >= limit => {
+                return Err(InputError::TooManyMalformed {
+                    limit,
+                    line: row.line,
+                });
+            }
+            ErrorPolicy::Skip | ErrorPolicy::SkipUpTo(_) => (),
+        }
+        self.malformed.push(row);
+        Ok(())
+    }
+}
+
+/// The column order of CSV input without a header row.
+static DEFAULT_HEADERS: LazyLock<StringRecord> =
+    LazyLock::new(|| StringRecord::from(vec!["type", "client", "tx", "amount", "to", "currency"]));
+
+/// Deserializes a CSV record into a transaction and validates its amount.
+///
+/// # Arguments
+/// * `record` - The record, with its input position.
+/// * `headers` - The header row, if the input has one; otherwise columns are `type,client,tx,amount`.
+/// * `precision` - The precision policy amounts are validated against.
+///
+/// # Returns
+/// The transaction tagged with its input line.
+pub(crate) fn parse_record(
+    record: &StringRecord,
+    headers: Option<&StringRecord>,
+    precision: Precision,
+) -> Result<Transaction, InputError> {
+    let line = record.position().map_or(0, |position| position.line());
+    let tx = record
+        .deserialize::<Transaction>(Some(headers.unwrap_or(&DEFAULT_HEADERS)))
+        .map_err(|source| InputError::Malformed { line, source })?
+        .with_line(line);
+    tx.validate(precision)
+        .map_err(|source| InputError::InvalidAmount { line, source })?;
+    Ok(tx)
+}
+
+/// Engine drives the data flow: it reads transactions, hands them to the accountant and exports the result.
 pub struct Engine<T, S> {
     writer: T,
-    reader: Reader<Box<dyn Read>>,
+    reader: Box<dyn RecordRead>,
     accountant: S,
+    error_policy: ErrorPolicy,
+    resume_offset: u64,
 }
 
 impl<T, I> Engine<T, I>
 where
-    T: CSVWrite,
+    T: RecordWrite,
     I: CSVExport + Accounting,
 {
-    pub fn new(writer: T, reader: Reader<Box<dyn Read>>, accountant: I) -> Self {
+    /// Creates an engine reading from `reader`, applying to `accountant` and exporting to `writer`.
+    pub fn new(writer: T, reader: impl RecordRead + 'static, accountant: I) -> Self {
         Self {
             writer,
-            reader,
+            reader: Box::new(reader),
             accountant,
+            error_policy: ErrorPolicy::default(),
+            resume_offset: 0,
         }
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        for rec in self.reader.deserialize::<Transaction>() {
-            let tx: Transaction = rec?;
-            self.accountant.apply_bookkeeping(tx)?;
+    /// Sets the policy applied to rows that cannot be parsed.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Skips every input record ending at or before `offset`, used to resume after a crash.
+    pub fn with_resume_offset(mut self, offset: u64) -> Self {
+        self.resume_offset = offset;
+        self
+    }
+
+    /// Returns the accountant.
+    pub fn accountant(&self) -> &I {
+        &self.accountant
+    }
+
+    /// Consumes the engine and returns the accountant.
+    pub fn into_accountant(self) -> I {
+        self.accountant
+    }
+
+    /// Replaces the input, so the next `ingest` applies another source to the same ledger.
+    /// The resume offset only applies to the first input and is cleared.
+    pub fn set_reader(&mut self, reader: impl RecordRead + 'static) {
+        self.reader = Box::new(reader);
+        self.resume_offset = 0;
+    }
+
+    /// Reads every input row, applies the valid ones and exports the balances.
+    ///
+    /// # Returns
+    /// The ingestion report, or an error if a malformed row is not allowed by the error policy.
+    pub fn run(&mut self) -> Result<IngestReport> {
+        let report = self.ingest()?;
+        self.export()?;
+        Ok(report)
+    }
+
+    /// Reads every row of the current input and applies the valid ones, without exporting.
+    ///
+    /// The error policy applies to each input on its own.
+    ///
+    /// # Returns
+    /// The ingestion report of this input, or an error if a malformed row is not allowed by the error policy.
+    pub fn ingest(&mut self) -> Result<IngestReport> {
+        let mut report = IngestReport::default();
+
+        let precision = self.accountant.precision();
+        while let Some(read) = self.reader.read_transaction(precision)? {
+            match read {
+                Ok(tx) if tx.offset().is_some_and(|end| end <= self.resume_offset) => (),
+                Err(row) if *row.byte() < self.resume_offset => (),
+                Ok(tx) => {
+                    report.rows_read += 1;
+                    self.accountant.apply_bookkeeping(tx)?;
+                }
+                Err(row) => {
+                    if !row.raw().is_empty() {
+                        report.rows_read += 1;
+                    }
+                    report.skip(self.error_policy, row)?;
+                }
+            }
         }
 
+        Ok(report)
+    }
+
+    /// Completes the bookkeeping and exports the balances, called once after the last input.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub fn export(&mut self) -> Result<()> {
+        self.accountant.finish()?;
+
         let writer = RefCell::new(&mut self.writer);
 
         self.accountant.export(writer)?;
+        self.writer.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ledger::Accountant, precision::ExcessPrecision};
+    use csv::ReaderBuilder;
+    use rust_decimal::prelude::*;
+    use std::io::{Cursor, Read};
+
+    struct MockWriter {
+        records: Vec<String>,
+    }
+
+    impl RecordWrite for MockWriter {
+        fn write_record<T: serde::Serialize>(&mut self, record: &T) -> Result<()> {
+            self.records.push(serde_json::to_string(record)?);
+            Ok(())
+        }
+    }
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,x,2,5.0
+payout,1,3,1.0
+deposit,1,4,-2.0
+deposit,1,5,2.5
+";
+
+    fn engine(input: &'static str) -> Engine<MockWriter, Accountant> {
+        engine_with(input, Accountant::new())
+    }
+
+    fn engine_with(input: &'static str, accountant: Accountant) -> Engine<MockWriter, Accountant> {
+        let reader: Box<dyn Read> = Box::new(Cursor::new(input));
+        Engine::new(
+            MockWriter {
+                records: Vec::new(),
+            },
+            ReaderBuilder::new().from_reader(reader),
+            accountant,
+        )
+    }
+
+    #[test]
+    fn test_error_policy_from_str() -> Result<()> {
+        assert_eq!("fail-fast".parse::<ErrorPolicy>()?, ErrorPolicy::FailFast);
+        assert_eq!("skip".parse::<ErrorPolicy>()?, ErrorPolicy::Skip);
+        assert_eq!(
+            "skip-up-to:3".parse::<ErrorPolicy>()?,
+            ErrorPolicy::SkipUpTo(3)
+        );
+        assert!("skip-up-to:".parse::<ErrorPolicy>().is_err());
+        assert!("ignore".parse::<ErrorPolicy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fail_fast_stops_on_first_malformed_row() {
+        let err = engine(INPUT).run().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<InputError>(),
+            Some(InputError::Malformed { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_skip_collects_malformed_rows() -> Result<()> {
+        let mut engine = engine(INPUT).with_error_policy(ErrorPolicy::Skip);
+        let report = engine.run()?;
+
+        assert_eq!(*report.rows_read(), 5);
+        let lines: Vec<u64> = report.malformed().iter().map(|row| *row.line()).collect();
+        assert_eq!(lines, vec![3, 4, 5]);
+
+        let first = &report.malformed()[0];
+        assert_eq!(*first.byte(), 39);
+        assert_eq!(first.raw(), "deposit,x,2,5.0");
+        assert!(matches!(
+            report.malformed()[2].error(),
+            InputError::InvalidAmount { line: 5, .. }
+        ));
+
+        assert_eq!(
+            engine.writer.records,
+            vec![
+                r#"{"client":1,"available":"12.5000","held":"0.0000","total":"12.5000","locked":false}"#
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_up_to_limit() -> Result<()> {
+        let report = engine(INPUT)
+            .with_error_policy(ErrorPolicy::SkipUpTo(3))
+            .run()?;
+        assert_eq!(report.malformed().len(), 3);
+
+        let err = engine(INPUT)
+            .with_error_policy(ErrorPolicy::SkipUpTo(2))
+            .run()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<InputError>(),
+            Some(InputError::TooManyMalformed { limit: 2, line: 5 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_unequal_row_length() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1\ndeposit,1,2,1.0\n";
+        let report = engine(input).with_error_policy(ErrorPolicy::Skip).run()?;
+
+        assert_eq!(*report.rows_read(), 1);
+        assert_eq!(report.malformed().len(), 1);
+        assert_eq!(*report.malformed()[0].line(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_precision_is_configurable() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.00001\n";
+        assert!(engine(input).run().is_err());
+
+        let accountant = Accountant::new().with_precision(Precision::default().with_scale(5)?);
+        let report = engine_with(input, accountant).run()?;
+        assert!(report.malformed().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_excess_precision_is_rounded_by_the_ledger() -> Result<()> {
+        let precision = Precision::default()
+            .with_scale(2)?
+            .with_excess(ExcessPrecision::Round);
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,1.005\ndeposit,1,2,2.5\ndeposit,1,3,0.019\n";
+        let mut engine = engine_with(input, Accountant::new().with_precision(precision));
+        engine.run()?;
+
+        assert_eq!(
+            engine.writer.records,
+            vec![r#"{"client":1,"available":"3.52","held":"0.00","total":"3.52","locked":false}"#]
+        );
+        let residuals = engine.accountant().residuals();
+        assert_eq!(*residuals[0].rounded(), 2);
+        assert_eq!(*residuals[0].residual(), dec!(0.004));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inputs_share_one_ledger() -> Result<()> {
+        let mut engine = engine("type,client,tx,amount\ndeposit,1,1,10.0\n");
+        assert_eq!(*engine.ingest()?.rows_read(), 1);
+
+        let second: Box<dyn Read> = Box::new(Cursor::new(
+            "type,client,tx,amount\ndeposit,1,1,3.0\nwithdrawal,1,2,4.0\n",
+        ));
+        engine.set_reader(ReaderBuilder::new().from_reader(second));
+        assert_eq!(*engine.ingest()?.rows_read(), 2);
+        engine.export()?;
+
+        assert_eq!(engine.accountant().rejections().len(), 1);
+        assert_eq!(
+            engine.writer.records,
+            vec![
+                r#"{"client":1,"available":"6.0000","held":"0.0000","total":"6.0000","locked":false}"#
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_offset_skips_consumed_records() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n";
+        let mut engine = engine(input).with_resume_offset(39);
+        let report = engine.run()?;
 
+        assert_eq!(*report.rows_read(), 1);
+        assert_eq!(
+            engine.writer.records,
+            vec![
+                r#"{"client":1,"available":"5.0000","held":"0.0000","total":"5.0000","locked":false}"#
+            ]
+        );
         Ok(())
     }
 }
@@ -0,0 +1,510 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    str::FromStr,
+};
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+use getset::Getters;
+use rust_decimal::Decimal;
+use serde::{Deserialize, de::Error as _};
+
+use crate::{
+    columnar::ParquetReader,
+    compression::decompress,
+    errors::InputError,
+    models::{Currency, Transaction, TransactionType},
+    precision::Precision,
+    processor::{MalformedRow, parse_record},
+    traits::RecordRead,
+};
+
+/// InputFormat is the encoding of transaction input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// CSV rows with a `type,client,tx,amount` header, plus optional `to` and `currency` columns.
+    #[default]
+    Csv,
+    /// One JSON object per line with `type`, `client`, `tx`, `amount` and optional `to` and `currency` fields.
+    Jsonl,
+    /// An Apache Parquet file with `type`, `client`, `tx` and `amount` columns.
+    ///
+    /// Parquet is not line oriented, so it is only read whole through `ReaderConfig::open` or
+    /// `ParquetReader`, never streamed.
+    Parquet,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `csv`, `jsonl` or `parquet`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(anyhow!(
+                "unknown input format {s:?}, expected csv, jsonl or parquet"
+            )),
+        }
+    }
+}
+
+/// ReaderConfig describes how transaction input is read.
+///
+/// The default configuration reads tolerant CSV: headers are expected, whitespace around fields is trimmed,
+/// rows may have fewer or more columns than the header (so dispute rows may omit the trailing amount)
+/// and fields are separated by a comma. The CSV settings are ignored for JSON Lines and Parquet input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Getters)]
+pub struct ReaderConfig {
+    /// The input format.
+    #[getset(get = "pub")]
+    format: InputFormat,
+    /// Whether the first row is a header row; without one, columns are `type,client,tx,amount,to,currency`.
+    #[getset(get = "pub")]
+    has_headers: bool,
+    /// Whether whitespace around headers and fields is trimmed.
+    #[getset(get = "pub")]
+    trim: bool,
+    /// Whether rows may have a different number of columns than the header.
+    #[getset(get = "pub")]
+    flexible: bool,
+    /// The field delimiter.
+    #[getset(get = "pub")]
+    delimiter: u8,
+}
+
+impl Default for ReaderConfig {
+    fn default() -> Self {
+        Self {
+            format: InputFormat::default(),
+            has_headers: true,
+            trim: true,
+            flexible: true,
+            delimiter: b',',
+        }
+    }
+}
+
+impl ReaderConfig {
+    /// Sets the input format.
+    pub fn with_format(mut self, format: InputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets whether the first row is a header row.
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sets whether whitespace around headers and fields is trimmed.
+    pub fn with_trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Sets whether rows may have a different number of columns than the header.
+    pub fn with_flexible(mut self, flexible: bool) -> Self {
+        self.flexible = flexible;
+        self
+    }
+
+    /// Sets the field delimiter.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Builds a CSV reader over `input` using this configuration.
+    ///
+    /// # Arguments
+    /// * `input` - The source of the CSV data.
+    ///
+    /// # Returns
+    /// A configured CSV reader.
+    pub fn build(&self, input: Box<dyn Read>) -> Reader<Box<dyn Read>> {
+        self.build_from(input)
+    }
+
+    /// Builds a CSV reader over any `input` using this configuration.
+    pub(crate) fn build_from<R: Read>(&self, input: R) -> Reader<R> {
+        ReaderBuilder::new()
+            .has_headers(self.has_headers)
+            .trim(if self.trim { Trim::All } else { Trim::None })
+            .flexible(self.flexible)
+            .delimiter(self.delimiter)
+            .from_reader(input)
+    }
+
+    /// Builds a transaction reader over `input` for the configured format.
+    ///
+    /// gzip and zstd input is decompressed on the fly, recognized by its magic bytes.
+    /// Parquet input is read into memory first since its footer comes last; use `ParquetReader`
+    /// directly to read a file in place.
+    ///
+    /// # Arguments
+    /// * `input` - The source of the transactions.
+    ///
+    /// # Returns
+    /// A reader to hand to the `Engine`, or an error if Parquet input cannot be opened.
+    pub fn open(&self, input: Box<dyn Read>) -> Result<Box<dyn RecordRead>> {
+        let mut input = decompress(input)?;
+        Ok(match self.format {
+            InputFormat::Csv => Box::new(CsvRecords {
+                reader: self.build(input),
+                record: StringRecord::new(),
+            }),
+            InputFormat::Jsonl => Box::new(JsonLinesReader::new(BufReader::new(input))),
+            InputFormat::Parquet => {
+                let mut bytes = Vec::new();
+                input.read_to_end(&mut bytes)?;
+                Box::new(ParquetReader::new(Bytes::from(bytes))?)
+            }
+        })
+    }
+}
+
+/// Reads the next CSV record of `reader` into `record` and parses it as a transaction.
+fn read_csv<R: Read>(
+    reader: &mut Reader<R>,
+    record: &mut StringRecord,
+    precision: Precision,
+) -> Result<Option<Result<Transaction, MalformedRow>>> {
+    if reader.has_headers() {
+        reader.headers()?;
+    }
+    let start = reader.position().clone();
+
+    match reader.read_record(record) {
+        Ok(false) => Ok(None),
+        Ok(true) => {
+            let end = reader.position().byte();
+            let headers = match reader.has_headers() {
+                true => Some(reader.headers()?),
+                false => None,
+            };
+            Ok(Some(match parse_record(record, headers, precision) {
+                Ok(tx) => Ok(tx.with_offset(end)),
+                Err(error) => {
+                    let position = record.position().cloned().unwrap_or(start);
+                    let raw = record.iter().collect::<Vec<_>>().join(",");
+                    Err(MalformedRow::new(&position, raw, error))
+                }
+            }))
+        }
+        Err(err) if err.is_io_error() => Err(err)?,
+        Err(source) => {
+            let position = source.position().cloned().unwrap_or(start);
+            let error = InputError::Malformed {
+                line: position.line(),
+                source,
+            };
+            Ok(Some(Err(MalformedRow::new(
+                &position,
+                String::new(),
+                error,
+            ))))
+        }
+    }
+}
+
+impl<R: Read> RecordRead for Reader<R> {
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>> {
+        read_csv(self, &mut StringRecord::new(), precision)
+    }
+}
+
+/// A CSV reader reusing one record buffer for every row.
+struct CsvRecords<R> {
+    reader: Reader<R>,
+    record: StringRecord,
+}
+
+impl<R: Read> RecordRead for CsvRecords<R> {
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>> {
+        read_csv(&mut self.reader, &mut self.record, precision)
+    }
+}
+
+/// An amount given either as a JSON string or as a JSON number.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonAmount {
+    Text(String),
+    Number(serde_json::Number),
+}
+
+/// The JSON form of a transaction.
+#[derive(Deserialize)]
+struct JsonTransaction {
+    #[serde(rename = "type")]
+    type_: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(default)]
+    amount: Option<JsonAmount>,
+    #[serde(default)]
+    to: Option<u16>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+/// Deserializes a transaction from a JSON object.
+///
+/// Amounts may be JSON strings or numbers; an empty string or `null` means no amount.
+pub(crate) fn parse_json(json: &[u8]) -> Result<Transaction, serde_json::Error> {
+    let parsed: JsonTransaction = serde_json::from_slice(json)?;
+    let amount = match parsed.amount {
+        None => None,
+        Some(JsonAmount::Text(raw)) if raw.is_empty() => None,
+        Some(JsonAmount::Text(raw)) => Some(raw),
+        Some(JsonAmount::Number(number)) => Some(number.to_string()),
+    };
+    let amount = amount
+        .map(|raw| {
+            Decimal::from_str_exact(&raw)
+                .map_err(|_| serde_json::Error::custom(format!("invalid amount {raw:?}")))
+        })
+        .transpose()?;
+    let currency = parsed
+        .currency
+        .filter(|raw| !raw.is_empty())
+        .map(|raw| raw.parse::<Currency>().map_err(serde_json::Error::custom))
+        .transpose()?;
+
+    let mut transaction = Transaction::new(parsed.type_, parsed.client, parsed.tx, amount);
+    if let Some(to) = parsed.to {
+        transaction = transaction.with_to(to);
+    }
+    if let Some(currency) = currency {
+        transaction = transaction.with_currency(currency);
+    }
+    Ok(transaction)
+}
+
+/// Numbers the lines of JSON Lines input and parses them into transactions.
+#[derive(Debug, Default)]
+pub(crate) struct JsonLines {
+    line: u64,
+    offset: u64,
+}
+
+impl JsonLines {
+    /// Parses the next input line, returning `None` for a blank line.
+    ///
+    /// # Arguments
+    /// * `bytes` - The line with its line terminator.
+    /// * `precision` - The precision policy amounts are validated against.
+    pub(crate) fn parse(
+        &mut self,
+        bytes: &[u8],
+        precision: Precision,
+    ) -> Option<Result<Transaction, MalformedRow>> {
+        let start = self.offset;
+        self.line += 1;
+        self.offset += bytes.len() as u64;
+        if bytes.trim_ascii().is_empty() {
+            return None;
+        }
+
+        let line = self.line;
+        let parsed = parse_json(bytes)
+            .map_err(|source| InputError::Json { line, source })
+            .and_then(|tx| {
+                tx.validate(precision)
+                    .map_err(|source| InputError::InvalidAmount { line, source })?;
+                Ok(tx.with_line(line).with_offset(self.offset))
+            });
+        Some(parsed.map_err(|error| {
+            let raw = String::from_utf8_lossy(bytes.trim_ascii_end()).into_owned();
+            MalformedRow::at(line, start, raw, error)
+        }))
+    }
+}
+
+/// JsonLinesReader reads transactions from newline-delimited JSON, skipping blank lines.
+pub struct JsonLinesReader<R> {
+    input: R,
+    lines: JsonLines,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> JsonLinesReader<R> {
+    /// Creates a reader over `input`.
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            lines: JsonLines::default(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> RecordRead for JsonLinesReader<R> {
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>> {
+        loop {
+            self.buffer.clear();
+            if self.input.read_until(b'\n', &mut self.buffer)? == 0 {
+                return Ok(None);
+            }
+            if let Some(read) = self.lines.parse(&self.buffer, precision) {
+                return Ok(Some(read));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::precision::Precision;
+    use rust_decimal::prelude::*;
+    use std::io::Cursor;
+
+    fn read_all(config: ReaderConfig, input: &'static str) -> Result<Vec<Transaction>> {
+        let mut reader = config.build(Box::new(Cursor::new(input)));
+        let transactions = reader
+            .deserialize::<Transaction>()
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(transactions)
+    }
+
+    #[test]
+    fn test_trims_whitespace_and_ignores_case() -> Result<()> {
+        let input = "type, client, tx, amount\nDeposit, 1, 1, 1.0\n  dispute ,1 ,1 , \n";
+        let transactions = read_all(ReaderConfig::default(), input)?;
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(*transactions[0].type_(), TransactionType::Deposit);
+        assert_eq!(*transactions[0].amount(), Some(dec!(1.0)));
+        assert_eq!(*transactions[1].type_(), TransactionType::Dispute);
+        assert_eq!(*transactions[1].amount(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_rows_may_omit_amount_column() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndispute,1,1\nresolve,1,1\n";
+        let transactions = read_all(ReaderConfig::default(), input)?;
+
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(*transactions[1].amount(), None);
+        assert_eq!(*transactions[2].type_(), TransactionType::Resolve);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_config_rejects_tolerated_input() {
+        let strict = ReaderConfig::default()
+            .with_trim(false)
+            .with_flexible(false);
+
+        assert!(read_all(strict, "type,client,tx,amount\ndeposit, 1, 1, 1.0\n").is_err());
+        assert!(read_all(strict, "type,client,tx,amount\ndispute,1,1\n").is_err());
+    }
+
+    #[test]
+    fn test_custom_delimiter() -> Result<()> {
+        let input = "type;client;tx;amount\ndeposit;3;4;5.5\n";
+        let transactions = read_all(ReaderConfig::default().with_delimiter(b';'), input)?;
+
+        assert_eq!(*transactions[0].client(), 3);
+        assert_eq!(*transactions[0].tx(), 4);
+        assert_eq!(*transactions[0].amount(), Some(dec!(5.5)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_without_headers() -> Result<()> {
+        let config = ReaderConfig::default().with_headers(false);
+        let mut reader = config.open(Box::new(Cursor::new(
+            "deposit,3,4,5.5
+dispute,3,4
+",
+        )))?;
+
+        let deposit = reader
+            .read_transaction(Precision::default())?
+            .unwrap()
+            .unwrap();
+        assert_eq!(*deposit.type_(), TransactionType::Deposit);
+        assert_eq!(*deposit.client(), 3);
+        assert_eq!(*deposit.tx(), 4);
+        assert_eq!(*deposit.amount(), Some(dec!(5.5)));
+        let dispute = reader
+            .read_transaction(Precision::default())?
+            .unwrap()
+            .unwrap();
+        assert_eq!(*dispute.line(), Some(2));
+        assert!(reader.read_transaction(Precision::default())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_lines() -> Result<()> {
+        let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.5\"}\n\n\
+            {\"type\": \"Deposit\", \"client\": 1, \"tx\": 2, \"amount\": 2.25}\n\
+            {\"type\": \"deposit\", \"client\": 1}\n\
+            {\"type\": \"dispute\", \"client\": 1, \"tx\": 1}";
+        let mut reader = ReaderConfig::default()
+            .with_format(InputFormat::Jsonl)
+            .open(Box::new(Cursor::new(input)))?;
+
+        let mut read = Vec::new();
+        while let Some(next) = reader.read_transaction(Precision::default())? {
+            read.push(next);
+        }
+        assert_eq!(read.len(), 4);
+
+        let first = read[0]
+            .as_ref()
+            .map_err(|_| anyhow!("first line is valid"))?;
+        assert_eq!(*first.amount(), Some(dec!(1.5)));
+        assert_eq!(*first.line(), Some(1));
+        assert_eq!(*first.offset(), Some(59));
+
+        let second = read[1]
+            .as_ref()
+            .map_err(|_| anyhow!("second line is valid"))?;
+        assert_eq!(*second.amount(), Some(dec!(2.25)));
+        assert_eq!(*second.line(), Some(3));
+
+        let Err(malformed) = &read[2] else {
+            return Err(anyhow!("third line is missing its tx"));
+        };
+        assert_eq!(*malformed.line(), 4);
+        assert_eq!(malformed.raw(), "{\"type\": \"deposit\", \"client\": 1}");
+        assert!(matches!(
+            malformed.error(),
+            InputError::Json { line: 4, .. }
+        ));
+
+        let dispute = read[3]
+            .as_ref()
+            .map_err(|_| anyhow!("last line is valid"))?;
+        assert_eq!(*dispute.type_(), TransactionType::Dispute);
+        assert_eq!(*dispute.amount(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_format_from_str() -> Result<()> {
+        assert_eq!("csv".parse::<InputFormat>()?, InputFormat::Csv);
+        assert_eq!("jsonl".parse::<InputFormat>()?, InputFormat::Jsonl);
+        assert_eq!("parquet".parse::<InputFormat>()?, InputFormat::Parquet);
+        assert!("json".parse::<InputFormat>().is_err());
+        Ok(())
+    }
+}
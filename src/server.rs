@@ -0,0 +1,340 @@
+//! TCP ingestion server.
+//!
+//! Every connection streams rows in the batch input format, header row included, and gets back one CSV
+//! reply line per row:
+//!
+//! * `ack,<line>,<tx>,` when the transaction was applied,
+//! * `reject,<line>,<tx>,<reason>` when the ledger refused it,
+//! * `malformed,<line>,,<reason>` when the row could not be parsed and the error policy skips it,
+//! * `error,<line>,,<reason>` when the connection cannot go on, after which its remaining rows are ignored.
+//!
+//! All connections apply to the same `Accountant`, one row at a time, so a transaction streamed on one
+//! connection can be disputed from another.
+
+use std::{future::Future, net::SocketAddr, pin::pin, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use csv::WriterBuilder;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    errors::InputError,
+    ledger::Accountant,
+    precision::Precision,
+    processor::{ErrorPolicy, IngestReport},
+    reader::ReaderConfig,
+    streaming::{BalanceUpdate, apply_update, transaction_stream},
+    traits::Accounting,
+};
+
+/// Pause before accepting again after a failed accept, so running out of file descriptors does not spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How the transactions sent to a server are read.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Settings {
+    pub(crate) reader_config: ReaderConfig,
+    pub(crate) precision: Precision,
+    pub(crate) error_policy: ErrorPolicy,
+}
+
+impl Settings {
+    /// The default settings, validating amounts against the precision policy of `accountant`.
+    pub(crate) fn of(accountant: &Accountant) -> Self {
+        Self {
+            precision: accountant.precision(),
+            ..Self::default()
+        }
+    }
+}
+
+/// The outcome of one row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ack,
+    Reject,
+    Malformed,
+    Error,
+}
+
+/// The line sent back for one row.
+#[derive(Debug, Serialize)]
+struct Reply {
+    status: Status,
+    line: Option<u64>,
+    tx: Option<u32>,
+    reason: String,
+}
+
+impl Reply {
+    fn new(status: Status, line: Option<u64>, tx: Option<u32>, reason: impl ToString) -> Self {
+        Self {
+            status,
+            line,
+            tx,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut writer = WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        writer.serialize(self)?;
+        Ok(writer.into_inner()?)
+    }
+}
+
+/// Server applies the transactions streamed by many TCP connections to a single shared ledger.
+pub struct Server {
+    listener: TcpListener,
+    ledger: Arc<Mutex<Accountant>>,
+    settings: Settings,
+}
+
+impl Server {
+    /// Listens on `addr` for connections applying to `accountant`.
+    ///
+    /// # Arguments
+    /// * `addr` - The address to listen on, port 0 picking any free port.
+    /// * `accountant` - The ledger every connection applies to.
+    ///
+    /// # Returns
+    /// The server, accepting connections once it runs.
+    pub async fn bind(addr: impl ToSocketAddrs, accountant: Accountant) -> Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            settings: Settings::of(&accountant),
+            ledger: Arc::new(Mutex::new(accountant)),
+        })
+    }
+
+    /// Sets how the CSV of every connection is read.
+    pub fn with_reader_config(mut self, reader_config: ReaderConfig) -> Self {
+        self.settings.reader_config = reader_config;
+        self
+    }
+
+    /// Sets the policy applied to rows that cannot be parsed, counted per connection.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.settings.error_policy = error_policy;
+        self
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves connections until `shutdown` completes.
+    ///
+    /// Connections still open at shutdown are closed between two rows. A failed accept, for example when
+    /// the process runs out of file descriptors, is reported on stderr and the server keeps listening.
+    ///
+    /// # Arguments
+    /// * `shutdown` - Completes when the server should stop, for example on ctrl-c.
+    ///
+    /// # Returns
+    /// The ledger with every row applied, or an error if it cannot be finished.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<Accountant> {
+        let mut shutdown = pin!(shutdown);
+        let mut connections = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((socket, _)) => {
+                        connections.spawn(serve_connection(
+                            socket,
+                            Arc::clone(&self.ledger),
+                            self.settings,
+                        ));
+                    }
+                    Err(error) => {
+                        eprintln!("accepting a connection failed: {error}");
+                        time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                },
+                Some(_) = connections.join_next() => {}
+            }
+        }
+        connections.shutdown().await;
+
+        let mut accountant = Arc::into_inner(self.ledger)
+            .ok_or(anyhow!("the ledger is still shared after shutdown"))?
+            .into_inner();
+        accountant.finish()?;
+        Ok(accountant)
+    }
+}
+
+/// Applies the rows of one connection and replies to each of them.
+async fn serve_connection(
+    socket: TcpStream,
+    ledger: Arc<Mutex<Accountant>>,
+    settings: Settings,
+) -> Result<()> {
+    let (input, mut output) = socket.into_split();
+    let mut rows = pin!(transaction_stream(
+        input,
+        settings.reader_config,
+        settings.precision
+    ));
+    let mut report = IngestReport::default();
+
+    while let Some(item) = rows.next().await {
+        let reply = match item {
+            Ok(transaction) => {
+                let (line, tx) = (*transaction.line(), *transaction.tx());
+                match apply_update(&mut *ledger.lock().await, transaction) {
+                    Ok(Some(BalanceUpdate::Rejected(rejection))) => {
+                        Reply::new(Status::Reject, line, Some(tx), rejection.reason())
+                    }
+                    Ok(_) => Reply::new(Status::Ack, line, Some(tx), ""),
+                    Err(error) => Reply::new(Status::Error, line, Some(tx), error),
+                }
+            }
+            Err(row) if matches!(row.error(), InputError::Io(_)) => Err(row.into_error())?,
+            Err(row) => {
+                let line = Some(*row.line());
+                let reason = row.error().to_string();
+                match report.skip(settings.error_policy, row) {
+                    Ok(()) => Reply::new(Status::Malformed, line, None, reason),
+                    Err(error) => Reply::new(Status::Error, line, None, error),
+                }
+            }
+        };
+
+        output.write_all(&reply.to_bytes()?).await?;
+        if reply.status == Status::Error {
+            // Read on until the client is done so that closing does not reset the connection under the reply.
+            while rows.next().await.is_some() {}
+        }
+    }
+
+    output.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::prelude::*;
+    use tokio::{io::AsyncReadExt, sync::oneshot, task::JoinHandle};
+
+    async fn start(
+        server: Server,
+    ) -> (
+        SocketAddr,
+        oneshot::Sender<()>,
+        JoinHandle<Result<Accountant>>,
+    ) {
+        let addr = server.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let running = tokio::spawn(server.run_until(async {
+            stopped.await.ok();
+        }));
+        (addr, stop, running)
+    }
+
+    async fn send(addr: SocketAddr, input: &str) -> Result<String> {
+        let mut socket = TcpStream::connect(addr).await?;
+        socket.write_all(input.as_bytes()).await?;
+        socket.shutdown().await?;
+        let mut replies = String::new();
+        socket.read_to_string(&mut replies).await?;
+        Ok(replies)
+    }
+
+    #[tokio::test]
+    async fn test_replies_to_every_row() -> Result<()> {
+        let server = Server::bind("127.0.0.1:0", Accountant::new())
+            .await?
+            .with_error_policy(ErrorPolicy::Skip);
+        let (addr, stop, running) = start(server).await;
+
+        let replies = send(
+            addr,
+            "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,50.0\ndeposit,x,3,1.0\ndispute,1,1,\n",
+        )
+        .await?;
+        let replies: Vec<&str> = replies.lines().collect();
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], "ack,2,1,");
+        assert!(replies[1].starts_with("reject,3,2,"));
+        assert!(replies[2].starts_with("malformed,4,,"));
+        assert_eq!(replies[3], "ack,5,1,");
+
+        stop.send(()).ok();
+        let accountant = running.await??;
+        let client = accountant.client(1).unwrap();
+        assert_eq!(*client.held(), dec!(10.0));
+        assert_eq!(accountant.rejections().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connections_share_one_ledger() -> Result<()> {
+        let (addr, stop, running) =
+            start(Server::bind("127.0.0.1:0", Accountant::new()).await?).await;
+
+        let (first, second) = tokio::join!(
+            send(
+                addr,
+                "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n"
+            ),
+            send(addr, "type,client,tx,amount\ndeposit,2,3,7.5\n"),
+        );
+        assert_eq!(first?, "ack,2,1,\nack,3,2,\n");
+        assert_eq!(second?, "ack,2,3,\n");
+
+        let replies = send(
+            addr,
+            "type,client,tx,amount\ndispute,1,2,\ndeposit,2,1,1.0\n",
+        )
+        .await?;
+        let replies: Vec<&str> = replies.lines().collect();
+        assert_eq!(replies[0], "ack,2,2,");
+        assert!(replies[1].starts_with("reject,3,1,"));
+
+        stop.send(()).ok();
+        let accountant = running.await??;
+        assert_eq!(*accountant.client(1).unwrap().held(), dec!(5.0));
+        assert_eq!(*accountant.client(2).unwrap().total(), dec!(7.5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_policy_stops_the_connection() -> Result<()> {
+        let (addr, stop, running) =
+            start(Server::bind("127.0.0.1:0", Accountant::new()).await?).await;
+
+        let replies = send(
+            addr,
+            "type,client,tx,amount\ndeposit,x,1,1.0\ndeposit,1,2,1.0\n",
+        )
+        .await?;
+        assert_eq!(replies.lines().count(), 1);
+        assert!(replies.starts_with("error,2,,"));
+
+        assert_eq!(
+            send(addr, "type,client,tx,amount\ndeposit,1,3,1.0\n").await?,
+            "ack,2,3,\n"
+        );
+
+        stop.send(()).ok();
+        let accountant = running.await??;
+        assert_eq!(*accountant.client(1).unwrap().total(), dec!(1.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,574 @@
+//! Versioned binary encoding of the ledger state.
+//!
+//! A snapshot starts with the `PWCS` magic followed by a little-endian `u16` format version.
+//! Every integer is little-endian, decimals are stored as their 16 byte `Decimal::serialize` form,
+//! options are prefixed with a presence byte and sequences with a `u64` length.
+
+use std::io::{Read, Write};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    errors::{LedgerError, SnapshotError},
+    fees::FeePosting,
+    models::{
+        ClientBalance, Currency, MAX_CURRENCY_LEN, Rejection, Transaction, TransactionState,
+        TransactionType,
+    },
+    precision::Residual,
+};
+
+const MAGIC: &[u8; 4] = b"PWCS";
+const VERSION: u16 = 1;
+
+/// Writes the snapshot magic and format version.
+pub(crate) fn write_header(w: &mut impl Write) -> Result<(), SnapshotError> {
+    w.write_all(MAGIC)?;
+    VERSION.encode(w)
+}
+
+/// Reads and checks the snapshot magic and format version.
+pub(crate) fn read_header(r: &mut impl Read) -> Result<(), SnapshotError> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::UnknownFormat);
+    }
+    match u16::decode(r)? {
+        VERSION => Ok(()),
+        version => Err(SnapshotError::UnsupportedVersion(version)),
+    }
+}
+
+pub(crate) trait Encode {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError>;
+}
+
+pub(crate) trait Decode: Sized {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError>;
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+                Ok(w.write_all(&self.to_le_bytes())?)
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+                let mut buf = [0u8; size_of::<$t>()];
+                r.read_exact(&mut buf)?;
+                Ok(<$t>::from_le_bytes(buf))
+            }
+        }
+    )*};
+}
+
+impl_int!(u8, u16, u32, u64);
+
+impl Encode for bool {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        u8::from(*self).encode(w)
+    }
+}
+
+impl Decode for bool {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(SnapshotError::UnknownTag { kind: "bool", tag }),
+        }
+    }
+}
+
+impl Encode for Decimal {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        Ok(w.write_all(&self.serialize())?)
+    }
+}
+
+impl Decode for Decimal {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut buf = [0u8; 16];
+        r.read_exact(&mut buf)?;
+        Ok(Decimal::deserialize(buf))
+    }
+}
+
+impl Encode for Currency {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        Ok(w.write_all(&self.to_bytes())?)
+    }
+}
+
+impl Decode for Currency {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut buf = [0u8; MAX_CURRENCY_LEN];
+        r.read_exact(&mut buf)?;
+        Ok(Currency::from_bytes(buf))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        match self {
+            None => false.encode(w),
+            Some(value) => {
+                true.encode(w)?;
+                value.encode(w)
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        match bool::decode(r)? {
+            false => Ok(None),
+            true => Ok(Some(T::decode(r)?)),
+        }
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        (**self).encode(w)
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        (self.len() as u64).encode(w)?;
+        self.iter().try_for_each(|item| item.encode(w))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.as_slice().encode(w)
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        let len = u64::decode(r)?;
+        (0..len).map(|_| T::decode(r)).collect()
+    }
+}
+
+impl Encode for TransactionType {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        let tag: u8 = match self {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => 1,
+            TransactionType::Dispute => 2,
+            TransactionType::Resolve => 3,
+            TransactionType::Chargeback => 4,
+            TransactionType::Transfer => 5,
+        };
+        tag.encode(w)
+    }
+}
+
+impl Decode for TransactionType {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        match u8::decode(r)? {
+            0 => Ok(TransactionType::Deposit),
+            1 => Ok(TransactionType::Withdrawal),
+            2 => Ok(TransactionType::Dispute),
+            3 => Ok(TransactionType::Resolve),
+            4 => Ok(TransactionType::Chargeback),
+            5 => Ok(TransactionType::Transfer),
+            tag => Err(SnapshotError::UnknownTag {
+                kind: "transaction type",
+                tag,
+            }),
+        }
+    }
+}
+
+impl Encode for TransactionState {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        let tag: u8 = match self {
+            TransactionState::Processed => 0,
+            TransactionState::Disputed => 1,
+            TransactionState::Resolved => 2,
+            TransactionState::ChargedBack => 3,
+        };
+        tag.encode(w)
+    }
+}
+
+impl Decode for TransactionState {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        match u8::decode(r)? {
+            0 => Ok(TransactionState::Processed),
+            1 => Ok(TransactionState::Disputed),
+            2 => Ok(TransactionState::Resolved),
+            3 => Ok(TransactionState::ChargedBack),
+            tag => Err(SnapshotError::UnknownTag {
+                kind: "transaction state",
+                tag,
+            }),
+        }
+    }
+}
+
+impl Encode for ClientBalance {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.client().encode(w)?;
+        self.currency().encode(w)?;
+        self.available().encode(w)?;
+        self.held().encode(w)?;
+        self.total().encode(w)?;
+        self.locked().encode(w)
+    }
+}
+
+impl Decode for ClientBalance {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        Ok(ClientBalance::from_parts(
+            u16::decode(r)?,
+            Option::<Currency>::decode(r)?,
+            Decimal::decode(r)?,
+            Decimal::decode(r)?,
+            Decimal::decode(r)?,
+            bool::decode(r)?,
+        ))
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.type_().encode(w)?;
+        self.client().encode(w)?;
+        self.tx().encode(w)?;
+        self.amount().encode(w)?;
+        self.to().encode(w)?;
+        self.currency().encode(w)?;
+        self.state().encode(w)?;
+        self.line().encode(w)
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut transaction = Transaction::new(
+            TransactionType::decode(r)?,
+            u16::decode(r)?,
+            u32::decode(r)?,
+            Option::<Decimal>::decode(r)?,
+        );
+        if let Some(to) = Option::<u16>::decode(r)? {
+            transaction = transaction.with_to(to);
+        }
+        if let Some(currency) = Option::<Currency>::decode(r)? {
+            transaction = transaction.with_currency(currency);
+        }
+        let transaction = transaction.with_state(TransactionState::decode(r)?);
+        Ok(match Option::<u64>::decode(r)? {
+            Some(line) => transaction.with_line(line),
+            None => transaction,
+        })
+    }
+}
+
+impl Encode for LedgerError {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        match self {
+            LedgerError::InsufficientFunds { amount, balance } => {
+                0u8.encode(w)?;
+                amount.encode(w)?;
+                balance.encode(w)
+            }
+            LedgerError::AccountLocked(client) => {
+                1u8.encode(w)?;
+                client.encode(w)
+            }
+            LedgerError::TxNotFound(tx) => {
+                2u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::TxDuplicated(tx) => {
+                3u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::ClientMismatch { tx, expected, got } => {
+                4u8.encode(w)?;
+                tx.encode(w)?;
+                expected.encode(w)?;
+                got.encode(w)
+            }
+            LedgerError::IllegalTransition { tx, from, to } => {
+                5u8.encode(w)?;
+                tx.encode(w)?;
+                from.encode(w)?;
+                to.encode(w)
+            }
+            LedgerError::MissingAmount(tx) => {
+                6u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::NonPositiveAmount(amount) => {
+                7u8.encode(w)?;
+                amount.encode(w)
+            }
+            LedgerError::ValueOverflow => 8u8.encode(w),
+            LedgerError::MissingDestination(tx) => {
+                9u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::TransferToSelf(tx) => {
+                10u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::UnexpectedDestination(tx) => {
+                11u8.encode(w)?;
+                tx.encode(w)
+            }
+            LedgerError::CurrencyMismatch { tx, expected, got } => {
+                12u8.encode(w)?;
+                tx.encode(w)?;
+                expected.encode(w)?;
+                got.encode(w)
+            }
+            LedgerError::TooPrecise { tx, max_scale } => {
+                13u8.encode(w)?;
+                tx.encode(w)?;
+                max_scale.encode(w)
+            }
+            LedgerError::HouseAccount(client) => {
+                14u8.encode(w)?;
+                client.encode(w)
+            }
+            LedgerError::FeeExceedsAmount { tx, fee } => {
+                15u8.encode(w)?;
+                tx.encode(w)?;
+                fee.encode(w)
+            }
+            LedgerError::TooLarge { tx, max_scale } => {
+                16u8.encode(w)?;
+                tx.encode(w)?;
+                max_scale.encode(w)
+            }
+        }
+    }
+}
+
+impl Decode for LedgerError {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        match u8::decode(r)? {
+            0 => Ok(LedgerError::InsufficientFunds {
+                amount: Decimal::decode(r)?,
+                balance: Decimal::decode(r)?,
+            }),
+            1 => Ok(LedgerError::AccountLocked(u16::decode(r)?)),
+            2 => Ok(LedgerError::TxNotFound(u32::decode(r)?)),
+            3 => Ok(LedgerError::TxDuplicated(u32::decode(r)?)),
+            4 => Ok(LedgerError::ClientMismatch {
+                tx: u32::decode(r)?,
+                expected: u16::decode(r)?,
+                got: u16::decode(r)?,
+            }),
+            5 => Ok(LedgerError::IllegalTransition {
+                tx: u32::decode(r)?,
+                from: TransactionState::decode(r)?,
+                to: TransactionState::decode(r)?,
+            }),
+            6 => Ok(LedgerError::MissingAmount(u32::decode(r)?)),
+            7 => Ok(LedgerError::NonPositiveAmount(Decimal::decode(r)?)),
+            8 => Ok(LedgerError::ValueOverflow),
+            9 => Ok(LedgerError::MissingDestination(u32::decode(r)?)),
+            10 => Ok(LedgerError::TransferToSelf(u32::decode(r)?)),
+            11 => Ok(LedgerError::UnexpectedDestination(u32::decode(r)?)),
+            12 => Ok(LedgerError::CurrencyMismatch {
+                tx: u32::decode(r)?,
+                expected: Option::<Currency>::decode(r)?,
+                got: Currency::decode(r)?,
+            }),
+            13 => Ok(LedgerError::TooPrecise {
+                tx: u32::decode(r)?,
+                max_scale: u32::decode(r)?,
+            }),
+            14 => Ok(LedgerError::HouseAccount(u16::decode(r)?)),
+            15 => Ok(LedgerError::FeeExceedsAmount {
+                tx: u32::decode(r)?,
+                fee: Decimal::decode(r)?,
+            }),
+            16 => Ok(LedgerError::TooLarge {
+                tx: u32::decode(r)?,
+                max_scale: u32::decode(r)?,
+            }),
+            tag => Err(SnapshotError::UnknownTag {
+                kind: "ledger error",
+                tag,
+            }),
+        }
+    }
+}
+
+impl Encode for Residual {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.currency().encode(w)?;
+        self.rounded().encode(w)?;
+        self.residual().encode(w)
+    }
+}
+
+impl Decode for Residual {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        Ok(Residual::from_parts(
+            Option::<Currency>::decode(r)?,
+            u64::decode(r)?,
+            Decimal::decode(r)?,
+        ))
+    }
+}
+
+impl Encode for FeePosting {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.tx().encode(w)?;
+        self.client().encode(w)?;
+        self.house().encode(w)?;
+        self.currency().encode(w)?;
+        self.fee().encode(w)
+    }
+}
+
+impl Decode for FeePosting {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        Ok(FeePosting::new(
+            u32::decode(r)?,
+            u16::decode(r)?,
+            u16::decode(r)?,
+            Option::<Currency>::decode(r)?,
+            Decimal::decode(r)?,
+        ))
+    }
+}
+
+impl Encode for Rejection {
+    fn encode(&self, w: &mut impl Write) -> Result<(), SnapshotError> {
+        self.tx().encode(w)?;
+        self.client().encode(w)?;
+        self.type_().encode(w)?;
+        self.reason().encode(w)?;
+        self.input_line().encode(w)
+    }
+}
+
+impl Decode for Rejection {
+    fn decode(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        Ok(Rejection::from_parts(
+            u32::decode(r)?,
+            u16::decode(r)?,
+            TransactionType::decode(r)?,
+            LedgerError::decode(r)?,
+            Option::<u64>::decode(r)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rust_decimal::prelude::*;
+
+    fn round_trip<T: Encode + Decode>(value: &T) -> Result<T> {
+        let mut buf = Vec::new();
+        value.encode(&mut buf)?;
+        let mut slice = buf.as_slice();
+        let decoded = T::decode(&mut slice)?;
+        assert!(slice.is_empty());
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_round_trip_primitives() -> Result<()> {
+        assert_eq!(round_trip(&u32::MAX)?, u32::MAX);
+        assert_eq!(round_trip(&dec!(-123.4567))?, dec!(-123.4567));
+        assert_eq!(round_trip(&Some(7u64))?, Some(7));
+        assert_eq!(round_trip(&None::<u64>)?, None);
+        assert_eq!(round_trip(&vec![1u16, 2, 3])?, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_ledger_errors() -> Result<()> {
+        let errors = [
+            LedgerError::InsufficientFunds {
+                amount: dec!(1.5),
+                balance: dec!(0.5),
+            },
+            LedgerError::AccountLocked(3),
+            LedgerError::TxNotFound(4),
+            LedgerError::TxDuplicated(5),
+            LedgerError::ClientMismatch {
+                tx: 6,
+                expected: 1,
+                got: 2,
+            },
+            LedgerError::IllegalTransition {
+                tx: 7,
+                from: TransactionState::Resolved,
+                to: TransactionState::Disputed,
+            },
+            LedgerError::MissingAmount(8),
+            LedgerError::NonPositiveAmount(dec!(-1)),
+            LedgerError::ValueOverflow,
+            LedgerError::MissingDestination(9),
+            LedgerError::TransferToSelf(10),
+            LedgerError::UnexpectedDestination(11),
+            LedgerError::CurrencyMismatch {
+                tx: 12,
+                expected: None,
+                got: "EUR".parse()?,
+            },
+            LedgerError::TooPrecise {
+                tx: 13,
+                max_scale: 2,
+            },
+            LedgerError::HouseAccount(14),
+            LedgerError::FeeExceedsAmount {
+                tx: 15,
+                fee: dec!(2),
+            },
+            LedgerError::TooLarge {
+                tx: 16,
+                max_scale: 28,
+            },
+        ];
+        for error in errors {
+            assert_eq!(round_trip(&error)?, error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_is_checked() {
+        let mut wrong_magic: &[u8] = b"XXXX\x01\x00";
+        assert!(matches!(
+            read_header(&mut wrong_magic),
+            Err(SnapshotError::UnknownFormat)
+        ));
+
+        let mut wrong_version: &[u8] = b"PWCS\x04\x00";
+        assert!(matches!(
+            read_header(&mut wrong_version),
+            Err(SnapshotError::UnsupportedVersion(4))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_tag_is_rejected() {
+        let mut bytes: &[u8] = &[9];
+        assert!(matches!(
+            TransactionType::decode(&mut bytes),
+            Err(SnapshotError::UnknownTag { tag: 9, .. })
+        ));
+    }
+}
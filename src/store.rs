@@ -0,0 +1,611 @@
+//! Transaction stores backing the ledger.
+//!
+//! `MemoryStore` keeps every stored transaction in a hash map and is the default.
+//! `DiskStore` keeps them in a memory-mapped table of fixed-size slots indexed by transaction id,
+//! so only the pages touched by recent disputes stay resident.
+//! `SpillStore` keeps up to a memory budget of transactions in memory and spills them to a `DiskStore`
+//! once the budget is reached.
+//!
+//! A resolved or charged-back transaction can no longer move funds, so every store keeps only a tombstone
+//! of it: its type, client, currency and state, enough to refuse its id and to report disputes of it,
+//! but not its amount, receiving client or input line.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use memmap2::MmapMut;
+
+use crate::{
+    models::{Currency, Transaction, TransactionState, TransactionType},
+    snapshot::{Decode, Encode},
+    traits::TransactionStore,
+};
+
+/// Approximate memory taken by one transaction in a `MemoryStore`, hash map overhead included.
+const ENTRY_BYTES: usize = (size_of::<(u32, Transaction)>() + 1) * 8 / 7;
+
+/// Size of one `DiskStore` slot: a presence byte followed by the snapshot encoding of the transaction.
+const SLOT: usize = 48;
+
+/// Smallest size of the `DiskStore` table, grown by doubling from there.
+const MIN_TABLE: u64 = 1 << 20;
+
+/// Number of consecutive `DiskStore` slots covered by one bit of its occupancy index.
+const BLOCK: usize = 64;
+
+/// What a store keeps of a transaction once it is resolved or charged back.
+#[derive(Debug, Clone, Copy)]
+struct Tombstone {
+    type_: TransactionType,
+    client: u16,
+    currency: Option<Currency>,
+    state: TransactionState,
+}
+
+impl Tombstone {
+    /// Returns the tombstone of `transaction` if it is resolved or charged back.
+    fn of(transaction: &Transaction) -> Option<Self> {
+        match transaction.state() {
+            TransactionState::Processed | TransactionState::Disputed => None,
+            TransactionState::Resolved | TransactionState::ChargedBack => Some(Self {
+                type_: *transaction.type_(),
+                client: *transaction.client(),
+                currency: *transaction.currency(),
+                state: *transaction.state(),
+            }),
+        }
+    }
+
+    /// Returns the transaction `tx` as far as the tombstone keeps it.
+    fn transaction(self, tx: u32) -> Transaction {
+        let transaction =
+            Transaction::new(self.type_, self.client, tx, None).with_state(self.state);
+        match self.currency {
+            Some(currency) => transaction.with_currency(currency),
+            None => transaction,
+        }
+    }
+}
+
+/// Returns `transaction`, reduced to its tombstone once it is resolved or charged back.
+fn compacted(transaction: Transaction) -> Transaction {
+    match Tombstone::of(&transaction) {
+        Some(tombstone) => tombstone.transaction(*transaction.tx()),
+        None => transaction,
+    }
+}
+
+/// MemoryStore keeps stored transactions in a hash map, and the tombstones of resolved and charged-back
+/// ones in another.
+#[derive(Default)]
+pub struct MemoryStore {
+    transactions: HashMap<u32, Transaction>,
+    tombstones: HashMap<u32, Tombstone>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sorted_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .transactions
+            .keys()
+            .chain(self.tombstones.keys())
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<Transaction> {
+        self.transactions.remove(&tx).or_else(|| {
+            self.tombstones
+                .remove(&tx)
+                .map(|tombstone| tombstone.transaction(tx))
+        })
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&self, tx: u32) -> Result<Option<Transaction>> {
+        Ok(match self.transactions.get(&tx) {
+            Some(transaction) => Some(transaction.clone()),
+            None => self
+                .tombstones
+                .get(&tx)
+                .map(|tombstone| tombstone.transaction(tx)),
+        })
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool> {
+        Ok(self.transactions.contains_key(&tx) || self.tombstones.contains_key(&tx))
+    }
+
+    fn insert(&mut self, transaction: Transaction) -> Result<()> {
+        let tx = *transaction.tx();
+        match Tombstone::of(&transaction) {
+            Some(tombstone) => {
+                self.transactions.remove(&tx);
+                self.tombstones.insert(tx, tombstone);
+            }
+            None => {
+                self.tombstones.remove(&tx);
+                self.transactions.insert(tx, transaction);
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.transactions.len() + self.tombstones.len()
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        for id in self.sorted_ids() {
+            if let Some(transaction) = self.get(id)? {
+                visit(transaction)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        for id in self.sorted_ids() {
+            if let Some(transaction) = self.remove(id) {
+                visit(transaction)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// DiskStore keeps stored transactions in a memory-mapped scratch file with one fixed-size slot per
+/// transaction id.
+///
+/// The file is sparse, grows by doubling up to the highest stored id and is removed when the store is dropped.
+/// The slot of a resolved or charged-back transaction holds its tombstone, the rest of it zeroed.
+/// An occupancy index in memory, one bit per block of slots, lets visits skip the blocks never written.
+pub struct DiskStore {
+    path: PathBuf,
+    file: File,
+    map: Option<MmapMut>,
+    occupied: Vec<u64>,
+    len: usize,
+}
+
+impl DiskStore {
+    /// Creates the store backed by `path`, truncating any existing file.
+    ///
+    /// # Arguments
+    /// * `path` - The scratch file, removed when the store is dropped.
+    ///
+    /// # Returns
+    /// The empty store.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(Self {
+            path,
+            file,
+            map: None,
+            occupied: Vec::new(),
+            len: 0,
+        })
+    }
+
+    /// Records that the block holding the slot of `tx` is written.
+    fn mark(&mut self, tx: u32) {
+        let block = tx as usize / BLOCK;
+        let word = block / u64::BITS as usize;
+        if word >= self.occupied.len() {
+            self.occupied.resize(word + 1, 0);
+        }
+        self.occupied[word] |= 1 << (block % u64::BITS as usize);
+    }
+
+    /// Returns the blocks holding at least one written slot, in ascending order.
+    fn occupied_blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.occupied.iter().enumerate().flat_map(|(word, bits)| {
+            (0..u64::BITS as usize)
+                .filter(move |bit| bits >> bit & 1 == 1)
+                .map(move |bit| word * u64::BITS as usize + bit)
+        })
+    }
+
+    fn slot(&self, tx: u32) -> Option<&[u8]> {
+        let start = tx as usize * SLOT;
+        self.map.as_ref()?.get(start..start + SLOT)
+    }
+
+    /// Grows the table so that it holds the slot of `tx`.
+    fn reserve(&mut self, tx: u32) -> Result<()> {
+        let needed = (u64::from(tx) + 1) * SLOT as u64;
+        if self.map.as_ref().map_or(0, |map| map.len() as u64) >= needed {
+            return Ok(());
+        }
+
+        self.map = None;
+        self.file
+            .set_len(needed.next_power_of_two().max(MIN_TABLE))?;
+        // SAFETY: the file is private to this store and only ever resized after the map is dropped.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    fn decode(slot: &[u8]) -> Result<Option<Transaction>> {
+        match slot[0] {
+            0 => Ok(None),
+            _ => Ok(Some(Transaction::decode(&mut &slot[1..])?)),
+        }
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, tx: u32) -> Result<Option<Transaction>> {
+        match self.slot(tx) {
+            None => Ok(None),
+            Some(slot) => Self::decode(slot),
+        }
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool> {
+        Ok(self.slot(tx).is_some_and(|slot| slot[0] != 0))
+    }
+
+    fn insert(&mut self, transaction: Transaction) -> Result<()> {
+        let transaction = compacted(transaction);
+        let mut encoded = vec![1u8];
+        transaction.encode(&mut encoded)?;
+        if encoded.len() > SLOT {
+            Err(anyhow!(
+                "transaction {} does not fit a disk store slot",
+                transaction.tx()
+            ))?
+        }
+
+        let tx = *transaction.tx();
+        self.reserve(tx)?;
+        let start = tx as usize * SLOT;
+        let Some(map) = self.map.as_mut() else {
+            unreachable!("reserve always maps the table");
+        };
+        if map[start] == 0 {
+            self.len += 1;
+        }
+        encoded.resize(SLOT, 0);
+        map[start..start + SLOT].copy_from_slice(&encoded);
+        self.mark(tx);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        let Some(map) = self.map.as_ref() else {
+            return Ok(());
+        };
+        for block in self.occupied_blocks() {
+            let start = block * BLOCK * SLOT;
+            let end = (start + BLOCK * SLOT).min(map.len());
+            for slot in map[start..end].chunks_exact(SLOT) {
+                if let Some(transaction) = Self::decode(slot)? {
+                    visit(transaction)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        self.for_each(visit)?;
+        self.map = None;
+        self.file.set_len(0)?;
+        self.occupied.clear();
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        self.map = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// SpillStore keeps transactions in memory up to a memory budget and spills all of them to a `DiskStore`
+/// whenever the budget is reached.
+///
+/// Resolved and charged-back transactions go straight to the `DiskStore` as tombstones, so the memory
+/// budget only holds transactions that can still be disputed.
+pub struct SpillStore {
+    memory: MemoryStore,
+    disk: DiskStore,
+    capacity: usize,
+}
+
+impl SpillStore {
+    /// Creates the store.
+    ///
+    /// # Arguments
+    /// * `memory_budget` - Approximate number of bytes the in-memory part may take.
+    /// * `disk` - The store transactions are spilled to.
+    ///
+    /// # Returns
+    /// The empty store.
+    pub fn new(memory_budget: usize, disk: DiskStore) -> Self {
+        Self {
+            memory: MemoryStore::new(),
+            disk,
+            capacity: (memory_budget / ENTRY_BYTES).max(1),
+        }
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let disk = &mut self.disk;
+        self.memory
+            .drain(&mut |transaction| disk.insert(transaction))
+    }
+}
+
+impl TransactionStore for SpillStore {
+    fn get(&self, tx: u32) -> Result<Option<Transaction>> {
+        match self.memory.get(tx)? {
+            Some(transaction) => Ok(Some(transaction)),
+            None => self.disk.get(tx),
+        }
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool> {
+        Ok(self.memory.contains(tx)? || self.disk.contains(tx)?)
+    }
+
+    fn insert(&mut self, transaction: Transaction) -> Result<()> {
+        let tx = *transaction.tx();
+        if Tombstone::of(&transaction).is_some() {
+            self.memory.remove(tx);
+            return self.disk.insert(transaction);
+        }
+        if !self.memory.contains(tx)? && self.disk.contains(tx)? {
+            return self.disk.insert(transaction);
+        }
+
+        self.memory.insert(transaction)?;
+        if self.memory.len() >= self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len() + self.disk.len()
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        let mut ids = self.memory.sorted_ids().into_iter().peekable();
+        self.disk.for_each(&mut |transaction| {
+            while let Some(id) = ids.next_if(|id| id < transaction.tx()) {
+                visit(self.memory.transactions[&id].clone())?;
+            }
+            visit(transaction)
+        })?;
+        for id in ids {
+            visit(self.memory.transactions[&id].clone())?;
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        let memory = &mut self.memory;
+        let mut ids = memory.sorted_ids().into_iter().peekable();
+        self.disk.drain(&mut |transaction| {
+            while let Some(id) = ids.next_if(|id| id < transaction.tx()) {
+                if let Some(spilled) = memory.transactions.remove(&id) {
+                    visit(spilled)?;
+                }
+            }
+            visit(transaction)
+        })?;
+        memory.drain(visit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{TransactionState, TransactionType};
+    use rust_decimal::prelude::*;
+    use std::env;
+
+    fn store_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("store-{}-{name}", std::process::id()))
+    }
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            (tx % 7) as u16,
+            tx,
+            Some(dec!(1.5)),
+        )
+        .with_line(u64::from(tx) + 2)
+    }
+
+    fn collected(store: &dyn TransactionStore) -> Result<Vec<u32>> {
+        let mut ids = Vec::new();
+        store.for_each(&mut |transaction| {
+            ids.push(*transaction.tx());
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    fn exercise(store: &mut dyn TransactionStore) -> Result<()> {
+        for tx in [40, 3, 100_000, 7, 12] {
+            store.insert(deposit(tx))?;
+        }
+        assert_eq!(store.len(), 5);
+        assert!(store.contains(100_000)?);
+        assert!(!store.contains(8)?);
+        assert_eq!(store.get(3)?, Some(deposit(3)));
+        assert_eq!(store.get(99)?, None);
+
+        store.insert(deposit(7).with_state(TransactionState::Disputed))?;
+        assert_eq!(store.len(), 5);
+        assert_eq!(
+            store.get(7)?.map(|transaction| *transaction.state()),
+            Some(TransactionState::Disputed)
+        );
+        assert_eq!(collected(store)?, vec![3, 7, 12, 40, 100_000]);
+
+        store.insert(deposit(12).with_state(TransactionState::ChargedBack))?;
+        assert_eq!(store.len(), 5);
+        assert!(store.contains(12)?);
+        assert_eq!(
+            store.get(12)?,
+            Some(
+                Transaction::new(TransactionType::Deposit, 5, 12, None)
+                    .with_state(TransactionState::ChargedBack)
+            )
+        );
+        assert_eq!(collected(store)?, vec![3, 7, 12, 40, 100_000]);
+
+        let mut drained = Vec::new();
+        store.drain(&mut |transaction| {
+            drained.push(*transaction.tx());
+            Ok(())
+        })?;
+        assert_eq!(drained, vec![3, 7, 12, 40, 100_000]);
+        assert!(store.is_empty());
+        assert!(!store.contains(3)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_store() -> Result<()> {
+        exercise(&mut MemoryStore::new())
+    }
+
+    #[test]
+    fn test_memory_store_keeps_tombstones_of_closed_transactions() -> Result<()> {
+        let mut store = MemoryStore::new();
+        for tx in 0..4 {
+            store.insert(deposit(tx))?;
+        }
+        store.insert(deposit(1).with_state(TransactionState::Resolved))?;
+        store.insert(deposit(2).with_state(TransactionState::ChargedBack))?;
+
+        assert_eq!(store.transactions.len(), 2);
+        assert_eq!(store.tombstones.len(), 2);
+        assert!(size_of::<(u32, Tombstone)>() * 2 < size_of::<(u32, Transaction)>());
+        assert_eq!(
+            store.get(1)?,
+            Some(
+                Transaction::new(TransactionType::Deposit, 1, 1, None)
+                    .with_state(TransactionState::Resolved)
+            )
+        );
+        assert_eq!(collected(&store)?, vec![0, 1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_store() -> Result<()> {
+        exercise(&mut DiskStore::create(store_path("disk"))?)
+    }
+
+    #[test]
+    fn test_disk_store_visits_only_written_blocks() -> Result<()> {
+        let mut store = DiskStore::create(store_path("sparse"))?;
+        for tx in [3, 5, 70_000, 200_000] {
+            store.insert(deposit(tx))?;
+        }
+        assert_eq!(
+            store.occupied_blocks().collect::<Vec<_>>(),
+            vec![0, 70_000 / BLOCK, 200_000 / BLOCK]
+        );
+        assert_eq!(collected(&store)?, vec![3, 5, 70_000, 200_000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_store_removes_its_file() -> Result<()> {
+        let path = store_path("drop");
+        let mut store = DiskStore::create(&path)?;
+        store.insert(deposit(1))?;
+        drop(store);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_store() -> Result<()> {
+        let mut store = SpillStore::new(ENTRY_BYTES * 2, DiskStore::create(store_path("spill"))?);
+        exercise(&mut store)
+    }
+
+    #[test]
+    fn test_spill_store_bounds_memory() -> Result<()> {
+        let mut store = SpillStore::new(ENTRY_BYTES * 3, DiskStore::create(store_path("bound"))?);
+        for tx in 0..10 {
+            store.insert(deposit(tx))?;
+            assert!(store.memory.len() < 3);
+        }
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.get(0)?, Some(deposit(0)));
+        assert_eq!(collected(&store)?, (0..10).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_store_moves_closed_transactions_to_disk() -> Result<()> {
+        let mut store = SpillStore::new(ENTRY_BYTES * 3, DiskStore::create(store_path("closed"))?);
+        store.insert(deposit(0))?;
+        store.insert(deposit(1))?;
+        assert_eq!(store.memory.len(), 2);
+
+        store.insert(deposit(0).with_state(TransactionState::Resolved))?;
+        assert_eq!(store.memory.len(), 1);
+        assert_eq!(store.disk.len(), 1);
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.get(0)?.map(|transaction| *transaction.state()),
+            Some(TransactionState::Resolved)
+        );
+        assert_eq!(collected(&store)?, vec![0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_largest_transaction_fits_a_slot() -> Result<()> {
+        let largest = Transaction::new(
+            TransactionType::Transfer,
+            u16::MAX,
+            u32::MAX,
+            Some(Decimal::MAX),
+        )
+        .with_to(u16::MAX)
+        .with_currency("ABCDEFGH".parse()?)
+        .with_line(u64::MAX)
+        .with_state(TransactionState::ChargedBack);
+        let mut encoded = vec![1u8];
+        largest.encode(&mut encoded)?;
+        assert!(encoded.len() <= SLOT);
+        Ok(())
+    }
+}
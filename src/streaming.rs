@@ -0,0 +1,474 @@
+//! Async ingestion over tokio.
+//!
+//! `transaction_stream` turns any `AsyncRead` into a stream of parsed and validated transactions.
+//! Rows are handed to the CSV reader one line at a time, so the input is parsed exactly like the blocking
+//! `Engine` does as long as no quoted field spans several lines. JSON Lines input is parsed line by line.
+//! Parquet input cannot be streamed and ends the stream with an i/o error.
+//!
+//! `AsyncEngine` applies such a stream to an `Accountant` and can publish a `BalanceUpdate` for every row
+//! on a tokio channel as it is applied.
+
+use std::{cell::RefCell, collections::VecDeque, io, pin::pin};
+
+use anyhow::Result;
+use csv::{Reader, StringRecord};
+use futures::{Stream, StreamExt, stream};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::mpsc::Sender,
+};
+
+use crate::{
+    errors::InputError,
+    ledger::Accountant,
+    models::{ClientBalance, Rejection, Transaction},
+    precision::Precision,
+    processor::{ErrorPolicy, IngestReport, MalformedRow, parse_record},
+    reader::{InputFormat, JsonLines, ReaderConfig},
+    traits::{Accounting, CSVExport},
+    writer::BufferCSVWriter,
+};
+
+/// A change published by the `AsyncEngine` for every applied row.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum BalanceUpdate {
+    /// The transaction was applied and left its client with `balance`.
+    Applied {
+        /// The applied transaction id.
+        tx: u32,
+        /// The client balance after the transaction.
+        balance: ClientBalance,
+    },
+    /// The transaction was refused by the ledger.
+    Rejected(Rejection),
+}
+
+/// Feeds an async input to a blocking CSV reader one line at a time.
+struct Feed<R> {
+    input: BufReader<R>,
+    json: Option<JsonLines>,
+    csv: Reader<VecDeque<u8>>,
+    expects_headers: bool,
+    headers: Option<StringRecord>,
+    precision: Precision,
+    line: Vec<u8>,
+    record: StringRecord,
+    parquet: bool,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> Feed<R> {
+    async fn next(&mut self) -> Option<Result<Transaction, MalformedRow>> {
+        if self.parquet && !self.done {
+            self.done = true;
+            let error = io::Error::new(
+                io::ErrorKind::Unsupported,
+                "parquet input cannot be streamed",
+            );
+            return Some(Err(MalformedRow::at(
+                1,
+                0,
+                String::new(),
+                InputError::Io(error),
+            )));
+        }
+        while !self.done {
+            self.line.clear();
+            match self.input.read_until(b'\n', &mut self.line).await {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    if let Some(json) = &mut self.json {
+                        match json.parse(&self.line, self.precision) {
+                            Some(item) => return Some(item),
+                            None => continue,
+                        }
+                    }
+                    self.csv.get_mut().extend(&self.line);
+                    // The CSV reader skips empty lines by reading on, so keep them until a record follows.
+                    if self.line.iter().all(|byte| matches!(byte, b'\r' | b'\n')) {
+                        continue;
+                    }
+                    if let Some(item) = self.read() {
+                        return Some(item);
+                    }
+                }
+                Err(err) => {
+                    self.done = true;
+                    let position = self.csv.position().clone();
+                    return Some(Err(MalformedRow::new(
+                        &position,
+                        String::new(),
+                        InputError::Io(err),
+                    )));
+                }
+            }
+        }
+        None
+    }
+
+    /// Reads the record just fed, returning `None` for the header row.
+    fn read(&mut self) -> Option<Result<Transaction, MalformedRow>> {
+        let start = self.csv.position().clone();
+        if self.expects_headers && self.headers.is_none() {
+            return match self.csv.headers() {
+                Ok(headers) => {
+                    self.headers = Some(headers.clone());
+                    None
+                }
+                Err(source) => {
+                    self.done = true;
+                    let error = InputError::Malformed {
+                        line: start.line(),
+                        source,
+                    };
+                    Some(Err(MalformedRow::new(&start, String::new(), error)))
+                }
+            };
+        }
+
+        match self.csv.read_record(&mut self.record) {
+            Ok(false) => None,
+            Ok(true) => Some(
+                match parse_record(&self.record, self.headers.as_ref(), self.precision) {
+                    Ok(tx) => Ok(tx.with_offset(self.csv.position().byte())),
+                    Err(error) => {
+                        let position = self.record.position().cloned().unwrap_or(start);
+                        let raw = self.record.iter().collect::<Vec<_>>().join(",");
+                        Err(MalformedRow::new(&position, raw, error))
+                    }
+                },
+            ),
+            Err(source) => {
+                let position = source.position().cloned().unwrap_or(start);
+                let error = InputError::Malformed {
+                    line: position.line(),
+                    source,
+                };
+                Some(Err(MalformedRow::new(&position, String::new(), error)))
+            }
+        }
+    }
+}
+
+/// Reads transactions from an async input.
+///
+/// # Arguments
+/// * `input` - The CSV input, for example a file, a socket or stdin.
+/// * `config` - How the input is read.
+/// * `precision` - The precision policy amounts are validated against.
+///
+/// # Returns
+/// A stream of transactions tagged with their input line and offset, or of the rows that could not be parsed.
+pub fn transaction_stream<R>(
+    input: R,
+    config: ReaderConfig,
+    precision: Precision,
+) -> impl Stream<Item = Result<Transaction, MalformedRow>>
+where
+    R: AsyncRead + Unpin,
+{
+    let feed = Feed {
+        input: BufReader::new(input),
+        json: (*config.format() == InputFormat::Jsonl).then(JsonLines::default),
+        csv: config.build_from(VecDeque::new()),
+        expects_headers: *config.has_headers(),
+        headers: None,
+        precision,
+        line: Vec::new(),
+        record: StringRecord::new(),
+        parquet: *config.format() == InputFormat::Parquet,
+        done: false,
+    };
+    stream::unfold(feed, |mut feed| async move {
+        let item = feed.next().await?;
+        Some((item, feed))
+    })
+}
+
+/// AsyncEngine applies a stream of transactions to an accountant without blocking the runtime on input.
+pub struct AsyncEngine {
+    accountant: Accountant,
+    error_policy: ErrorPolicy,
+    updates: Option<Sender<BalanceUpdate>>,
+}
+
+impl AsyncEngine {
+    /// Creates an engine applying to `accountant`.
+    pub fn new(accountant: Accountant) -> Self {
+        Self {
+            accountant,
+            error_policy: ErrorPolicy::default(),
+            updates: None,
+        }
+    }
+
+    /// Sets the policy applied to rows that cannot be parsed.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Publishes a `BalanceUpdate` on `updates` for every applied row.
+    ///
+    /// Publishing waits for room in the channel and stops once the receiver is dropped.
+    pub fn with_updates(mut self, updates: Sender<BalanceUpdate>) -> Self {
+        self.updates = Some(updates);
+        self
+    }
+
+    /// Returns the accountant.
+    pub fn accountant(&self) -> &Accountant {
+        &self.accountant
+    }
+
+    /// Consumes the engine and returns the accountant.
+    pub fn into_accountant(self) -> Accountant {
+        self.accountant
+    }
+
+    /// Applies every transaction of `transactions`.
+    ///
+    /// # Arguments
+    /// * `transactions` - The transactions, usually from `transaction_stream`.
+    ///
+    /// # Returns
+    /// The ingestion report, or an error if the input fails or a malformed row is not allowed by the error policy.
+    pub async fn run(
+        &mut self,
+        transactions: impl Stream<Item = Result<Transaction, MalformedRow>>,
+    ) -> Result<IngestReport> {
+        let mut transactions = pin!(transactions);
+        let mut report = IngestReport::default();
+
+        while let Some(item) = transactions.next().await {
+            let transaction = match item {
+                Ok(transaction) => transaction,
+                Err(row) if matches!(row.error(), InputError::Io(_)) => Err(row.into_error())?,
+                Err(row) => {
+                    if !row.raw().is_empty() {
+                        report.rows_read += 1;
+                    }
+                    report.skip(self.error_policy, row)?;
+                    continue;
+                }
+            };
+            report.rows_read += 1;
+            self.apply(transaction).await?;
+        }
+
+        self.accountant.finish()?;
+        Ok(report)
+    }
+
+    /// Writes the client balances as CSV to `writer`.
+    ///
+    /// # Arguments
+    /// * `writer` - The destination of the balances.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    pub async fn export(&self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        let mut buffer = BufferCSVWriter::new();
+        self.accountant.export(RefCell::new(&mut buffer))?;
+        writer.write_all(&buffer.into_bytes()?).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn apply(&mut self, transaction: Transaction) -> Result<()> {
+        let Some(updates) = &self.updates else {
+            return self.accountant.apply_bookkeeping(transaction);
+        };
+        let Some(update) = apply_update(&mut self.accountant, transaction)? else {
+            return Ok(());
+        };
+        if updates.send(update).await.is_err() {
+            self.updates = None;
+        }
+        Ok(())
+    }
+}
+
+/// Applies `transaction` to `accountant` and describes the outcome.
+///
+/// # Arguments
+/// * `accountant` - The ledger the transaction is applied to.
+/// * `transaction` - The transaction to apply.
+///
+/// # Returns
+/// The rejection or the resulting client balance in the currency of the applied or referenced transaction,
+/// `None` if the client is unknown after the transaction.
+pub(crate) fn apply_update(
+    accountant: &mut Accountant,
+    transaction: Transaction,
+) -> Result<Option<BalanceUpdate>> {
+    let (tx, client) = (*transaction.tx(), *transaction.client());
+    let rejected = accountant.rejections().len();
+    accountant.apply_bookkeeping(transaction)?;
+
+    if let Some(rejection) = accountant.rejections().get(rejected) {
+        return Ok(Some(BalanceUpdate::Rejected(rejection.clone())));
+    }
+    let currency = accountant
+        .transaction(tx)?
+        .and_then(|transaction| *transaction.currency());
+    Ok(accountant
+        .balance(client, currency)
+        .map(|balance| BalanceUpdate::Applied {
+            tx,
+            balance: balance.clone(),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::LedgerError, precision::Precision, processor::Engine, traits::RecordRead};
+    use rust_decimal::prelude::*;
+    use std::{fs, io::Cursor};
+    use tokio::sync::mpsc;
+
+    async fn run_async(
+        input: &[u8],
+        error_policy: ErrorPolicy,
+    ) -> Result<(AsyncEngine, IngestReport)> {
+        let mut engine = AsyncEngine::new(Accountant::new()).with_error_policy(error_policy);
+        let report = engine
+            .run(transaction_stream(
+                input,
+                ReaderConfig::default(),
+                Precision::default(),
+            ))
+            .await?;
+        Ok((engine, report))
+    }
+
+    #[tokio::test]
+    async fn test_artefacts_match_blocking_engine() -> Result<()> {
+        for file in [
+            "test_transactions_small.csv",
+            "test_transactions_large.csv",
+            "test_transactions_with_all_types.csv",
+        ] {
+            let input = fs::read(format!("{}/artefacts/{file}", env!("CARGO_MANIFEST_DIR")))?;
+
+            let reader = ReaderConfig::default().build(Box::new(Cursor::new(input.clone())));
+            let mut blocking = Engine::new(BufferCSVWriter::new(), reader, Accountant::new());
+            blocking.run()?;
+            let mut expected = BufferCSVWriter::new();
+            blocking.accountant().export(RefCell::new(&mut expected))?;
+
+            let (engine, _) = run_async(&input, ErrorPolicy::FailFast).await?;
+            let mut exported = Vec::new();
+            engine.export(&mut exported).await?;
+
+            assert_eq!(exported, expected.into_bytes()?);
+            assert_eq!(
+                engine.accountant().rejections(),
+                blocking.accountant().rejections()
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publishes_balance_updates() -> Result<()> {
+        let input = b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,50.0\n";
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut engine = AsyncEngine::new(Accountant::new()).with_updates(sender);
+        let feed = tokio::spawn(async move {
+            engine
+                .run(transaction_stream(
+                    &input[..],
+                    ReaderConfig::default(),
+                    Precision::default(),
+                ))
+                .await
+        });
+
+        match receiver.recv().await {
+            Some(BalanceUpdate::Applied { tx: 1, balance }) => {
+                assert_eq!(*balance.available(), dec!(10.0))
+            }
+            other => panic!("unexpected update {other:?}"),
+        }
+        match receiver.recv().await {
+            Some(BalanceUpdate::Rejected(rejection)) => assert!(matches!(
+                rejection.reason(),
+                LedgerError::InsufficientFunds { .. }
+            )),
+            other => panic!("unexpected update {other:?}"),
+        }
+        assert!(receiver.recv().await.is_none());
+        assert_eq!(*feed.await??.rows_read(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_keeps_blocking_lines_and_offsets() -> Result<()> {
+        let input =
+            b"type,client,tx,amount\r\n\r\ndeposit,1,1,10.0\r\ndispute,1,1,\ndeposit,2,2,1.0";
+
+        let mut reader = ReaderConfig::default().build(Box::new(Cursor::new(input.to_vec())));
+        let headers = reader.headers()?.clone();
+        let mut record = StringRecord::new();
+        let mut expected = Vec::new();
+        while reader.read_record(&mut record)? {
+            let transaction = parse_record(&record, Some(&headers), Precision::default())?;
+            expected.push(transaction.with_offset(reader.position().byte()));
+        }
+
+        let streamed: Vec<_> =
+            transaction_stream(&input[..], ReaderConfig::default(), Precision::default())
+                .collect()
+                .await;
+        let streamed = streamed
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|row| row.into_error())?;
+
+        assert_eq!(expected.len(), 3);
+        assert_eq!(streamed, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_reads_json_lines_like_blocking_reader() -> Result<()> {
+        let input = b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2\"}\r\n\n{\"type\":\"oops\"}\n{\"type\":\"dispute\",\"client\":1,\"tx\":1}";
+        let config = ReaderConfig::default().with_format(InputFormat::Jsonl);
+
+        let mut reader = config.open(Box::new(Cursor::new(input.to_vec())))?;
+        let mut expected = Vec::new();
+        while let Some(read) = reader.read_transaction(Precision::default())? {
+            expected.push(read.map_err(|row| (*row.line(), *row.byte())));
+        }
+
+        let streamed: Vec<_> = transaction_stream(&input[..], config, Precision::default())
+            .map(|read| read.map_err(|row| (*row.line(), *row.byte())))
+            .collect()
+            .await;
+
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected[1], Err((3, 52)));
+        assert_eq!(streamed, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_rows_follow_error_policy() -> Result<()> {
+        let input = b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,x,2,5.0\ndeposit,1,3,-1\n";
+
+        assert!(run_async(input, ErrorPolicy::FailFast).await.is_err());
+        assert!(run_async(input, ErrorPolicy::SkipUpTo(1)).await.is_err());
+
+        let (engine, report) = run_async(input, ErrorPolicy::Skip).await?;
+        assert_eq!(*report.rows_read(), 3);
+        let lines: Vec<u64> = report.malformed().iter().map(|row| *row.line()).collect();
+        assert_eq!(lines, vec![3, 4]);
+        assert_eq!(report.malformed()[0].raw(), "deposit,x,2,5.0");
+        assert_eq!(*report.malformed()[0].byte(), 39);
+        assert_eq!(*engine.accountant().client(1).unwrap().total(), dec!(10.0));
+        Ok(())
+    }
+}
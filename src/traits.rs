@@ -3,30 +3,63 @@ use std::cell::RefCell;
 use anyhow::Result;
 use serde::Serialize;
 
-use crate::models::Transaction;
+use crate::{models::Transaction, precision::Precision, processor::MalformedRow};
 
-/// CSVWrite trait provides a method to write a record to a CSV file.
-pub trait CSVWrite {
-    /// Writes a record to a CSV file.
+/// RecordWrite trait provides a method to write a record in the output format of the writer.
+pub trait RecordWrite {
+    /// Writes a record.
     ///
     /// # Arguments
-    /// * `record` - The record to write to the CSV file that is Serializable.
+    /// * `record` - The record to write that is Serializable.
     ///
     /// # Returns
     /// A Result indicating success or failure.
     fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()>;
+
+    /// Completes the output after the last record, for example by closing a JSON array.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// RecordRead trait provides a method to read transactions one input record at a time.
+pub trait RecordRead {
+    /// Reads the next record as a transaction.
+    ///
+    /// # Arguments
+    /// * `precision` - The precision policy amounts are validated against.
+    ///
+    /// # Returns
+    /// The transaction tagged with its input line and the offset just past its record, the row that
+    /// could not be parsed, `None` at the end of the input, or an error if the input cannot be read.
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>>;
+}
+
+impl<T: RecordRead + ?Sized> RecordRead for Box<T> {
+    fn read_transaction(
+        &mut self,
+        precision: Precision,
+    ) -> Result<Option<Result<Transaction, MalformedRow>>> {
+        (**self).read_transaction(precision)
+    }
 }
 
-/// CSVExport trait provides a method to export a CSV file.
+/// CSVExport trait provides a method to export the client balances.
 pub trait CSVExport {
-    /// Exports a CSV file.
+    /// Exports the client balances.
     ///
     /// # Arguments
-    /// * `writer` - The writer to write the CSV file to.
+    /// * `writer` - The writer to write the balances to.
     ///
     /// # Returns
     /// A Result indicating success or failure.
-    fn export(&self, writer: RefCell<&mut impl CSVWrite>) -> Result<()>;
+    fn export(&self, writer: RefCell<&mut impl RecordWrite>) -> Result<()>;
 }
 
 /// Accounting trait provides a method to apply bookkeeping.
@@ -39,4 +72,74 @@ pub trait Accounting {
     /// # Returns
     /// A Result indicating success or failure.
     fn apply_bookkeeping(&mut self, transaction: Transaction) -> Result<()>;
+
+    /// Completes any pending bookkeeping, called once after the last transaction and before export.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Returns the precision policy the ledger keeps amounts under, which input is validated against.
+    fn precision(&self) -> Precision {
+        Precision::default()
+    }
+}
+
+/// TransactionStore keeps the deposits and withdrawals that disputes, resolves and chargebacks can reference.
+pub trait TransactionStore: Send {
+    /// Returns the stored transaction `tx`, if any.
+    ///
+    /// # Arguments
+    /// * `tx` - The transaction id.
+    ///
+    /// # Returns
+    /// The stored transaction with its lifecycle state.
+    fn get(&self, tx: u32) -> Result<Option<Transaction>>;
+
+    /// Checks whether the transaction `tx` is stored.
+    ///
+    /// # Arguments
+    /// * `tx` - The transaction id.
+    ///
+    /// # Returns
+    /// True when the transaction is stored.
+    fn contains(&self, tx: u32) -> Result<bool> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    /// Stores a transaction, replacing the one stored under the same id.
+    ///
+    /// # Arguments
+    /// * `transaction` - The transaction to store.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn insert(&mut self, transaction: Transaction) -> Result<()>;
+
+    /// Returns the number of stored transactions.
+    fn len(&self) -> usize;
+
+    /// Returns true when no transaction is stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Visits every stored transaction in ascending id order.
+    ///
+    /// # Arguments
+    /// * `visit` - Called with every stored transaction.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn for_each(&self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()>;
+
+    /// Removes every stored transaction, visiting them in ascending id order.
+    ///
+    /// # Arguments
+    /// * `visit` - Called with every removed transaction.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn drain(&mut self, visit: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()>;
 }
@@ -1,23 +1,688 @@
-use crate::traits::CSVWrite;
-use anyhow::Result;
-use csv::{Writer, WriterBuilder};
+use crate::columnar::ParquetWriter;
+use crate::compression::{Compression, Encoder};
+use crate::traits::RecordWrite;
+use anyhow::{Result, anyhow};
+use csv::{Terminator, Writer, WriterBuilder};
+use getset::Getters;
 use serde::Serialize;
-use std::io::{self, Stdout};
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+/// OutputFormat is the encoding of exported records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// CSV with a header row.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+    /// A single JSON array of objects.
+    Json,
+    /// An Apache Parquet file, balance amounts typed as `decimal(38, s)` at the scale of the precision policy.
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `csv`, `jsonl`, `json` or `parquet`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            "json" => Ok(Self::Json),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(anyhow!(
+                "unknown output format {s:?}, expected csv, jsonl, json or parquet"
+            )),
+        }
+    }
+}
+
+/// QuoteStyle decides which CSV fields are quoted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// Only fields holding a delimiter, a quote or a line break.
+    #[default]
+    Necessary,
+    /// Every field.
+    Always,
+    /// Every field that is not a number.
+    NonNumeric,
+    /// No field, even when the output can then not be read back.
+    Never,
+}
+
+impl FromStr for QuoteStyle {
+    type Err = anyhow::Error;
+
+    /// Parses `necessary`, `always`, `non-numeric` or `never`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "necessary" => Ok(Self::Necessary),
+            "always" => Ok(Self::Always),
+            "non-numeric" => Ok(Self::NonNumeric),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow!(
+                "unknown quote style {s:?}, expected necessary, always, non-numeric or never"
+            )),
+        }
+    }
+}
+
+/// LineTerminator is the end of every CSV record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineTerminator {
+    /// `\n`.
+    #[default]
+    Lf,
+    /// `\r\n`, as RFC 4180 asks for.
+    Crlf,
+}
+
+impl FromStr for LineTerminator {
+    type Err = anyhow::Error;
+
+    /// Parses `lf` or `crlf`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::Crlf),
+            _ => Err(anyhow!(
+                "unknown line terminator {s:?}, expected lf or crlf"
+            )),
+        }
+    }
+}
+
+/// CsvDialect describes how CSV output is written.
+///
+/// The default dialect writes a header row, separates fields with a comma, quotes them only when
+/// necessary and ends every record with `\n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Getters)]
+pub struct CsvDialect {
+    /// The field delimiter.
+    #[getset(get = "pub")]
+    delimiter: u8,
+    /// Which fields are quoted.
+    #[getset(get = "pub")]
+    quote_style: QuoteStyle,
+    /// The end of every record.
+    #[getset(get = "pub")]
+    terminator: LineTerminator,
+    /// Whether a header row is written before the first record.
+    #[getset(get = "pub")]
+    has_headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote_style: QuoteStyle::default(),
+            terminator: LineTerminator::default(),
+            has_headers: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Sets the field delimiter.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets which fields are quoted.
+    pub fn with_quote_style(mut self, quote_style: QuoteStyle) -> Self {
+        self.quote_style = quote_style;
+        self
+    }
+
+    /// Sets the end of every record.
+    pub fn with_terminator(mut self, terminator: LineTerminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+
+    /// Sets whether a header row is written before the first record.
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Builds a CSV writer over `output` using this dialect.
+    pub(crate) fn build<W: Write>(&self, output: W) -> Writer<W> {
+        WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote_style(match self.quote_style {
+                QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+                QuoteStyle::Always => csv::QuoteStyle::Always,
+                QuoteStyle::NonNumeric => csv::QuoteStyle::NonNumeric,
+                QuoteStyle::Never => csv::QuoteStyle::Never,
+            })
+            .terminator(match self.terminator {
+                LineTerminator::Lf => Terminator::Any(b'\n'),
+                LineTerminator::Crlf => Terminator::CRLF,
+            })
+            .has_headers(self.has_headers)
+            .from_writer(output)
+    }
+}
+
+enum Sink<W: Write + Send> {
+    /// The CSV writer, taken by `finish` to reach the output it owns.
+    Csv(Option<Box<Writer<W>>>),
+    Jsonl(BufWriter<W>),
+    Json {
+        output: BufWriter<W>,
+        records: u64,
+    },
+    Parquet(Box<ParquetWriter<W>>),
+}
+
+/// Writes records to any `io::Write` in the chosen output format, optionally compressed.
+pub struct RecordWriter<W: Write + Send> {
+    sink: Sink<Encoder<W>>,
+}
+
+impl<W: Write + Send> RecordWriter<W> {
+    /// Creates a writer encoding records as `format` into `output`.
+    ///
+    /// # Arguments
+    /// * `output` - The destination of the records.
+    /// * `format` - How the records are encoded.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record.
+    pub fn new(output: W, format: OutputFormat) -> Self {
+        Self::encoding(Encoder::Plain(output), format, CsvDialect::default())
+    }
+
+    /// Creates a writer encoding records as `format` and compressing them into `output`.
+    ///
+    /// # Arguments
+    /// * `output` - The destination of the compressed records.
+    /// * `format` - How the records are encoded.
+    /// * `compression` - The codec compressing the encoded records.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record so the compressed stream is complete.
+    pub fn compressed(output: W, format: OutputFormat, compression: Compression) -> Result<Self> {
+        Ok(Self::encoding(
+            Encoder::new(output, compression)?,
+            format,
+            CsvDialect::default(),
+        ))
+    }
+
+    /// Creates a writer encoding records as CSV in `dialect` and compressing them into `output`.
+    ///
+    /// # Arguments
+    /// * `output` - The destination of the records.
+    /// * `dialect` - How the CSV is written.
+    /// * `compression` - The codec compressing the CSV, `Compression::None` for plain CSV.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record.
+    pub fn csv(output: W, dialect: CsvDialect, compression: Compression) -> Result<Self> {
+        Ok(Self::encoding(
+            Encoder::new(output, compression)?,
+            OutputFormat::Csv,
+            dialect,
+        ))
+    }
+
+    fn encoding(output: Encoder<W>, format: OutputFormat, dialect: CsvDialect) -> Self {
+        let sink = match format {
+            OutputFormat::Csv => Sink::Csv(Some(Box::new(dialect.build(output)))),
+            OutputFormat::Jsonl => Sink::Jsonl(BufWriter::new(output)),
+            OutputFormat::Json => Sink::Json {
+                output: BufWriter::new(output),
+                records: 0,
+            },
+            OutputFormat::Parquet => Sink::Parquet(Box::new(ParquetWriter::new(output))),
+        };
+        Self { sink }
+    }
+}
+
+impl<W: Write + Send> RecordWrite for RecordWriter<W> {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer
+                .as_mut()
+                .ok_or(anyhow!("the writer is already finished"))?
+                .serialize(record)?,
+            Sink::Jsonl(output) => {
+                serde_json::to_writer(&mut *output, record)?;
+                output.write_all(b"\n")?;
+            }
+            Sink::Json { output, records } => {
+                output.write_all(if *records == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *output, record)?;
+                *records += 1;
+            }
+            Sink::Parquet(writer) => writer.write_record(record)?,
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let mut csv_output = None;
+        let encoder = match &mut self.sink {
+            Sink::Csv(writer) => match writer.take() {
+                Some(writer) => Some(
+                    &mut *csv_output.insert(writer.into_inner().map_err(|err| err.into_error())?),
+                ),
+                None => None,
+            },
+            Sink::Jsonl(output) => {
+                output.flush()?;
+                Some(output.get_mut())
+            }
+            Sink::Json { output, records } => {
+                output.write_all(if *records == 0 { b"[]\n" } else { b"\n]\n" })?;
+                output.flush()?;
+                Some(output.get_mut())
+            }
+            Sink::Parquet(writer) => {
+                writer.finish()?;
+                writer.get_mut()
+            }
+        };
+        if let Some(encoder) = encoder {
+            encoder.try_finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// AtomicFile is a file that only appears at its path, complete, once committed.
+///
+/// It is written as a hidden temporary file in the same directory and renamed over the path on
+/// `commit`, so readers see either the previous file or the new one, never a partial one.
+/// Dropped without a commit, the temporary file is removed and the path is left untouched.
+pub struct AtomicFile {
+    file: File,
+    tmp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file of `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let name = path
+            .file_name()
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file path", path.display()),
+            ))?
+            .to_string_lossy();
+        let tmp = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+        Ok(Self {
+            file: File::create(&tmp)?,
+            tmp,
+            path,
+            committed: false,
+        })
+    }
+
+    /// Returns a second handle on the temporary file, writing to the same file.
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Syncs the temporary file to disk and renames it over the path.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Writes records to a file, in any output format, replacing the file atomically on `finish`.
+pub struct FileRecordWriter {
+    writer: RecordWriter<File>,
+    file: Option<AtomicFile>,
+}
+
+impl FileRecordWriter {
+    /// Creates a writer encoding records as `format` and compressing them into the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The file, only created or replaced once the writer is finished.
+    /// * `format` - How the records are encoded.
+    /// * `compression` - The codec compressing the encoded records.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record.
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: OutputFormat,
+        compression: Compression,
+    ) -> Result<Self> {
+        let file = AtomicFile::create(path)?;
+        Ok(Self {
+            writer: RecordWriter::compressed(file.try_clone()?, format, compression)?,
+            file: Some(file),
+        })
+    }
+
+    /// Creates a writer encoding records as CSV in `dialect` and compressing them into the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The file, only created or replaced once the writer is finished.
+    /// * `dialect` - How the CSV is written.
+    /// * `compression` - The codec compressing the CSV.
+    ///
+    /// # Returns
+    /// The writer, to be finished after the last record.
+    pub fn csv(
+        path: impl AsRef<Path>,
+        dialect: CsvDialect,
+        compression: Compression,
+    ) -> Result<Self> {
+        let file = AtomicFile::create(path)?;
+        Ok(Self {
+            writer: RecordWriter::csv(file.try_clone()?, dialect, compression)?,
+            file: Some(file),
+        })
+    }
+}
+
+impl RecordWrite for FileRecordWriter {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        self.writer.write_record(record)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            self.writer.finish()?;
+            file.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes CSV records to stdout.
 pub struct StdOutCSVWriter {
     stdout_writer: Writer<Stdout>,
 }
 
 impl StdOutCSVWriter {
+    /// Creates a writer with default CSV settings.
     pub fn new() -> Self {
+        Self::with_dialect(CsvDialect::default())
+    }
+
+    /// Creates a writer with the CSV settings of `dialect`.
+    pub fn with_dialect(dialect: CsvDialect) -> Self {
         StdOutCSVWriter {
-            stdout_writer: WriterBuilder::new().from_writer(io::stdout()),
+            stdout_writer: dialect.build(io::stdout()),
         }
     }
 }
 
-impl CSVWrite for StdOutCSVWriter {
+impl Default for StdOutCSVWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordWrite for StdOutCSVWriter {
     fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
         Ok(self.stdout_writer.serialize(record)?)
     }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.stdout_writer.flush()?)
+    }
+}
+
+/// Writes CSV records to a file, replacing it atomically on `finish`.
+pub struct FileCSVWriter {
+    file_writer: FileRecordWriter,
+}
+
+impl FileCSVWriter {
+    /// Creates a writer to the file at `path` with default CSV settings.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_dialect(path, CsvDialect::default())
+    }
+
+    /// Creates a writer to the file at `path` with the CSV settings of `dialect`.
+    pub fn with_dialect(path: impl AsRef<Path>, dialect: CsvDialect) -> Result<Self> {
+        Ok(FileCSVWriter {
+            file_writer: FileRecordWriter::csv(path, dialect, Compression::None)?,
+        })
+    }
+}
+
+impl RecordWrite for FileCSVWriter {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        self.file_writer.write_record(record)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.file_writer.finish()
+    }
+}
+
+/// Collects CSV records in memory.
+pub(crate) struct BufferCSVWriter {
+    buffer_writer: Writer<Vec<u8>>,
+}
+
+impl BufferCSVWriter {
+    /// Creates an empty buffer with default CSV settings.
+    pub(crate) fn new() -> Self {
+        BufferCSVWriter {
+            buffer_writer: WriterBuilder::new().from_writer(Vec::new()),
+        }
+    }
+
+    /// Returns the CSV written so far.
+    pub(crate) fn into_bytes(self) -> Result<Vec<u8>> {
+        Ok(self.buffer_writer.into_inner()?)
+    }
+}
+
+impl RecordWrite for BufferCSVWriter {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        Ok(self.buffer_writer.serialize(record)?)
+    }
+}
+
+/// Collects records in memory as JSON values.
+#[derive(Default)]
+pub(crate) struct JsonValueWriter {
+    values: Vec<Value>,
+}
+
+impl JsonValueWriter {
+    /// Creates an empty collection.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the records written so far.
+    pub(crate) fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+}
+
+impl RecordWrite for JsonValueWriter {
+    fn write_record<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        self.values.push(serde_json::to_value(record)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[derive(Serialize)]
+    struct Row {
+        client: u16,
+        locked: bool,
+    }
+
+    fn written(format: OutputFormat, rows: &[Row]) -> Result<String> {
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::new(&mut output, format);
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        writer.finish()?;
+        drop(writer);
+        Ok(String::from_utf8(output)?)
+    }
+
+    #[test]
+    fn test_record_writer_formats() -> Result<()> {
+        let rows = [
+            Row {
+                client: 1,
+                locked: false,
+            },
+            Row {
+                client: 2,
+                locked: true,
+            },
+        ];
+
+        assert_eq!(
+            written(OutputFormat::Csv, &rows)?,
+            "client,locked\n1,false\n2,true\n"
+        );
+        assert_eq!(
+            written(OutputFormat::Jsonl, &rows)?,
+            "{\"client\":1,\"locked\":false}\n{\"client\":2,\"locked\":true}\n"
+        );
+        assert_eq!(
+            written(OutputFormat::Json, &rows)?,
+            "[\n{\"client\":1,\"locked\":false},\n{\"client\":2,\"locked\":true}\n]\n"
+        );
+        assert_eq!(written(OutputFormat::Json, &[])?, "[]\n");
+        assert_eq!(written(OutputFormat::Jsonl, &[])?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_output_is_complete() -> Result<()> {
+        let mut output = Vec::new();
+        let mut writer =
+            RecordWriter::compressed(&mut output, OutputFormat::Jsonl, Compression::Gzip)?;
+        writer.write_record(&Row {
+            client: 1,
+            locked: false,
+        })?;
+        writer.finish()?;
+        drop(writer);
+
+        let mut plain = String::new();
+        crate::compression::decompress(&output[..])?.read_to_string(&mut plain)?;
+        assert_eq!(plain, "{\"client\":1,\"locked\":false}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_dialect() -> Result<()> {
+        let dialect = CsvDialect::default()
+            .with_delimiter(b';')
+            .with_quote_style(QuoteStyle::NonNumeric)
+            .with_terminator(LineTerminator::Crlf);
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::csv(&mut output, dialect, Compression::None)?;
+        writer.write_record(&Row {
+            client: 1,
+            locked: false,
+        })?;
+        writer.finish()?;
+        drop(writer);
+        assert_eq!(
+            String::from_utf8(output)?,
+            "\"client\";\"locked\"\r\n1;\"false\"\r\n"
+        );
+
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::csv(
+            &mut output,
+            CsvDialect::default().with_headers(false),
+            Compression::None,
+        )?;
+        writer.write_record(&Row {
+            client: 2,
+            locked: true,
+        })?;
+        writer.finish()?;
+        drop(writer);
+        assert_eq!(String::from_utf8(output)?, "2,true\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_appears_on_finish() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("play-with-csv-{}-atomic", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("balances.csv");
+        fs::write(&path, "previous\n")?;
+
+        let mut writer = FileRecordWriter::create(&path, OutputFormat::Csv, Compression::None)?;
+        writer.write_record(&Row {
+            client: 1,
+            locked: false,
+        })?;
+        assert_eq!(fs::read_to_string(&path)?, "previous\n");
+        writer.finish()?;
+        assert_eq!(fs::read_to_string(&path)?, "client,locked\n1,false\n");
+
+        let abandoned = FileRecordWriter::create(
+            dir.join("abandoned.csv"),
+            OutputFormat::Csv,
+            Compression::None,
+        )?;
+        drop(abandoned);
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_from_str() -> Result<()> {
+        assert_eq!("json".parse::<OutputFormat>()?, OutputFormat::Json);
+        assert_eq!("jsonl".parse::<OutputFormat>()?, OutputFormat::Jsonl);
+        assert_eq!("csv".parse::<OutputFormat>()?, OutputFormat::Csv);
+        assert_eq!("parquet".parse::<OutputFormat>()?, OutputFormat::Parquet);
+        assert!("xml".parse::<OutputFormat>().is_err());
+        Ok(())
+    }
 }